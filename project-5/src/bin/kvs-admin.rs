@@ -0,0 +1,204 @@
+use std::{env::current_dir, path::PathBuf, process::exit};
+
+use async_std::task;
+use clap::{load_yaml, App, AppSettings};
+use log::LevelFilter;
+
+use kvs::{GenerationUsage, KvsAdmin, KvsError, LogOperation, Result};
+
+mod options;
+use options::{kvs_store_options, sync_policy};
+
+/// How much of a key or value is printed before it is cut short.
+const PRINTED_LENGTH: usize = 64;
+
+async fn run() -> Result<()> {
+    env_logger::builder().filter_level(LevelFilter::Warn).init();
+
+    let yaml = load_yaml!("cli-admin.yml");
+    let matches = App::from_yaml(yaml)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .get_matches();
+    let dir = match matches.value_of("dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => current_dir()?,
+    };
+    let admin = KvsAdmin::new(&dir);
+
+    match matches.subcommand() {
+        ("verify", Some(_)) => {
+            let mut damaged = 0;
+            for check in admin.verify().await? {
+                let name = check.path.file_name().unwrap().to_string_lossy();
+                if check.missing {
+                    println!("{}: missing", name);
+                } else if check.is_intact() {
+                    println!("{}: {} records, intact", name, check.records);
+                } else {
+                    println!(
+                        "{}: {} records, damaged from offset {} of {}",
+                        name, check.records, check.valid_length, check.length
+                    );
+                }
+                if !check.is_intact() {
+                    damaged += 1;
+                }
+            }
+            if damaged > 0 {
+                return Err(KvsError::StringError(format!(
+                    "{} damaged or missing files",
+                    damaged
+                )));
+            }
+        }
+        ("dump", Some(matches)) => {
+            let generation = matches.value_of("GENERATION").unwrap();
+            let generation = generation.parse().map_err(|_| {
+                KvsError::StringError(format!("Invalid generation: {}", generation))
+            })?;
+            let (entries, check) = admin.dump(generation).await?;
+            for entry in entries {
+                let batched = if entry.batched { ", batched" } else { "" };
+                print!("@{} ({} bytes{}) ", entry.offset, entry.length, batched);
+                let expires_at = match entry.operation {
+                    LogOperation::Set {
+                        key,
+                        value,
+                        expires_at,
+                    } => {
+                        print!("set {} = {}", printable(&key), printable(&value));
+                        expires_at
+                    }
+                    LogOperation::SetBlob {
+                        key,
+                        blob,
+                        blob_offset,
+                        blob_length,
+                        expires_at,
+                    } => {
+                        print!(
+                            "set {} = blob {} @{} ({} bytes)",
+                            printable(&key),
+                            blob,
+                            blob_offset,
+                            blob_length
+                        );
+                        expires_at
+                    }
+                    LogOperation::Remove { key } => {
+                        print!("rm {}", printable(&key));
+                        None
+                    }
+                };
+                match expires_at {
+                    Some(expires_at) => println!(", expires at {}", expires_at),
+                    None => println!(),
+                }
+            }
+            if !check.is_intact() {
+                println!(
+                    "damaged from offset {} of {}",
+                    check.valid_length, check.length
+                );
+            }
+        }
+        ("stats", Some(_)) => {
+            let usage = admin.usage().await?;
+            println!("keys: {}", usage.keys);
+            println!(
+                "{:>10} {:>12} {:>12} {:>12}",
+                "generation", "bytes", "live", "dead"
+            );
+            for generation in &usage.generations {
+                println!(
+                    "{:>10} {:>12} {:>12} {:>12}",
+                    generation.generation,
+                    generation.bytes,
+                    generation.live_bytes,
+                    generation.dead_bytes()
+                );
+            }
+            let total = |bytes: fn(&GenerationUsage) -> usize| {
+                usage.generations.iter().map(bytes).sum::<usize>()
+            };
+            println!(
+                "{:>10} {:>12} {:>12} {:>12}",
+                "total",
+                total(|generation| generation.bytes),
+                total(|generation| generation.live_bytes),
+                total(|generation| generation.dead_bytes())
+            );
+        }
+        ("compact", Some(matches)) => {
+            let sync_policy = sync_policy(matches)?;
+            admin
+                .compact(kvs_store_options(matches, sync_policy)?)
+                .await?;
+        }
+        ("repair", Some(matches)) => {
+            let mut left = 0;
+            for damaged in admin.repair(matches.is_present("truncate-sealed")).await? {
+                let check = damaged.check;
+                let name = check.path.file_name().unwrap().to_string_lossy();
+                if damaged.truncated {
+                    println!(
+                        "{}: cut {} damaged bytes from offset {}",
+                        name,
+                        check.length - check.valid_length,
+                        check.valid_length
+                    );
+                } else {
+                    println!(
+                        "{}: sealed, damaged from offset {} of {}, left as is",
+                        name, check.valid_length, check.length
+                    );
+                    left += 1;
+                }
+            }
+            if left > 0 {
+                return Err(KvsError::StringError(format!(
+                    "{} damaged sealed generations left as they are, \
+                     --truncate-sealed cuts them short",
+                    left
+                )));
+            }
+        }
+        ("upgrade", Some(_)) => {
+            if admin.upgrade().await? {
+                println!("upgraded from the legacy layout");
+            } else {
+                println!("already in the current layout");
+            }
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// `bytes` quoted if valid UTF-8 and as hex otherwise, cut short past
+/// `PRINTED_LENGTH` characters or bytes.
+fn printable(bytes: &[u8]) -> String {
+    let mut printed = match std::str::from_utf8(bytes) {
+        Ok(text) => format!(
+            "{:?}",
+            text.chars().take(PRINTED_LENGTH).collect::<String>()
+        ),
+        Err(_) => bytes
+            .iter()
+            .take(PRINTED_LENGTH)
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    };
+    if bytes.len() > PRINTED_LENGTH {
+        printed.push_str(&format!("... ({} bytes)", bytes.len()));
+    }
+    printed
+}
+
+fn main() {
+    if let Err(e) = task::block_on(run()) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
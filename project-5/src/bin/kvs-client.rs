@@ -1,9 +1,15 @@
-use std::{net::SocketAddr, process::exit};
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    ops::Bound,
+    process::exit,
+    time::Duration,
+};
 
-use async_std::task;
-use clap::{load_yaml, App};
+use async_std::{fs, task};
+use clap::{load_yaml, App, ArgMatches};
 
-use kvs::{KvsClient, Result};
+use kvs::{KvsClient, KvsError, Result, ScanOrder};
 
 async fn run() -> Result<()> {
     let yaml = load_yaml!("cli-client.yml");
@@ -11,42 +17,83 @@ async fn run() -> Result<()> {
 
     match matches.subcommand() {
         ("set", Some(matches)) => {
-            let key = matches
-                .value_of("KEY")
-                .expect("KEY argument missing")
-                .to_string();
-            let value = matches
-                .value_of("VALUE")
-                .expect("VALUE argument missing")
-                .to_string();
+            let key = bytes_of(matches, "KEY")?.expect("KEY argument missing");
+            let value = match matches.value_of("file") {
+                Some(path) => fs::read(path).await?,
+                None => bytes_of(matches, "VALUE")?.expect("VALUE argument missing"),
+            };
             let addr: SocketAddr = matches.value_of("addr").unwrap().parse()?;
 
+            let ttl = match matches.value_of("ttl") {
+                Some(ttl) => Some(ttl.parse().map(Duration::from_secs).map_err(|_| {
+                    KvsError::StringError(format!("Invalid value for --ttl: {}", ttl))
+                })?),
+                None => None,
+            };
+
             let mut client = KvsClient::connect(addr).await?;
-            client.set(key, value).await?;
+            match ttl {
+                Some(ttl) => client.set_bytes_with_ttl(key, value, ttl).await?,
+                None => client.set_bytes(key, value).await?,
+            }
         }
         ("get", Some(matches)) => {
-            let key = matches
-                .value_of("KEY")
-                .expect("KEY argument missing")
-                .to_string();
+            let key = bytes_of(matches, "KEY")?.expect("KEY argument missing");
             let addr: SocketAddr = matches.value_of("addr").unwrap().parse()?;
 
             let mut client = KvsClient::connect(addr).await?;
-            if let Some(value) = client.get(key).await? {
-                println!("{}", value);
+            if let Some(value) = client.get_bytes(key).await? {
+                print_line(matches, &[&value])?;
             } else {
                 println!("Key not found");
             }
         }
         ("rm", Some(matches)) => {
-            let key = matches
-                .value_of("KEY")
-                .expect("KEY argument missing")
-                .to_string();
+            let key = bytes_of(matches, "KEY")?.expect("KEY argument missing");
             let addr: SocketAddr = matches.value_of("addr").unwrap().parse()?;
 
             let mut client = KvsClient::connect(addr).await?;
-            client.remove(key).await?;
+            client.remove_bytes(key).await?;
+        }
+        ("scan", Some(matches)) => {
+            let order = if matches.is_present("reverse") {
+                ScanOrder::Reverse
+            } else {
+                ScanOrder::Forward
+            };
+            let addr: SocketAddr = matches.value_of("addr").unwrap().parse()?;
+
+            let mut client = KvsClient::connect(addr).await?;
+            let pairs = if let Some(prefix) = bytes_of(matches, "prefix")? {
+                client.scan_prefix_bytes(prefix, order).await?
+            } else {
+                let start = bytes_of(matches, "start")?.map_or(Bound::Unbounded, Bound::Included);
+                let end = bytes_of(matches, "end")?.map_or(Bound::Unbounded, Bound::Excluded);
+                let limit = match matches.value_of("limit") {
+                    Some(limit) => Some(limit.parse().map_err(|_| {
+                        KvsError::StringError(format!("Invalid value for --limit: {}", limit))
+                    })?),
+                    None => None,
+                };
+                client.scan_bytes((start, end), limit, order).await?
+            };
+            for (key, value) in pairs {
+                print_line(matches, &[&key, &value])?;
+            }
+        }
+        ("backup", Some(matches)) => {
+            let dest = matches.value_of("dest").expect("--dest argument missing");
+            let addr: SocketAddr = matches.value_of("addr").unwrap().parse()?;
+
+            let mut client = KvsClient::connect(addr).await?;
+            client.checkpoint(dest).await?;
+        }
+        ("stats", Some(matches)) => {
+            let addr: SocketAddr = matches.value_of("addr").unwrap().parse()?;
+
+            let mut client = KvsClient::connect(addr).await?;
+            let stats = client.stats().await?;
+            println!("{}", serde_json::to_string_pretty(&stats)?);
         }
         _ => unreachable!(),
     }
@@ -54,6 +101,53 @@ async fn run() -> Result<()> {
     Ok(())
 }
 
+/// The bytes of argument `name`, decoded from hex with `--hex`.
+fn bytes_of(matches: &ArgMatches, name: &str) -> Result<Option<Vec<u8>>> {
+    let arg = match matches.value_of(name) {
+        Some(arg) => arg,
+        None => return Ok(None),
+    };
+    if !matches.is_present("hex") {
+        return Ok(Some(arg.as_bytes().to_vec()));
+    }
+
+    let invalid = || KvsError::StringError(format!("Invalid hex for {}: {}", name, arg));
+    if arg.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    (0..arg.len())
+        .step_by(2)
+        .map(|i| {
+            arg.get(i..i + 2)
+                .filter(|byte| byte.bytes().all(|digit| digit.is_ascii_hexdigit()))
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+/// Prints `fields` separated by tabs, encoded as hex with `--hex` and as they
+/// are otherwise.
+fn print_line(matches: &ArgMatches, fields: &[&[u8]]) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            stdout.write_all(b"\t")?;
+        }
+        if matches.is_present("hex") {
+            for byte in field.iter() {
+                write!(stdout, "{:02x}", byte)?;
+            }
+        } else {
+            stdout.write_all(field)?;
+        }
+    }
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
+
 fn main() {
     if let Err(e) = task::block_on(run()) {
         eprintln!("{}", e);
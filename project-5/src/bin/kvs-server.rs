@@ -3,20 +3,23 @@ use std::{env::current_dir, path::PathBuf, process::exit};
 use async_std::{fs, net::SocketAddr, task};
 use clap::{load_yaml, App};
 use log::{error, info, LevelFilter};
-use sled;
 
-use kvs::{KvStore, KvsError, KvsServer, Result, SledKvsEngine};
+use kvs::{KvStore, KvsError, KvsServer, Result, SledKvsEngine, SyncPolicy};
+
+mod options;
+use options::{kvs_store_options, sync_policy};
 
 macro_rules! with_engine {
-    ($engine: expr, $path: expr, |$name: ident| $block: block) => {{
+    ($engine: expr, $path: expr, $options: expr, $sync_policy: expr, |$name: ident| $block: block) => {{
         match $engine {
             "kvs" => {
-                let $name = KvStore::open($path).await?;
+                let $name = KvStore::builder($path).options($options).open().await?;
                 let result: Result<()> = $block;
                 result
             }
             "sled" => {
-                let $name = SledKvsEngine::new(sled::open($path)?);
+                let sync_policy = $sync_policy.unwrap_or(SyncPolicy::Always);
+                let $name = SledKvsEngine::open($path, sync_policy)?;
                 let result: Result<()> = $block;
                 result
             }
@@ -38,11 +41,24 @@ async fn run() -> Result<()> {
     info!("Storage engine: {}", engine);
     info!("Listening on {}", addr);
 
+    // sled offers no way to open its database without writing to it
+    if engine == "sled" && matches.is_present("read-only") {
+        return Err(KvsError::StringError(
+            "--read-only is only supported by the kvs engine".to_owned(),
+        ));
+    }
+
     let engine_file = current_dir()?.join("engine");
     same_engine_as_last_time(&engine_file, &engine).await?;
-    fs::write(engine_file, format!("{}", engine)).await?;
+    // A read-only store leaves its directory as it is
+    if !matches.is_present("read-only") {
+        fs::write(engine_file, format!("{}", engine)).await?;
+    }
+
+    let sync_policy = sync_policy(&matches)?;
+    let options = kvs_store_options(&matches, sync_policy)?;
 
-    with_engine!(engine, current_dir()?, |engine| {
+    with_engine!(engine, current_dir()?, options, sync_policy, |engine| {
         let server = KvsServer::new(engine, addr);
         server.run().await
     })?;
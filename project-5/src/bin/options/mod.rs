@@ -0,0 +1,84 @@
+use std::{str::FromStr, time::Duration};
+
+use clap::ArgMatches;
+
+use kvs::{CompactionMode, Compression, IndexMode, KvStoreOptions, KvsError, Result, SyncPolicy};
+
+pub fn sync_policy(matches: &ArgMatches) -> Result<Option<SyncPolicy>> {
+    Ok(match matches.value_of("sync") {
+        Some("never") => Some(SyncPolicy::Never),
+        Some("periodic") => {
+            let interval = parse_arg(matches, "sync-interval")?.unwrap();
+            Some(SyncPolicy::Periodic(Duration::from_millis(interval)))
+        }
+        Some("always") => Some(SyncPolicy::Always),
+        _ => None,
+    })
+}
+
+/// The options of the kvs engine set by the flags of kvs-server, which the
+/// commands of kvs-admin opening a store take as well.
+pub fn kvs_store_options(
+    matches: &ArgMatches,
+    sync_policy: Option<SyncPolicy>,
+) -> Result<KvStoreOptions> {
+    let mut options = KvStoreOptions::default();
+    if let Some(compaction_threshold) = parse_arg(matches, "compaction-threshold")? {
+        options.compaction_threshold = compaction_threshold;
+    }
+    options.compaction_dead_ratio = parse_arg(matches, "compaction-dead-ratio")?;
+    options.max_segment_size = parse_arg(matches, "max-segment-size")?;
+    if let Some("incremental") = matches.value_of("compaction-mode") {
+        options.compaction_mode = CompactionMode::Incremental {
+            min_garbage_ratio: parse_arg(matches, "compaction-min-garbage-ratio")?.unwrap(),
+            max_generations: parse_arg(matches, "compaction-max-generations")?.unwrap(),
+        };
+    }
+    if let Some(sync_policy) = sync_policy {
+        options.sync_policy = sync_policy;
+    }
+    options.read_only = matches.is_present("read-only");
+    if let Some(read_buffer_size) = parse_arg(matches, "read-buffer-size")? {
+        options.read_buffer_size = read_buffer_size;
+    }
+    if let Some(write_buffer_size) = parse_arg(matches, "write-buffer-size")? {
+        options.write_buffer_size = write_buffer_size;
+    }
+    if let Some("on-disk") = matches.value_of("index-mode") {
+        options.index_mode = IndexMode::OnDisk;
+    }
+    match parse_arg(matches, "expiry-sweep-interval")? {
+        Some(0) => options.expiry_sweep_interval = None,
+        Some(interval) => options.expiry_sweep_interval = Some(Duration::from_millis(interval)),
+        // Only an index held in memory is swept
+        None if options.index_mode == IndexMode::OnDisk => options.expiry_sweep_interval = None,
+        None => (),
+    }
+    options.compression = match matches.value_of("compression") {
+        Some("lz4") => Some(Compression::Lz4),
+        Some("zstd") => Some(Compression::Zstd),
+        _ => None,
+    };
+    if let Some(compression_threshold) = parse_arg(matches, "compression-threshold")? {
+        options.compression_threshold = compression_threshold;
+    }
+    options.blob_threshold = parse_arg(matches, "blob-threshold")?;
+    if let Some(blob_garbage_ratio) = parse_arg(matches, "blob-garbage-ratio")? {
+        options.blob_garbage_ratio = blob_garbage_ratio;
+    }
+    if let Some(value_cache_size) = parse_arg(matches, "value-cache-size")? {
+        options.value_cache_size = value_cache_size;
+    }
+    Ok(options)
+}
+
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>> {
+    matches
+        .value_of(name)
+        .map(|value| {
+            value.parse().map_err(|_| {
+                KvsError::StringError(format!("Invalid value for --{}: {}", name, value))
+            })
+        })
+        .transpose()
+}
@@ -1,9 +1,12 @@
+use std::{path::PathBuf, time::Duration};
+
 use async_std::{
     net::{TcpStream, ToSocketAddrs},
     prelude::*,
 };
 
 use crate::{
+    engines::{range_into_bytes, BytesRange, EngineStats, KeyRange, ScanOrder, WriteBatch},
     error::{KvsError, Result},
     protocol::{KvsStream, Request, Response},
 };
@@ -20,33 +23,186 @@ impl KvsClient {
         })
     }
 
+    pub async fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.call(Request::Get { key }).await
+    }
+
     pub async fn get(&mut self, key: String) -> Result<Option<String>> {
-        let request = Request::Get { key };
-        self.kvs_stream.send(&request).await?;
-        let response = self.kvs_stream.next().await.unwrap();
-        match response? {
-            Response::Ok(value) => Ok(value),
-            Response::Err(e) => Err(KvsError::StringError(e)),
-        }
+        let value = self.get_bytes(key.into_bytes()).await?;
+        Ok(value.map(String::from_utf8).transpose()?)
+    }
+
+    pub async fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.call(Request::Set { key, value }).await.map(|_| ())
     }
 
     pub async fn set(&mut self, key: String, value: String) -> Result<()> {
-        let request = Request::Set { key, value };
-        self.kvs_stream.send(&request).await?;
-        let response = self.kvs_stream.next().await.unwrap();
-        match response? {
-            Response::Ok(_) => Ok(()),
-            Response::Err(e) => Err(KvsError::StringError(e)),
-        }
+        self.set_bytes(key.into_bytes(), value.into_bytes()).await
+    }
+
+    pub async fn set_bytes_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.call(Request::SetWithTtl { key, value, ttl })
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+            .await
+    }
+
+    pub async fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.call(Request::Remove { key }).await.map(|_| ())
     }
 
     pub async fn remove(&mut self, key: String) -> Result<()> {
-        let request = Request::Remove { key };
+        self.remove_bytes(key.into_bytes()).await
+    }
+
+    /// Sets `key` to `new`, or removes it if `new` is `None`, provided its
+    /// current value is `expected`.
+    ///
+    /// On mismatch, fails with `KvsError::CompareAndSwapMismatch` holding the
+    /// current value.
+    pub async fn compare_and_swap_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let request = Request::CompareAndSwap { key, expected, new };
+        self.call(request).await.map(|_| ())
+    }
+
+    pub async fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        let expected = expected.map(String::into_bytes);
+        let new = new.map(String::into_bytes);
+        self.compare_and_swap_bytes(key.into_bytes(), expected, new)
+            .await
+    }
+
+    pub async fn set_bytes_if_absent(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.compare_and_swap_bytes(key, None, Some(value)).await
+    }
+
+    pub async fn set_if_absent(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes_if_absent(key.into_bytes(), value.into_bytes())
+            .await
+    }
+
+    pub async fn set_bytes_if_present(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.call(Request::SetIfPresent { key, value })
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn set_if_present(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes_if_present(key.into_bytes(), value.into_bytes())
+            .await
+    }
+
+    pub async fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.call(Request::Batch { batch }).await.map(|_| ())
+    }
+
+    pub async fn scan_bytes(
+        &mut self,
+        range: BytesRange,
+        limit: Option<usize>,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = Request::Scan {
+            range,
+            limit,
+            order,
+        };
+        self.kvs_stream.send(&request).await?;
+        self.receive_pairs().await
+    }
+
+    pub async fn scan(
+        &mut self,
+        range: KeyRange,
+        limit: Option<usize>,
+        order: ScanOrder,
+    ) -> Result<Vec<(String, String)>> {
+        let pairs = self
+            .scan_bytes(range_into_bytes(range), limit, order)
+            .await?;
+        utf8_pairs(pairs)
+    }
+
+    pub async fn scan_prefix_bytes(
+        &mut self,
+        prefix: Vec<u8>,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = Request::ScanPrefix { prefix, order };
+        self.kvs_stream.send(&request).await?;
+        self.receive_pairs().await
+    }
+
+    pub async fn scan_prefix(
+        &mut self,
+        prefix: String,
+        order: ScanOrder,
+    ) -> Result<Vec<(String, String)>> {
+        let pairs = self.scan_prefix_bytes(prefix.into_bytes(), order).await?;
+        utf8_pairs(pairs)
+    }
+
+    /// Has the server write a checkpoint of its store to `dest`, a directory
+    /// on the server's machine, relative to its working directory if not
+    /// absolute.
+    pub async fn checkpoint(&mut self, dest: impl Into<PathBuf>) -> Result<()> {
+        let dest = dest.into();
+        self.call(Request::Checkpoint { dest }).await.map(|_| ())
+    }
+
+    pub async fn stats(&mut self) -> Result<EngineStats> {
+        let stats = self.call(Request::Stats).await?;
+        let stats = stats.ok_or(KvsError::UnexpectedResponse)?;
+        Ok(serde_json::from_slice(&stats)?)
+    }
+
+    async fn call(&mut self, request: Request) -> Result<Option<Vec<u8>>> {
         self.kvs_stream.send(&request).await?;
         let response = self.kvs_stream.next().await.unwrap();
         match response? {
-            Response::Ok(_) => Ok(()),
+            Response::Ok(value) => Ok(value),
             Response::Err(e) => Err(KvsError::StringError(e)),
+            Response::Mismatch(current) => Err(KvsError::CompareAndSwapMismatch { current }),
+            Response::Pair(..) => Err(KvsError::UnexpectedResponse),
         }
     }
+
+    async fn receive_pairs(&mut self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        loop {
+            let response = self.kvs_stream.next().await.unwrap();
+            match response? {
+                Response::Pair(key, value) => pairs.push((key, value)),
+                Response::Ok(_) => return Ok(pairs),
+                Response::Err(e) => return Err(KvsError::StringError(e)),
+                Response::Mismatch(_) => return Err(KvsError::UnexpectedResponse),
+            }
+        }
+    }
+}
+
+fn utf8_pairs(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<(String, String)>> {
+    pairs
+        .into_iter()
+        .map(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)))
+        .collect()
 }
@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+/// A write that is part of a `WriteBatch`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum BatchOperation {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+/// A group of writes applied atomically by `KvsEngine::write_batch`.
+///
+/// Either every write of the batch survives a crash or none does. Writes are
+/// applied in the order they were added, and removing a key that does not
+/// exist is not an error within a batch.
+///
+/// ```rust
+/// use kvs::WriteBatch;
+///
+/// let mut batch = WriteBatch::new();
+/// batch.set("from".to_owned(), "0".to_owned());
+/// batch.set("to".to_owned(), "100".to_owned());
+/// batch.remove("pending".to_owned());
+/// assert_eq!(batch.len(), 3);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct WriteBatch {
+    operations: Vec<BatchOperation>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.operations.push(BatchOperation::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+    }
+
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) {
+        self.operations
+            .push(BatchOperation::Remove { key: key.into() });
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    pub fn operations(&self) -> &[BatchOperation] {
+        &self.operations
+    }
+
+    pub fn into_operations(self) -> Vec<BatchOperation> {
+        self.operations
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use async_std::{
+    fs::{self, File},
+    io::BufReader,
+};
+
+use super::{
+    command::Command,
+    compression::Compressor,
+    constants,
+    format::read_file_header,
+    get_generations,
+    hint::HintEntry,
+    legacy::{legacy_layout, upgrade_json_layout, upgrade_unchecksummed_layout, LegacyLayout},
+    load, load_record,
+    lock::DirLock,
+    log_common::*,
+    manifest::Manifest,
+    options::KvStoreOptions,
+    record::Record,
+    store_files, truncate_log_file, KvStore,
+};
+use crate::{engines::now_millis, KvsError, Result};
+
+/// Offline maintenance of the directory of a `KvStore` that no process has
+/// open, as done by `kvs-admin`.
+///
+/// Every call reads the files it needs afresh, without building an index.
+pub struct KvsAdmin {
+    path: PathBuf,
+}
+
+/// The outcome of reading a log or blob file record by record.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileCheck {
+    pub path: PathBuf,
+    /// Whether the file is listed by the manifest but nowhere to be found.
+    pub missing: bool,
+    /// The number of intact records, a batch counting as one.
+    pub records: usize,
+    pub length: usize,
+    /// The offset right after the last intact record. Everything from there
+    /// on is damaged.
+    pub valid_length: usize,
+}
+
+impl FileCheck {
+    pub fn is_intact(&self) -> bool {
+        !self.missing && self.valid_length == self.length
+    }
+}
+
+/// A damaged log file, as found by [`KvsAdmin::repair`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct DamagedFile {
+    /// The check of the file as it was before the repair.
+    pub check: FileCheck,
+    /// Whether the file was cut short of its first damaged record.
+    pub truncated: bool,
+}
+
+/// A record of a generation, as listed by [`KvsAdmin::dump`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogEntry {
+    pub offset: usize,
+    /// The length of the record, header included.
+    pub length: usize,
+    /// Whether the record is one of the writes of a batch.
+    pub batched: bool,
+    pub operation: LogOperation,
+}
+
+/// The write a record of a generation stands for.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogOperation {
+    /// A set, expiring at `expires_at` in milliseconds since the Unix epoch
+    /// if given.
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+    /// A set whose value is kept in blob file `blob`, in the record at
+    /// `blob_offset` spanning `blob_length` bytes.
+    SetBlob {
+        key: Vec<u8>,
+        blob: u64,
+        blob_offset: usize,
+        blob_length: usize,
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+}
+
+impl From<Command> for LogOperation {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Set { key, value } => LogOperation::Set {
+                key,
+                value,
+                expires_at: None,
+            },
+            Command::SetExpiring {
+                key,
+                value,
+                expires_at,
+            } => LogOperation::Set {
+                key,
+                value,
+                expires_at: Some(expires_at),
+            },
+            Command::SetBlob {
+                key,
+                blob,
+                expires_at,
+            } => LogOperation::SetBlob {
+                key,
+                blob: blob.generation,
+                blob_offset: blob.offset,
+                blob_length: blob.length,
+                expires_at,
+            },
+            Command::Remove { key } => LogOperation::Remove { key },
+        }
+    }
+}
+
+/// How much of a store is live, by generation.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StoreUsage {
+    /// The number of keys holding a value that has not expired.
+    pub keys: usize,
+    pub generations: Vec<GenerationUsage>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GenerationUsage {
+    pub generation: u64,
+    pub bytes: usize,
+    /// The bytes of the records holding the current value of a key.
+    pub live_bytes: usize,
+}
+
+impl GenerationUsage {
+    /// The bytes compaction would reclaim: overwritten, removed and expired
+    /// values, the removals themselves, and any damaged tail. The format
+    /// header is counted in as well.
+    pub fn dead_bytes(&self) -> usize {
+        self.bytes - self.live_bytes
+    }
+}
+
+impl KvsAdmin {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        KvsAdmin { path: path.into() }
+    }
+
+    /// The generations making up the store, oldest first, as listed by the
+    /// manifest or by the log files found if the store predates it.
+    ///
+    /// A store of a legacy layout fails with `KvsError::OutdatedLayout`, its
+    /// log files not being made of checksummed records.
+    pub async fn generations(&self) -> Result<Vec<u64>> {
+        match Manifest::load(&self.path).await? {
+            Some(manifest) => Ok(manifest.generations().collect()),
+            None if legacy_layout(&self.path).await?.is_some() => {
+                Err(KvsError::OutdatedLayout(self.path.clone()))
+            }
+            None => get_generations(&self.path, "log"),
+        }
+    }
+
+    async fn blobs(&self) -> Result<Vec<u64>> {
+        match Manifest::load(&self.path).await? {
+            Some(manifest) => Ok(manifest.blobs().collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Reads every record of the log and blob files of the store, checking
+    /// them against their checksums.
+    pub async fn verify(&self) -> Result<Vec<FileCheck>> {
+        let mut checks = Vec::new();
+        for generation in self.generations().await? {
+            let (_, check) = read_records(&log_path(&self.path, generation), generation).await?;
+            checks.push(check);
+        }
+        for blob in self.blobs().await? {
+            let (_, check) = read_records(&blob_path(&self.path, blob), blob).await?;
+            checks.push(check);
+        }
+        Ok(checks)
+    }
+
+    /// Lists the records of `generation` in log order, the writes of a batch
+    /// each on their own, up to the first damaged record.
+    pub async fn dump(&self, generation: u64) -> Result<(Vec<LogEntry>, FileCheck)> {
+        let log_path = log_path(&self.path, generation);
+        if !log_path.exists() {
+            return Err(KvsError::StringError(format!(
+                "Generation {} not found",
+                generation
+            )));
+        }
+
+        let (records, check) = read_records(&log_path, generation).await?;
+        let mut entries = Vec::new();
+        for (offset, length, record) in records {
+            match record {
+                Record::Command(command) => entries.push(LogEntry {
+                    offset,
+                    length,
+                    batched: false,
+                    operation: command.into(),
+                }),
+                Record::Batch(commands) => {
+                    for (command, relative_offset, length) in commands {
+                        entries.push(LogEntry {
+                            offset: offset + relative_offset,
+                            length,
+                            batched: true,
+                            operation: command.into(),
+                        });
+                    }
+                }
+            }
+        }
+        Ok((entries, check))
+    }
+
+    /// Replays every generation to tell the live bytes of each from the dead.
+    pub async fn usage(&self) -> Result<StoreUsage> {
+        let generations = self.generations().await?;
+        let mut generation_bytes = Vec::with_capacity(generations.len());
+        // The generation and length of the record holding the value of each
+        // key, along with its expiry
+        let mut live: HashMap<Vec<u8>, (u64, usize, Option<u64>)> = HashMap::new();
+        for &generation in &generations {
+            let log_path = log_path(&self.path, generation);
+            // Left out when the store is opened, as `verify` reports
+            if !log_path.exists() {
+                continue;
+            }
+            let start = read_file_header(&log_path).await?;
+            let end_of_file = fs::metadata(&log_path).await?.len() as usize;
+            let mut reader = BufReader::new(File::open(&log_path).await?);
+            let (entries, _) = load(generation, &mut reader, start, end_of_file, true).await?;
+            for entry in entries {
+                match entry {
+                    HintEntry::Set { key, length, .. } => {
+                        live.insert(key, (generation, length, None));
+                    }
+                    HintEntry::SetExpiring {
+                        key,
+                        length,
+                        expires_at,
+                        ..
+                    } => {
+                        live.insert(key, (generation, length, Some(expires_at)));
+                    }
+                    HintEntry::SetBlob {
+                        key,
+                        length,
+                        expires_at,
+                        ..
+                    } => {
+                        live.insert(key, (generation, length, expires_at));
+                    }
+                    HintEntry::Remove { key, .. } => {
+                        live.remove(&key);
+                    }
+                }
+            }
+            generation_bytes.push((generation, end_of_file));
+        }
+
+        let now = now_millis();
+        let mut live_bytes: HashMap<u64, usize> = HashMap::new();
+        let mut keys = 0;
+        for (generation, length, expires_at) in live.values() {
+            if expires_at.is_none_or(|expires_at| expires_at > now) {
+                *live_bytes.entry(*generation).or_default() += length;
+                keys += 1;
+            }
+        }
+        Ok(StoreUsage {
+            keys,
+            generations: generation_bytes
+                .into_iter()
+                .map(|(generation, bytes)| GenerationUsage {
+                    generation,
+                    bytes,
+                    live_bytes: live_bytes.get(&generation).copied().unwrap_or(0),
+                })
+                .collect(),
+        })
+    }
+
+    /// Rewrites a store of a legacy layout, whose string commands are logged
+    /// as JSON into `0.log` or `1.log` or logged without checksums, into the
+    /// current layout, returning whether it was one.
+    ///
+    /// Files written before the format header was introduced are read as
+    /// they are, and need no upgrade.
+    pub async fn upgrade(&self) -> Result<bool> {
+        let _dir_lock = DirLock::acquire(&self.path)?;
+        match legacy_layout(&self.path).await? {
+            Some(LegacyLayout::Json) => {
+                upgrade_json_layout(&self.path, Compressor::default()).await?;
+                Ok(true)
+            }
+            Some(LegacyLayout::Unchecksummed) => {
+                upgrade_unchecksummed_layout(&self.path, Compressor::default()).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Opens the store with `options` and compacts it, then collects the
+    /// garbage of its blob files.
+    ///
+    /// Fails with `KvsError::StoreNotFound` if the directory holds no
+    /// manifest, rather than creating a store in a missing or mistyped
+    /// directory.
+    pub async fn compact(&self, options: KvStoreOptions) -> Result<()> {
+        if Manifest::load(&self.path).await?.is_none() {
+            return Err(KvsError::StoreNotFound(self.path.clone()));
+        }
+        let store = KvStore::open_with_options(&self.path, options).await?;
+        store.compact().await?;
+        store.compact_blobs().await
+    }
+
+    /// Cuts the newest generation short of its first damaged record, as
+    /// opening the store would, returning the damaged log files found.
+    ///
+    /// A sealed generation is only damaged by something other than a crash,
+    /// and cutting it short loses the records past the damage for good, so
+    /// it is left as it is and merely reported unless `truncate_sealed` is
+    /// set. Blob files are left as they are.
+    ///
+    /// Fails with `KvsError::Locked` if a process has the store open for
+    /// writing.
+    pub async fn repair(&self, truncate_sealed: bool) -> Result<Vec<DamagedFile>> {
+        let _dir_lock = DirLock::acquire(&self.path)?;
+        // The generations the store is opened with, so that the newest one is
+        // never a leftover of an interrupted compaction
+        let manifest = Manifest::load(&self.path).await?;
+        if manifest.is_none() && legacy_layout(&self.path).await?.is_some() {
+            return Err(KvsError::OutdatedLayout(self.path.clone()));
+        }
+        let (generations, _) = store_files(&self.path, manifest.as_ref())?;
+        let newest = generations.last().copied();
+        let mut damaged = Vec::new();
+        for generation in generations {
+            let log_path = log_path(&self.path, generation);
+            let (_, check) = read_records(&log_path, generation).await?;
+            if check.is_intact() || check.missing {
+                continue;
+            }
+
+            let truncated = truncate_sealed || Some(generation) == newest;
+            if truncated {
+                // The hint and the sorted table describe the log file as it was
+                for derived_path in &[
+                    hint_path(&self.path, generation),
+                    index_path(&self.path, generation),
+                ] {
+                    if derived_path.exists() {
+                        fs::remove_file(derived_path).await?;
+                    }
+                }
+                truncate_log_file(&log_path, check.valid_length).await?;
+            }
+            damaged.push(DamagedFile { check, truncated });
+        }
+        Ok(damaged)
+    }
+}
+
+/// Reads the records of the log or blob file at `path` up to the first damaged
+/// one, each along with its offset and length.
+async fn read_records(
+    path: &Path,
+    generation: u64,
+) -> Result<(Vec<(usize, usize, Record)>, FileCheck)> {
+    let mut check = FileCheck {
+        path: path.to_path_buf(),
+        missing: !path.exists(),
+        records: 0,
+        length: 0,
+        valid_length: 0,
+    };
+    if check.missing {
+        return Ok((Vec::new(), check));
+    }
+
+    let mut position = read_file_header(path).await?;
+    let end_of_file = fs::metadata(path).await?.len() as usize;
+    let mut reader =
+        BufReader::with_capacity(constants::DEFAULT_BUFFER_SIZE, File::open(path).await?);
+    let mut records = Vec::new();
+    while position < end_of_file {
+        let (record, data_block_size) =
+            match load_record(generation, &mut reader, position, end_of_file).await {
+                Ok(loaded) => loaded,
+                Err(KvsError::CorruptedLog { .. }) => break,
+                Err(e) => return Err(e),
+            };
+        records.push((position, data_block_size, record));
+        position += data_block_size;
+    }
+
+    check.records = records.len();
+    check.length = end_of_file;
+    check.valid_length = position;
+    Ok((records, check))
+}
@@ -0,0 +1,295 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use async_std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, SeekFrom},
+    prelude::*,
+    sync::Arc,
+    task,
+};
+
+use super::{
+    command::Command,
+    compaction::CompactionHandle,
+    format::FILE_HEADER_BYTES,
+    hint::HintEntry,
+    load_record,
+    log_common::*,
+    log_pointer::LogPointer,
+    record::{EncodedRecord, Record},
+    remove_orphaned_files, KvStore,
+};
+use crate::{engines::now_millis, KvsError, Result};
+
+/// Appends values too large for the log to a blob file.
+///
+/// Each value is stored as a `Set` record of its own, so that blob garbage
+/// collection can tell which key it belongs to.
+#[derive(Debug)]
+pub struct BlobWriter {
+    writer: BufWriter<File>,
+    path: Arc<PathBuf>,
+    buffer_size: usize,
+    pub generation: u64,
+    size: u64,
+    dirty: bool,
+}
+
+impl BlobWriter {
+    pub async fn open(path: Arc<PathBuf>, generation: u64, buffer_size: usize) -> Result<Self> {
+        let mut file = new_blob_file(&path, generation).await?;
+        let size = file.seek(SeekFrom::End(0)).await?;
+        Ok(BlobWriter {
+            writer: BufWriter::with_capacity(buffer_size, file),
+            path,
+            buffer_size,
+            generation,
+            size,
+            dirty: false,
+        })
+    }
+
+    /// Appends `record`, returning where it went.
+    ///
+    /// The record is handed over to the operating system before this returns,
+    /// ahead of the log record pointing to it.
+    pub async fn append(&mut self, record: &EncodedRecord) -> Result<LogPointer> {
+        let offset = self.size;
+        self.writer.write_all(&record.bytes).await?;
+        self.writer.flush().await?;
+        self.size += record.bytes.len() as u64;
+        self.dirty = true;
+        Ok((self.generation, offset..self.size).into())
+    }
+
+    pub async fn sync(&mut self) -> Result<()> {
+        if self.dirty {
+            self.writer.flush().await?;
+            self.writer.get_ref().sync_data().await?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// The number of bytes in the current blob file.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The number of bytes of the records in the current blob file.
+    pub fn record_bytes(&self) -> usize {
+        self.size as usize - FILE_HEADER_BYTES
+    }
+
+    /// Syncs the current blob file and moves on to `generation`.
+    pub async fn refresh(&mut self, generation: u64) -> Result<()> {
+        self.sync().await?;
+        let mut file = new_blob_file(&self.path, generation).await?;
+        self.size = file.seek(SeekFrom::End(0)).await?;
+        self.writer = BufWriter::with_capacity(self.buffer_size, file);
+        self.generation = generation;
+        Ok(())
+    }
+}
+
+/// Reads the value of the `Set` record `blob` points to.
+///
+/// Blob files are not kept open between reads: the values they hold are large
+/// enough for opening the file to be cheap in comparison, and a blob file
+/// deleted by garbage collection is not held on to.
+pub(super) async fn read_blob(dir: &Path, blob: LogPointer) -> Result<Vec<u8>> {
+    let file = File::open(blob_path(dir, blob.generation)).await?;
+    let mut reader = BufReader::new(file);
+    let end_of_blob = blob.offset + blob.length;
+    match load_record(blob.generation, &mut reader, blob.offset, end_of_blob).await? {
+        (Record::Command(Command::Set { value, .. }), _) => Ok(value),
+        _ => Err(KvsError::UnexpectedCommandType),
+    }
+}
+
+/// A value still referred to by the index, found in a blob file.
+struct LiveBlob {
+    key: Vec<u8>,
+    /// The log record pointing to the value.
+    log_pointer: LogPointer,
+    blob: LogPointer,
+    expires_at: Option<u64>,
+}
+
+impl KvStore {
+    /// Starts collecting the garbage of the blob files in the background.
+    ///
+    /// The active blob file is sealed first. Every sealed blob file in which
+    /// at least `blob_garbage_ratio` of the bytes belong to values overwritten,
+    /// removed or expired since then has its live values logged anew, after
+    /// which it is deleted. Log compaction copies the pointers to blobs rather
+    /// than the values, so it leaves this to blob garbage collection.
+    pub fn compact_blobs(&self) -> CompactionHandle {
+        let store = self.for_background();
+        let cancelled = self.running_compactions.start();
+        let task_cancelled = Arc::clone(&cancelled);
+        let task = task::spawn(async move { store.run_blob_compaction(&task_cancelled).await });
+
+        CompactionHandle { cancelled, task }
+    }
+
+    pub(super) async fn run_blob_compaction(&self, cancelled: &AtomicBool) -> Result<()> {
+        let _compaction_guard = self.compaction_lock.lock().await;
+
+        let sealed_blobs: BTreeSet<u64> = {
+            let mut writer = self.kvs_writer()?.lock().await;
+            let mut manifest = self.manifest.lock().await;
+            if let Some(blob_writer) = &mut writer.blob_writer {
+                self.garbage
+                    .seal_blob(blob_writer.generation, blob_writer.record_bytes());
+                let active_blob = blob_writer.generation + 1;
+                manifest.insert_blob(active_blob);
+                manifest.store(&self.path).await?;
+                blob_writer.refresh(active_blob).await?;
+            }
+            let active_blob = writer.blob_writer.as_ref().map(|w| w.generation);
+            manifest
+                .blobs()
+                .filter(|&blob| Some(blob) != active_blob)
+                .collect()
+        };
+        if sealed_blobs.is_empty() {
+            return Ok(());
+        }
+
+        let mut live_blobs = self.find_live_blobs(&sealed_blobs, cancelled).await?;
+        let mut collected = Vec::new();
+        for generation in sealed_blobs {
+            if cancelled.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let live_blobs = live_blobs.remove(&generation).unwrap_or_default();
+            let blob_bytes = fs::metadata(blob_path(&self.path, generation)).await?.len() as usize;
+            let live_bytes: usize = live_blobs.iter().map(|live| live.blob.length).sum();
+            let garbage_bytes = blob_bytes.saturating_sub(live_bytes);
+            if blob_bytes > 0
+                && (garbage_bytes as f64) < self.options.blob_garbage_ratio * blob_bytes as f64
+            {
+                continue;
+            }
+
+            self.relocate_blobs(live_blobs).await?;
+            collected.push(generation);
+        }
+
+        if !collected.is_empty() {
+            // The relocated values must be durable before the blob files they
+            // come from are gone
+            self.kvs_writer()?.lock().await.sync().await?;
+
+            let mut manifest = self.manifest.lock().await;
+            for &generation in &collected {
+                manifest.remove_blob(generation);
+            }
+            self.garbage.remove_blobs(&collected);
+            manifest.store(&self.path).await?;
+            self.pinned.retire(&[], &collected);
+            remove_orphaned_files(&self.path, &manifest, &self.pinned).await?;
+        }
+
+        if cancelled.load(Ordering::SeqCst) {
+            return Err(KvsError::CompactionCancelled);
+        }
+        Ok(())
+    }
+
+    /// Lists the values of `sealed_blobs` that the index still refers to and
+    /// that have not expired, by blob file.
+    ///
+    /// The index rather than the blob files is authoritative, so a value torn
+    /// by a crash cannot hide the values after it.
+    async fn find_live_blobs(
+        &self,
+        sealed_blobs: &BTreeSet<u64>,
+        cancelled: &AtomicBool,
+    ) -> Result<BTreeMap<u64, Vec<LiveBlob>>> {
+        let mut live_blobs: BTreeMap<u64, Vec<LiveBlob>> = BTreeMap::new();
+        let mut cursor = self.index.cursor();
+        while let Some((key, log_pointer)) = cursor.next().await? {
+            if cancelled.load(Ordering::SeqCst) {
+                return Err(KvsError::CompactionCancelled);
+            }
+
+            if let Command::SetBlob {
+                blob, expires_at, ..
+            } = self.kvs_reader.read_log_command(log_pointer).await?
+            {
+                let expired = expires_at.is_some_and(|expires_at| expires_at <= now_millis());
+                if sealed_blobs.contains(&blob.generation) && !expired {
+                    live_blobs
+                        .entry(blob.generation)
+                        .or_default()
+                        .push(LiveBlob {
+                            key,
+                            log_pointer,
+                            blob,
+                            expires_at,
+                        });
+                }
+            }
+        }
+
+        Ok(live_blobs)
+    }
+
+    /// Logs the values of `live_blobs` anew, skipping those written to since
+    /// they were found.
+    ///
+    /// The values go to the active blob file, or into the log itself if blob
+    /// files are disabled by now.
+    async fn relocate_blobs(&self, live_blobs: Vec<LiveBlob>) -> Result<()> {
+        let mut writer = self.kvs_writer()?.lock().await;
+        let mut records = Vec::with_capacity(live_blobs.len());
+        for live in live_blobs {
+            if self.index.get(&live.key).await? != Some(live.log_pointer) {
+                continue;
+            }
+
+            let value = read_blob(&self.path, live.blob).await?;
+            let command = match live.expires_at {
+                Some(expires_at) => Command::SetExpiring {
+                    key: live.key,
+                    value,
+                    expires_at,
+                },
+                None => Command::Set {
+                    key: live.key,
+                    value,
+                },
+            };
+            records.push(writer.encode_record(command).await?);
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        let offsets = self.append_records(&mut writer, &records).await?;
+        for (record, offset) in records.into_iter().zip(offsets) {
+            for (command, relative_offset, length) in record.commands {
+                let entry = HintEntry::new(&command, offset as usize + relative_offset, length);
+                self.index
+                    .apply(
+                        writer.current_generation,
+                        entry,
+                        &self.expiries,
+                        &self.kvs_reader.cache,
+                        &self.garbage,
+                        &self.versions,
+                    )
+                    .await;
+            }
+        }
+
+        self.roll_if_oversized(&mut writer).await
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{command::Command, log_pointer::LogPointer, KvStore};
+
+/// How well the value cache of a `KvStore` has been doing since the store
+/// was opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct CacheStats {
+    /// The number of reads served from the cache.
+    pub hits: u64,
+    /// The number of reads that went to disk.
+    pub misses: u64,
+    /// The number of values held.
+    pub entries: usize,
+    /// The number of key and value bytes held.
+    pub bytes: usize,
+}
+
+/// Decoded commands by the log pointer they were read from, evicting the
+/// least recently used once their keys and values take up more than
+/// `capacity` bytes.
+///
+/// A log pointer is never reused for another record, so a cached command
+/// cannot go stale. Commands are dropped as soon as the index stops pointing
+/// to them all the same, so as not to hold on to dead values.
+#[derive(Debug)]
+pub struct ValueCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<LogPointer, (Command, u64)>,
+    // The pointers by when they were last read, least recently first
+    recency: BTreeMap<u64, LogPointer>,
+    clock: u64,
+    bytes: usize,
+}
+
+impl ValueCache {
+    pub fn new(capacity: usize) -> Self {
+        ValueCache {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, log_pointer: LogPointer) -> Option<Command> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let command = match state.entries.get_mut(&log_pointer) {
+            Some((command, last_read)) => {
+                state.clock += 1;
+                state.recency.remove(last_read);
+                state.recency.insert(state.clock, log_pointer);
+                *last_read = state.clock;
+                Some(command.clone())
+            }
+            None => None,
+        };
+        drop(guard);
+
+        let counter = if command.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+        command
+    }
+
+    /// Caches `command`, unless it is larger than the whole cache.
+    pub fn insert(&self, log_pointer: LogPointer, command: &Command) {
+        let size = cached_size(command);
+        if self.capacity == 0 || size > self.capacity {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.remove(log_pointer);
+        state.clock += 1;
+        let clock = state.clock;
+        state.entries.insert(log_pointer, (command.clone(), clock));
+        state.recency.insert(clock, log_pointer);
+        state.bytes += size;
+        while state.bytes > self.capacity {
+            let oldest = match state.recency.values().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            state.remove(oldest);
+        }
+    }
+
+    /// Drops the command read from `log_pointer`, if cached.
+    pub fn invalidate(&self, log_pointer: LogPointer) {
+        if self.capacity > 0 {
+            self.state.lock().unwrap().remove(log_pointer);
+        }
+    }
+
+    /// Drops the commands read from `generations`, once compaction has
+    /// replaced them.
+    pub fn invalidate_generations(&self, generations: &BTreeSet<u64>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let compacted: Vec<LogPointer> = state
+            .entries
+            .keys()
+            .filter(|log_pointer| generations.contains(&log_pointer.generation))
+            .copied()
+            .collect();
+        for log_pointer in compacted {
+            state.remove(log_pointer);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::SeqCst),
+            misses: self.misses.load(Ordering::SeqCst),
+            entries: state.entries.len(),
+            bytes: state.bytes,
+        }
+    }
+}
+
+impl CacheState {
+    fn remove(&mut self, log_pointer: LogPointer) {
+        if let Some((command, last_read)) = self.entries.remove(&log_pointer) {
+            self.recency.remove(&last_read);
+            self.bytes -= cached_size(&command);
+        }
+    }
+}
+
+fn cached_size(command: &Command) -> usize {
+    match command {
+        Command::Set { key, value } | Command::SetExpiring { key, value, .. } => {
+            key.len() + value.len()
+        }
+        command => command.key().len(),
+    }
+}
+
+impl KvStore {
+    /// Reports the hits and misses of the value cache, along with what it
+    /// holds, to help size it through `value_cache_size`.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.kvs_reader.cache.stats()
+    }
+}
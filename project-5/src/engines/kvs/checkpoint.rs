@@ -0,0 +1,138 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use async_std::task;
+
+use super::{
+    log_common::*,
+    manifest::{sync_dir, Manifest},
+    KvStore,
+};
+use crate::{engines::prepare_checkpoint_dir, KvsError, Result};
+
+impl KvStore {
+    /// Writes a consistent copy of the store to `dest`.
+    ///
+    /// The files making up the store are pinned while writes are held off,
+    /// and the length of the generation and blob file being appended to is
+    /// taken down. Every other file is sealed and copied whole, the files being
+    /// appended to up to that length. No file is hard-linked, so that
+    /// repairing or truncating a file of either the store or the copy leaves
+    /// the other as it is. Hint files and sorted tables are left out, to be
+    /// rebuilt when the copy is first opened. The copy is synced to disk
+    /// before this returns.
+    ///
+    /// `dest` must be empty if it exists, and must not lie within the store.
+    pub(super) async fn write_checkpoint(&self, dest: PathBuf) -> Result<()> {
+        if lies_within(&dest, &self.path)? {
+            return Err(KvsError::CheckpointDirInStore(dest));
+        }
+        prepare_checkpoint_dir(&dest)?;
+        if let Some(parent) = dest.parent().filter(|parent| parent.exists()) {
+            sync_dir(parent)?;
+        }
+
+        let writer = match &self.kvs_writer {
+            Some(kvs_writer) => Some(kvs_writer.lock().await),
+            None => None,
+        };
+        let manifest = self.manifest.lock().await;
+        let generations: Vec<u64> = manifest.generations().collect();
+        let blobs: Vec<u64> = manifest.blobs().collect();
+        let _pin = self.pin(generations.clone(), blobs.clone());
+        drop(manifest);
+
+        // The length each file is copied up to, none for a sealed file
+        let mut files: BTreeMap<PathBuf, Option<u64>> = generations
+            .iter()
+            .map(|&generation| (log_path(&self.path, generation), None))
+            .chain(
+                blobs
+                    .iter()
+                    .map(|&blob| (blob_path(&self.path, blob), None)),
+            )
+            .collect();
+        match writer {
+            Some(mut writer) => {
+                // Every acknowledged write must be in the files to be copied
+                writer.sync().await?;
+                files.insert(
+                    log_path(&self.path, writer.current_generation),
+                    Some(writer.size()),
+                );
+                if let Some(blob_writer) = &writer.blob_writer {
+                    files.insert(
+                        blob_path(&self.path, blob_writer.generation),
+                        Some(blob_writer.size()),
+                    );
+                }
+            }
+            // A read-only store may share its directory with a writer, which
+            // only ever appends to the newest generation and blob file
+            None => {
+                let newest = generations
+                    .last()
+                    .map(|&generation| log_path(&self.path, generation))
+                    .into_iter()
+                    .chain(blobs.last().map(|&blob| blob_path(&self.path, blob)));
+                for path in newest {
+                    let length = fs::metadata(&path)?.len();
+                    files.insert(path, Some(length));
+                }
+            }
+        }
+
+        let copy_dest = dest.clone();
+        task::spawn_blocking(move || {
+            for (path, length) in files {
+                let file_name = path.file_name().expect("store files are named");
+                copy_file(&path, &copy_dest.join(file_name), length)?;
+            }
+            Ok::<_, io::Error>(())
+        })
+        .await?;
+
+        // Storing the manifest syncs `dest`, along with the entries of the
+        // files copied into it
+        let mut checkpoint_manifest = Manifest::new(generations);
+        for blob in blobs {
+            checkpoint_manifest.insert_blob(blob);
+        }
+        checkpoint_manifest.store(&dest).await
+    }
+}
+
+/// Whether `dest` is `dir` or lies within it, once both are made absolute
+/// with the symbolic links of the part of `dest` that exists resolved.
+fn lies_within(dest: &Path, dir: &Path) -> Result<bool> {
+    let dir = dir.canonicalize()?;
+    let dest = std::env::current_dir()?.join(dest);
+    let mut existing = dest.as_path();
+    let mut missing = Vec::new();
+    while !existing.exists() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+
+    let mut dest = existing.canonicalize()?;
+    dest.extend(missing.into_iter().rev());
+    Ok(dest.starts_with(dir))
+}
+
+/// Copies the first `length` bytes of `source` to `dest`, or all of it if no
+/// length is given, and syncs the copy.
+fn copy_file(source: &Path, dest: &Path, length: Option<u64>) -> io::Result<()> {
+    let mut reader = fs::File::open(source)?.take(length.unwrap_or(u64::MAX));
+    let mut writer = fs::File::create(dest)?;
+    io::copy(&mut reader, &mut writer)?;
+    writer.sync_all()
+}
@@ -1,7 +1,52 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+use super::log_pointer::LogPointer;
+
+/// A write as logged on disk.
+///
+/// bincode encodes `Vec<u8>` the same way as `String`, so logs written back
+/// when keys and values were strings still decode.
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    /// A set whose value expires at `expires_at`, in milliseconds since the
+    /// Unix epoch.
+    SetExpiring {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: u64,
+    },
+    /// A set whose value is kept in a blob file, as the `Set` record `blob`
+    /// points to, expiring at `expires_at` if given.
+    SetBlob {
+        key: Vec<u8>,
+        blob: LogPointer,
+        expires_at: Option<u64>,
+    },
+}
+
+impl Command {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Command::Set { key, .. }
+            | Command::Remove { key }
+            | Command::SetExpiring { key, .. }
+            | Command::SetBlob { key, .. } => key,
+        }
+    }
+
+    /// When the value set by the command expires, if ever.
+    pub fn expires_at(&self) -> Option<u64> {
+        match self {
+            Command::SetExpiring { expires_at, .. } => Some(*expires_at),
+            Command::SetBlob { expires_at, .. } => *expires_at,
+            Command::Set { .. } | Command::Remove { .. } => None,
+        }
+    }
 }
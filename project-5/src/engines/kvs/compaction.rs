@@ -0,0 +1,652 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    ops::RangeInclusive,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use async_std::{
+    fs::{self, File},
+    future::Future,
+    io::BufReader,
+    sync::Arc,
+    task::{self, JoinHandle},
+};
+use log::error;
+
+use super::{
+    command::Command,
+    format::read_file_header,
+    hint::{read_hint_file, HintEntry},
+    index::Index,
+    load,
+    log_common::*,
+    log_pointer::LogPointer,
+    remove_orphaned_files,
+    table::{SortedTable, TableWriter},
+    writer::KvsWriter,
+    KvStore,
+};
+use crate::{engines::now_millis, KvsError, Result};
+
+/// A handle to a compaction running in the background.
+///
+/// Awaiting the handle yields the outcome of the compaction. Dropping it lets
+/// the compaction run to completion unobserved, unless the store is closed
+/// first: dropping the last handle to the store cancels the compaction.
+pub struct CompactionHandle {
+    pub(super) cancelled: Arc<AtomicBool>,
+    pub(super) task: JoinHandle<Result<()>>,
+}
+
+impl CompactionHandle {
+    /// Asks the compaction to stop as soon as possible.
+    ///
+    /// A cancelled compaction discards its partial output and leaves the
+    /// store as it was, and the handle resolves to `KvsError::CompactionCancelled`.
+    /// Cancelling has no effect once the compaction has been committed.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+/// The cancellation flags of the compactions started, so that they are all
+/// cancelled once the store is closed.
+#[derive(Debug, Default)]
+pub(super) struct RunningCompactions {
+    closed: AtomicBool,
+    cancelled: std::sync::Mutex<Vec<Weak<AtomicBool>>>,
+}
+
+impl RunningCompactions {
+    /// The cancellation flag of a new compaction, raised from the start if
+    /// the store is closed.
+    pub(super) fn start(&self) -> Arc<AtomicBool> {
+        let mut flags = self.cancelled.lock().unwrap();
+        flags.retain(|cancelled| cancelled.strong_count() > 0);
+        let cancelled = Arc::new(AtomicBool::new(self.closed.load(Ordering::SeqCst)));
+        flags.push(Arc::downgrade(&cancelled));
+        cancelled
+    }
+
+    pub(super) fn cancel_all(&self) {
+        let flags = self.cancelled.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        for cancelled in flags.iter().filter_map(Weak::upgrade) {
+            cancelled.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// The compactions committed since the store was opened.
+#[derive(Debug, Default)]
+pub(super) struct CompactionHistory {
+    count: AtomicU64,
+    millis: AtomicU64,
+    // When the last one was committed, in milliseconds since the Unix epoch,
+    // or zero if none was
+    last_committed_at: AtomicU64,
+}
+
+impl CompactionHistory {
+    fn record(&self, duration: Duration) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.millis
+            .fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+        self.last_committed_at.store(now_millis(), Ordering::SeqCst);
+    }
+
+    pub(super) fn count(&self) -> u64 {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// The time spent in the compactions committed, in milliseconds.
+    pub(super) fn millis(&self) -> u64 {
+        self.millis.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn last_committed_at(&self) -> Option<u64> {
+        Some(self.last_committed_at.load(Ordering::SeqCst)).filter(|&at| at > 0)
+    }
+}
+
+/// Which generations a compaction merges.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionMode {
+    /// Every generation, the active one included, so that nothing stale is
+    /// left behind.
+    Full,
+    /// Only the sealed generations in which at least `min_garbage_ratio` of
+    /// the bytes are stale, up to `max_generations` of those with the most,
+    /// so that the cost of a compaction follows the amount of garbage rather
+    /// than the size of the store. Generations with less garbage are left
+    /// untouched.
+    Incremental {
+        min_garbage_ratio: f64,
+        max_generations: usize,
+    },
+}
+
+impl Future for CompactionHandle {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task).poll(cx)
+    }
+}
+
+/// The outcome of copying the live entries of the compacted generations.
+struct CopiedEntries {
+    /// Each copied key, with its pointers before and after the copy, if the
+    /// index is held in memory.
+    compacted: Vec<(Vec<u8>, LogPointer, LogPointer)>,
+    /// The sorted tables of the generations written to, if the index is on disk.
+    tables: Vec<SortedTable>,
+    /// Each key left behind because its value expired, with its pointer.
+    expired: Vec<(Vec<u8>, LogPointer)>,
+    /// The pointers before and after the copy of each record whose value lies
+    /// in a blob file.
+    blob_refs: Vec<(LogPointer, LogPointer)>,
+    /// The bytes of the removals written, by generation.
+    removals: BTreeMap<u64, usize>,
+    /// The last generation written to.
+    last_generation: u64,
+    compacted_bytes: usize,
+}
+
+/// Writes the compaction output, moving on to the next generation of
+/// `compaction_generations` whenever a segment is full.
+struct CompactionOutput {
+    path: Arc<PathBuf>,
+    writer: KvsWriter,
+    last_generation: u64,
+    max_segment_size: Option<u64>,
+    // The sorted table of the current generation, if the index is on disk.
+    // The keys are written in order, so the tables are written along.
+    table_writer: Option<TableWriter>,
+    copied: CopiedEntries,
+}
+
+impl CompactionOutput {
+    async fn open(store: &KvStore, compaction_generations: &RangeInclusive<u64>) -> Result<Self> {
+        let first_generation = *compaction_generations.start();
+        let mut writer = KvsWriter::open(
+            Arc::clone(&store.path),
+            first_generation,
+            store.options.write_buffer_size,
+            store.options.compressor(),
+        )
+        .await?;
+        let table_writer = match &*store.index {
+            Index::InMemory(_) => None,
+            Index::OnDisk(_) => {
+                writer.disable_hints();
+                Some(TableWriter::create(&store.path, first_generation).await?)
+            }
+        };
+
+        Ok(CompactionOutput {
+            path: Arc::clone(&store.path),
+            writer,
+            last_generation: *compaction_generations.end(),
+            max_segment_size: store.options.max_segment_size,
+            table_writer,
+            copied: CopiedEntries {
+                compacted: Vec::new(),
+                tables: Vec::new(),
+                expired: Vec::new(),
+                blob_refs: Vec::new(),
+                removals: BTreeMap::new(),
+                last_generation: first_generation,
+                compacted_bytes: 0,
+            },
+        })
+    }
+
+    /// Writes `command`, the value of `key` found at `sealed_pointer` or its
+    /// removal.
+    async fn write(
+        &mut self,
+        key: Vec<u8>,
+        sealed_pointer: Option<LogPointer>,
+        command: Command,
+    ) -> Result<()> {
+        if let Some(max_segment_size) = self.max_segment_size {
+            let generation = self.writer.current_generation;
+            if self.writer.size() >= max_segment_size && generation < self.last_generation {
+                let on_disk = self.table_writer.is_some();
+                self.finish_table().await?;
+                self.writer.refresh(generation + 1).await?;
+                if on_disk {
+                    self.table_writer =
+                        Some(TableWriter::create(&self.path, generation + 1).await?);
+                }
+            }
+        }
+
+        let generation = self.writer.current_generation;
+        let entry = self.writer.write_command(command).await?;
+        self.copied.compacted_bytes += entry.length();
+        if let HintEntry::Remove { length, .. } = entry {
+            *self.copied.removals.entry(generation).or_default() += length;
+        }
+        if let (Some(sealed_pointer), Some(compacted_pointer), Some(_)) =
+            (sealed_pointer, entry.pointer(generation), entry.blob())
+        {
+            self.copied
+                .blob_refs
+                .push((sealed_pointer, compacted_pointer));
+        }
+        match &mut self.table_writer {
+            Some(writer) => writer.push(entry).await?,
+            None => {
+                if let (Some(sealed_pointer), Some(compacted_pointer)) =
+                    (sealed_pointer, entry.pointer(generation))
+                {
+                    self.copied
+                        .compacted
+                        .push((key, sealed_pointer, compacted_pointer));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn finish_table(&mut self) -> Result<()> {
+        if let Some(writer) = self.table_writer.take() {
+            let generation = self.writer.current_generation;
+            let stale_bytes = self.copied.removals.get(&generation).copied();
+            let table = writer
+                .finish(self.writer.size() as usize, stale_bytes.unwrap_or(0))
+                .await?;
+            self.copied.tables.push(table);
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<CopiedEntries> {
+        self.writer.seal().await?;
+        self.finish_table().await?;
+        self.copied.last_generation = self.writer.current_generation;
+        Ok(self.copied)
+    }
+}
+
+impl KvStore {
+    /// Starts compacting the store in the background, as `compaction_mode`
+    /// says.
+    ///
+    /// Writes keep going to a fresh generation while the compacted ones are
+    /// merged. If another compaction is in progress, this one starts after it
+    /// finishes. The compaction does not keep the store open: it is cancelled
+    /// once every handle to the store is dropped, and the store can be opened
+    /// again as soon as it stops.
+    pub fn compact(&self) -> CompactionHandle {
+        let store = self.for_background();
+        let cancelled = self.running_compactions.start();
+        let task_cancelled = Arc::clone(&cancelled);
+        let task = task::spawn(async move { store.run_compaction(&task_cancelled).await });
+
+        CompactionHandle { cancelled, task }
+    }
+
+    /// Starts a background compaction if the amount of stale data calls for one
+    /// and none has been started by an earlier write.
+    ///
+    /// The log is compacted first; once it no longer needs to be, the blob
+    /// files are garbage collected if one of them has at least
+    /// `blob_garbage_ratio` of its bytes stale.
+    pub(super) fn maybe_compact(&self) {
+        let compact_log = self.needs_compaction();
+        if !compact_log
+            && !self
+                .garbage
+                .has_blob_garbage(self.options.blob_garbage_ratio)
+        {
+            return;
+        }
+        if self.compacting.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let store = self.for_background();
+        let cancelled = self.running_compactions.start();
+        task::spawn(async move {
+            let compacted = if compact_log {
+                store.run_compaction(&cancelled).await
+            } else {
+                store.run_blob_compaction(&cancelled).await
+            };
+            match compacted {
+                Ok(()) | Err(KvsError::CompactionCancelled) => (),
+                Err(e) => error!("Background compaction failed: {}", e),
+            }
+            store.compacting.store(false, Ordering::SeqCst);
+        });
+    }
+
+    fn needs_compaction(&self) -> bool {
+        let uncompacted = self.garbage.total();
+        if uncompacted <= self.options.compaction_threshold {
+            return false;
+        }
+
+        match self.options.compaction_dead_ratio {
+            Some(ratio) => {
+                uncompacted as f64 >= ratio * self.log_bytes.load(Ordering::SeqCst) as f64
+            }
+            None => true,
+        }
+    }
+
+    /// Merges the generations `compaction_mode` picks into new ones.
+    ///
+    /// The active generation is sealed first and replaced by a fresh one, so
+    /// writers only wait for the writer lock while generations are switched and
+    /// while the result is committed. The generations in between are reserved
+    /// for the compaction output, which is split into segments of at most
+    /// `max_segment_size` bytes like the generations it replaces. The output
+    /// only becomes part of the store once the manifest naming it has been
+    /// committed; until then, a crash leaves the compacted generations in
+    /// charge and the output is discarded on the next open.
+    async fn run_compaction(&self, cancelled: &AtomicBool) -> Result<()> {
+        let _compaction_guard = self.compaction_lock.lock().await;
+        let started = Instant::now();
+
+        let (compacted, compacted_bytes, reserved, sealed_generation, oldest_kept) = {
+            let mut writer = self.kvs_writer()?.lock().await;
+            let mut manifest = self.manifest.lock().await;
+            let sealed_generation = writer.current_generation;
+            let (compacted, compacted_bytes) = match self.options.compaction_mode {
+                CompactionMode::Full => (
+                    manifest.generations().collect::<BTreeSet<_>>(),
+                    self.log_bytes.load(Ordering::SeqCst),
+                ),
+                CompactionMode::Incremental {
+                    min_garbage_ratio,
+                    max_generations,
+                } => {
+                    self.select_generations(
+                        manifest
+                            .generations()
+                            .filter(|&generation| generation < sealed_generation),
+                        min_garbage_ratio,
+                        max_generations,
+                    )
+                    .await?
+                }
+            };
+            if compacted.is_empty() {
+                return Ok(());
+            }
+            let oldest_kept = manifest
+                .generations()
+                .find(|generation| !compacted.contains(generation));
+
+            // Every output segment but the last holds at least `max_segment_size`
+            // bytes, and the output is never larger than the compacted generations
+            let reserved = match self.options.max_segment_size {
+                Some(max_segment_size) => compacted_bytes as u64 / max_segment_size.max(1) + 1,
+                None => 1,
+            };
+            let active_generation = sealed_generation + reserved + 1;
+            manifest.insert(active_generation);
+            manifest.store(&self.path).await?;
+            self.switch_generation(&mut writer, active_generation)
+                .await?;
+
+            (
+                compacted,
+                compacted_bytes,
+                reserved,
+                sealed_generation,
+                oldest_kept,
+            )
+        };
+        let compaction_generations = (sealed_generation + 1)..=(sealed_generation + reserved);
+
+        let copied = match oldest_kept {
+            None => {
+                self.copy_live_entries(&compacted, &compaction_generations, cancelled)
+                    .await
+            }
+            Some(oldest_kept) => {
+                self.copy_compacted_entries(
+                    &compacted,
+                    oldest_kept,
+                    &compaction_generations,
+                    cancelled,
+                )
+                .await
+            }
+        };
+        let copied = match copied {
+            Ok(copied) => copied,
+            Err(e) => {
+                for generation in compaction_generations {
+                    for compaction_path in &[
+                        hint_path(&self.path, generation),
+                        index_path(&self.path, generation),
+                        log_path(&self.path, generation),
+                    ] {
+                        if compaction_path.exists() {
+                            if let Err(e) = fs::remove_file(compaction_path).await {
+                                error!("{:?} cannot be deleted: {}", compaction_path, e);
+                            }
+                        }
+                    }
+                }
+                return Err(e);
+            }
+        };
+        let first_generation = *compaction_generations.start();
+
+        let writer = self.kvs_writer()?.lock().await;
+        let mut manifest = self.manifest.lock().await;
+        let committed = manifest.with_generations(
+            manifest
+                .generations()
+                .filter(|generation| !compacted.contains(generation))
+                .chain(first_generation..=copied.last_generation),
+        );
+        committed.store(&self.path).await?;
+        *manifest = committed;
+        self.pinned
+            .retire(&compacted.iter().copied().collect::<Vec<_>>(), &[]);
+
+        // Keys written since the generations were sealed keep their newer value;
+        // their copy in the compaction output is stale from the start. With the
+        // index on disk, the copies are not tracked one by one, and are only
+        // found to be stale by the next compaction.
+        for (sealed_pointer, compacted_pointer) in copied.blob_refs {
+            self.garbage
+                .move_blob_ref(sealed_pointer, compacted_pointer);
+        }
+        self.garbage.remove(&compacted);
+        match &*self.index {
+            Index::InMemory(index_map) => {
+                for (key, sealed_pointer, compacted_pointer) in copied.compacted {
+                    match index_map.get(&key) {
+                        Some(entry) if *entry.value() == sealed_pointer => {
+                            index_map.insert(key, compacted_pointer);
+                        }
+                        _ => self.garbage.add_replaced(Some(compacted_pointer)),
+                    }
+                }
+                for (key, sealed_pointer) in copied.expired {
+                    if let Some(entry) = index_map.get(&key) {
+                        if *entry.value() == sealed_pointer {
+                            entry.remove();
+                            self.expiries.remove(&key);
+                        }
+                    }
+                }
+            }
+            // The expired values are left out of the tables replacing those
+            // they were found in
+            Index::OnDisk(sorted) => sorted.replace_tables(&compacted, copied.tables),
+        }
+        for (&generation, &bytes) in &copied.removals {
+            self.garbage.add(generation, bytes);
+        }
+        self.log_bytes.fetch_sub(compacted_bytes, Ordering::SeqCst);
+        self.log_bytes
+            .fetch_add(copied.compacted_bytes, Ordering::SeqCst);
+        self.kvs_reader.cache.invalidate_generations(&compacted);
+        drop(writer);
+
+        self.kvs_reader.close_stale_readers(&compacted);
+        self.compactions.record(started.elapsed());
+
+        remove_orphaned_files(&self.path, &manifest, &self.pinned).await?;
+
+        Ok(())
+    }
+
+    /// Picks up to `max_generations` of `sealed_generations` in which at least
+    /// `min_garbage_ratio` of the bytes are stale, most stale bytes first,
+    /// returning them along with their total size.
+    async fn select_generations(
+        &self,
+        sealed_generations: impl Iterator<Item = u64>,
+        min_garbage_ratio: f64,
+        max_generations: usize,
+    ) -> Result<(BTreeSet<u64>, usize)> {
+        let garbage = self.garbage.by_generation();
+        let mut candidates = Vec::new();
+        for generation in sealed_generations {
+            let garbage_bytes = garbage.get(&generation).copied().unwrap_or(0);
+            let log_bytes = fs::metadata(log_path(&self.path, generation)).await?.len() as usize;
+            if garbage_bytes > 0 && garbage_bytes as f64 >= min_garbage_ratio * log_bytes as f64 {
+                candidates.push((generation, garbage_bytes, log_bytes));
+            }
+        }
+        candidates.sort_by_key(|&(_, garbage_bytes, _)| Reverse(garbage_bytes));
+        candidates.truncate(max_generations);
+
+        let compacted_bytes = candidates.iter().map(|&(_, _, log_bytes)| log_bytes).sum();
+        let compacted = candidates
+            .into_iter()
+            .map(|(generation, _, _)| generation)
+            .collect();
+        Ok((compacted, compacted_bytes))
+    }
+
+    /// Copies the live entries of the `compacted` generations, which include
+    /// every generation of the store, into `compaction_generations`.
+    ///
+    /// The index lists every live key in order, so nothing else is read than
+    /// the values copied. Expired values are left behind.
+    async fn copy_live_entries(
+        &self,
+        compacted: &BTreeSet<u64>,
+        compaction_generations: &RangeInclusive<u64>,
+        cancelled: &AtomicBool,
+    ) -> Result<CopiedEntries> {
+        let mut output = CompactionOutput::open(self, compaction_generations).await?;
+        let mut cursor = self.index.cursor();
+        while let Some((key, sealed_pointer)) = cursor.next().await? {
+            if cancelled.load(Ordering::SeqCst) {
+                return Err(KvsError::CompactionCancelled);
+            }
+
+            if !compacted.contains(&sealed_pointer.generation) {
+                continue;
+            }
+
+            // Values kept in blob files stay there, only the pointers are copied
+            let command = self.kvs_reader.read_log_command(sealed_pointer).await?;
+            if let Some(expires_at) = command.expires_at() {
+                if expires_at <= now_millis() {
+                    output.copied.expired.push((key, sealed_pointer));
+                    continue;
+                }
+            }
+            output.write(key, Some(sealed_pointer), command).await?;
+        }
+
+        output.finish().await
+    }
+
+    /// Copies the live entries of the `compacted` generations into
+    /// `compaction_generations`, while generations from `oldest_kept` on stay.
+    ///
+    /// Only the entries of the compacted generations are visited. A key they
+    /// last held that is now removed or expired may still have a value in an
+    /// older generation that stays, which a removal is written for in their
+    /// place, so that the value does not come back on the next open.
+    async fn copy_compacted_entries(
+        &self,
+        compacted: &BTreeSet<u64>,
+        oldest_kept: u64,
+        compaction_generations: &RangeInclusive<u64>,
+        cancelled: &AtomicBool,
+    ) -> Result<CopiedEntries> {
+        // The last entry of each key, in key order
+        let mut latest = BTreeMap::new();
+        for &generation in compacted {
+            for entry in self.generation_entries(generation).await? {
+                latest.insert(entry.key().to_vec(), generation);
+            }
+        }
+
+        let mut output = CompactionOutput::open(self, compaction_generations).await?;
+        for (key, generation) in latest {
+            if cancelled.load(Ordering::SeqCst) {
+                return Err(KvsError::CompactionCancelled);
+            }
+
+            let removal = Command::Remove { key: key.clone() };
+            let sealed_pointer = match self.index.get(&key).await? {
+                Some(sealed_pointer) if compacted.contains(&sealed_pointer.generation) => {
+                    sealed_pointer
+                }
+                // Superseded by a generation that stays
+                Some(_) => continue,
+                None => {
+                    if oldest_kept < generation {
+                        output.write(key, None, removal).await?;
+                    }
+                    continue;
+                }
+            };
+
+            let command = self.kvs_reader.read_log_command(sealed_pointer).await?;
+            if let Some(expires_at) = command.expires_at() {
+                if expires_at <= now_millis() {
+                    if oldest_kept < sealed_pointer.generation {
+                        output.write(key.clone(), None, removal).await?;
+                    }
+                    output.copied.expired.push((key, sealed_pointer));
+                    continue;
+                }
+            }
+            output.write(key, Some(sealed_pointer), command).await?;
+        }
+
+        output.finish().await
+    }
+
+    /// Lists the entries of `generation` in log order, from its hint file if
+    /// it has a sound one.
+    async fn generation_entries(&self, generation: u64) -> Result<Vec<HintEntry>> {
+        let log_path = log_path(&self.path, generation);
+        let log_length = fs::metadata(&log_path).await?.len() as usize;
+        if let Some(entries) = read_hint_file(&self.path, generation, log_length).await? {
+            return Ok(entries);
+        }
+
+        let start = read_file_header(&log_path).await?;
+        let file = File::open(&log_path).await?;
+        let mut reader = BufReader::with_capacity(self.options.read_buffer_size, file);
+        let (entries, _) = load(generation, &mut reader, start, log_length, false).await?;
+        Ok(entries)
+    }
+}
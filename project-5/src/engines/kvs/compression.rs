@@ -0,0 +1,58 @@
+use std::borrow::Cow;
+
+use crate::Result;
+
+/// The algorithm record payloads are compressed with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    /// Fast, with a moderate ratio.
+    Lz4,
+    /// Slower, with a better ratio.
+    Zstd,
+}
+
+/// The codec of a payload stored as is.
+pub(super) const UNCOMPRESSED: u8 = 0;
+const LZ4: u8 = 1;
+const ZSTD: u8 = 2;
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compresses the payload of each command record at least `threshold` bytes
+/// long, if `compression` is set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Compressor {
+    pub compression: Option<Compression>,
+    pub threshold: usize,
+}
+
+impl Compressor {
+    /// Returns the codec `payload` ended up with, along with the bytes to store.
+    ///
+    /// A payload that does not shrink is stored as is.
+    pub fn compress(&self, payload: Vec<u8>) -> Result<(u8, Vec<u8>)> {
+        let compressed = match self.compression {
+            Some(_) if payload.len() < self.threshold => return Ok((UNCOMPRESSED, payload)),
+            Some(Compression::Lz4) => (LZ4, lz4_flex::compress_prepend_size(&payload)),
+            Some(Compression::Zstd) => (ZSTD, zstd::stream::encode_all(&payload[..], ZSTD_LEVEL)?),
+            None => return Ok((UNCOMPRESSED, payload)),
+        };
+        if compressed.1.len() < payload.len() {
+            Ok(compressed)
+        } else {
+            Ok((UNCOMPRESSED, payload))
+        }
+    }
+}
+
+/// Restores a payload stored with `codec`, returning `None` if the codec is
+/// unknown or the payload cannot be decompressed.
+pub(super) fn decompress(codec: u8, payload: &[u8]) -> Option<Cow<'_, [u8]>> {
+    match codec {
+        UNCOMPRESSED => Some(Cow::Borrowed(payload)),
+        LZ4 => lz4_flex::decompress_size_prepended(payload)
+            .ok()
+            .map(Cow::Owned),
+        ZSTD => zstd::stream::decode_all(payload).ok().map(Cow::Owned),
+        _ => None,
+    }
+}
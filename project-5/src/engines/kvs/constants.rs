@@ -1,2 +1,13 @@
+use std::time::Duration;
+
+pub(super) const BLOB_FILE_SIZE: u64 = 64 * 1024 * 1024;
+pub(super) const BLOB_GARBAGE_RATIO: f64 = 0.5;
 pub(super) const COMPACTION_THRESHOLD: usize = 1024 * 1024;
+pub(super) const COMPRESSION_THRESHOLD: usize = 256;
+pub(super) const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+pub(super) const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+pub(super) const INDEX_BLOCK_ENTRIES: usize = 128;
+pub(super) const VALUE_CACHE_SIZE: usize = 8 * 1024 * 1024;
 pub(super) const USIZE_BYTES: usize = std::mem::size_of::<usize>();
+pub(super) const CHECKSUM_BYTES: usize = std::mem::size_of::<u32>();
+pub(super) const RECORD_HEADER_BYTES: usize = USIZE_BYTES + CHECKSUM_BYTES + 1;
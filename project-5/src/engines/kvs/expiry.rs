@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use async_std::{
+    sync::{Arc, Mutex},
+    task,
+};
+use crossbeam_skiplist::SkipMap;
+
+use super::{cache::ValueCache, garbage::Garbage, index::Index, writer::KvsWriter, KvStore};
+use crate::{engines::now_millis, Result};
+
+impl KvStore {
+    /// Whether `key` holds a value that has not expired yet.
+    pub(super) async fn is_live(&self, key: &[u8]) -> Result<bool> {
+        Ok(
+            match self.index.get_with_expiry(key, &self.expiries).await? {
+                Some((_, Some(expires_at))) => expires_at > now_millis(),
+                Some((_, None)) => true,
+                None => false,
+            },
+        )
+    }
+}
+
+/// Drops expired keys from an index held in memory every `interval` for as
+/// long as the store is open.
+///
+/// Nothing is logged for a swept key: its value stays in the log, where it is
+/// still marked as expired, until compaction leaves it behind.
+pub(super) fn spawn_expiry_sweep(
+    kvs_writer: &Arc<Mutex<KvsWriter>>,
+    index: Arc<Index>,
+    expiries: Arc<SkipMap<Vec<u8>, u64>>,
+    cache: Arc<ValueCache>,
+    garbage: Arc<Garbage>,
+    interval: Duration,
+) {
+    let kvs_writer = Arc::downgrade(kvs_writer);
+    task::spawn(async move {
+        loop {
+            task::sleep(interval).await;
+            let kvs_writer = match kvs_writer.upgrade() {
+                Some(kvs_writer) => kvs_writer,
+                None => break,
+            };
+
+            // Holding the writer lock keeps a key from being set again while
+            // it is swept
+            let _writer = kvs_writer.lock().await;
+            let now = now_millis();
+            for entry in expiries.iter() {
+                if *entry.value() > now {
+                    continue;
+                }
+                if let Some(old_pointer) = index.forget(entry.key()) {
+                    cache.invalidate(old_pointer);
+                    garbage.add_replaced(Some(old_pointer));
+                }
+                entry.remove();
+            }
+        }
+    });
+}
@@ -0,0 +1,56 @@
+use std::{convert::TryInto, path::Path};
+
+use async_std::{fs::File, prelude::*};
+
+use crate::{KvsError, Result};
+
+/// The bytes every log and blob file starts with, followed by the version of
+/// its format as a `u32 LE`.
+const MAGIC: [u8; 4] = *b"KVS\0";
+
+pub(super) const FILE_HEADER_BYTES: usize = MAGIC.len() + 4;
+
+/// The version of the format log and blob files are written in.
+///
+/// Version 1 is that of the files written before the header was introduced,
+/// whose records are laid out as in version 2 from the start of the file. Read
+/// as a record length, the header of a later version is far too large for a
+/// file of version 1 to start with it.
+pub(super) const FORMAT_VERSION: u32 = 2;
+
+pub(super) fn file_header() -> [u8; FILE_HEADER_BYTES] {
+    let mut header = [0; FILE_HEADER_BYTES];
+    header[..MAGIC.len()].copy_from_slice(&MAGIC);
+    header[MAGIC.len()..].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+/// Reads the header of the log or blob file at `path`, returning the offset
+/// of its first record.
+///
+/// A file shorter than a header holds no record, whether its header was torn
+/// or it is an empty file of version 1. A file of an unknown version fails
+/// with `KvsError::UnsupportedFormat`.
+pub(super) async fn read_file_header(path: &Path) -> Result<usize> {
+    let mut file = File::open(path).await?;
+    let mut header = Vec::with_capacity(FILE_HEADER_BYTES);
+    (&mut file)
+        .take(FILE_HEADER_BYTES as u64)
+        .read_to_end(&mut header)
+        .await?;
+    if header.len() < FILE_HEADER_BYTES {
+        return Ok(header.len());
+    }
+
+    let (magic, version) = header.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return Ok(0);
+    }
+    match u32::from_le_bytes(version.try_into()?) {
+        FORMAT_VERSION => Ok(FILE_HEADER_BYTES),
+        version => Err(KvsError::UnsupportedFormat {
+            path: path.to_path_buf(),
+            version,
+        }),
+    }
+}
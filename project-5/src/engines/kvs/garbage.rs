@@ -0,0 +1,144 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use super::log_pointer::LogPointer;
+
+/// The number of stale bytes in each generation, which compaction reclaims.
+///
+/// A byte is charged to the generation holding it: an overwritten value to
+/// the generation it was written to, a removal to its own.
+///
+/// The values kept in blob files are accounted for apart, by the live bytes of
+/// each blob file, which blob garbage collection reclaims the rest of.
+#[derive(Debug, Default)]
+pub struct Garbage {
+    generations: Mutex<BTreeMap<u64, usize>>,
+    total: AtomicUsize,
+    blobs: Mutex<BlobUsage>,
+}
+
+#[derive(Debug, Default)]
+struct BlobUsage {
+    // The value each live `SetBlob` record points to, by where the record lies
+    refs: HashMap<LogPointer, LogPointer>,
+    // The bytes of the live values of each blob file
+    live: BTreeMap<u64, usize>,
+    // The bytes of the records of each sealed blob file
+    sealed: BTreeMap<u64, usize>,
+}
+
+impl BlobUsage {
+    fn remove_ref(&mut self, log_pointer: LogPointer) -> Option<LogPointer> {
+        let blob = self.refs.remove(&log_pointer)?;
+        if let Some(live) = self.live.get_mut(&blob.generation) {
+            *live = live.saturating_sub(blob.length);
+        }
+        Some(blob)
+    }
+}
+
+impl Garbage {
+    pub fn add(&self, generation: u64, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        *self
+            .generations
+            .lock()
+            .unwrap()
+            .entry(generation)
+            .or_default() += bytes;
+        self.total.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Charges the value `old_pointer` points to, if any, now replaced.
+    pub fn add_replaced(&self, old_pointer: Option<LogPointer>) {
+        if let Some(old_pointer) = old_pointer {
+            self.add(old_pointer.generation, old_pointer.length);
+            self.blobs.lock().unwrap().remove_ref(old_pointer);
+        }
+    }
+
+    /// Counts `blob` as live for as long as the `SetBlob` record found at
+    /// `log_pointer` is.
+    pub fn add_blob_ref(&self, log_pointer: LogPointer, blob: LogPointer) {
+        let mut blobs = self.blobs.lock().unwrap();
+        blobs.remove_ref(log_pointer);
+        *blobs.live.entry(blob.generation).or_default() += blob.length;
+        blobs.refs.insert(log_pointer, blob);
+    }
+
+    /// Follows a live `SetBlob` record copied from `from` to `to`, as
+    /// compaction does.
+    pub fn move_blob_ref(&self, from: LogPointer, to: LogPointer) {
+        let mut blobs = self.blobs.lock().unwrap();
+        if let Some(blob) = blobs.refs.remove(&from) {
+            blobs.refs.insert(to, blob);
+        }
+    }
+
+    /// Records the size of the records of `blob`, now sealed.
+    pub fn seal_blob(&self, blob: u64, bytes: usize) {
+        self.blobs.lock().unwrap().sealed.insert(blob, bytes);
+    }
+
+    /// Whether at least `ratio` of the bytes of a sealed blob file are stale.
+    pub fn has_blob_garbage(&self, ratio: f64) -> bool {
+        let blobs = self.blobs.lock().unwrap();
+        blobs.sealed.iter().any(|(blob, &bytes)| {
+            let live = blobs.live.get(blob).copied().unwrap_or(0);
+            let garbage = bytes.saturating_sub(live);
+            garbage > 0 && garbage as f64 >= ratio * bytes as f64
+        })
+    }
+
+    /// Forgets the blob files `blobs`, collected.
+    pub fn remove_blobs<'a>(&self, blobs: impl IntoIterator<Item = &'a u64>) {
+        let blobs: BTreeSet<u64> = blobs.into_iter().copied().collect();
+        let mut usage = self.blobs.lock().unwrap();
+        usage
+            .refs
+            .retain(|_, blob| !blobs.contains(&blob.generation));
+        for blob in &blobs {
+            usage.live.remove(blob);
+            usage.sealed.remove(blob);
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::SeqCst)
+    }
+
+    /// The stale bytes of every generation that has any.
+    pub fn by_generation(&self) -> BTreeMap<u64, usize> {
+        self.generations.lock().unwrap().clone()
+    }
+
+    /// Forgets the stale bytes of `generations`, compacted away, along with
+    /// the values the records left behind in them pointed to.
+    pub fn remove<'a>(&self, generations: impl IntoIterator<Item = &'a u64>) {
+        let generations: BTreeSet<u64> = generations.into_iter().copied().collect();
+        let mut by_generation = self.generations.lock().unwrap();
+        for generation in &generations {
+            if let Some(bytes) = by_generation.remove(generation) {
+                self.total.fetch_sub(bytes, Ordering::SeqCst);
+            }
+        }
+
+        let mut blobs = self.blobs.lock().unwrap();
+        let left_behind: Vec<_> = blobs
+            .refs
+            .keys()
+            .filter(|log_pointer| generations.contains(&log_pointer.generation))
+            .copied()
+            .collect();
+        for log_pointer in left_behind {
+            blobs.remove_ref(log_pointer);
+        }
+    }
+}
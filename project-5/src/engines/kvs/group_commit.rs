@@ -0,0 +1,252 @@
+use std::{collections::HashMap, io, sync::atomic::Ordering};
+
+use async_std::sync::Arc;
+use crossbeam::atomic::AtomicCell;
+use log::error;
+
+use super::{command::Command, hint::HintEntry, record::EncodedRecord, writer::KvsWriter, KvStore};
+use crate::{
+    engines::{KvsEngine, SyncPolicy},
+    KvsError, Result,
+};
+
+/// A write waiting in the group commit queue, along with the slot its
+/// outcome is reported through.
+pub struct PendingWrite {
+    write: Write,
+    result: Arc<AtomicCell<Option<Result<()>>>>,
+}
+
+/// The commands a pending write consists of.
+pub(super) enum Write {
+    /// A single set or remove, logged as a record of its own.
+    Command(Command),
+    /// Commands logged as one batch record, so that they are replayed all
+    /// together or not at all.
+    Batch(Vec<Command>),
+}
+
+impl KvStore {
+    /// Appends `write` to the active generation as part of a group commit.
+    ///
+    /// Writes are queued, and whichever writer gets hold of the writer lock
+    /// appends everything queued so far in one go, syncs once according to the
+    /// sync policy, and only then updates the index and acknowledges each write.
+    pub(super) async fn commit(&self, write: Write) -> Result<()> {
+        let kvs_writer = self.kvs_writer()?;
+        let result = Arc::new(AtomicCell::new(None));
+        self.pending_writes.push(PendingWrite {
+            write,
+            result: Arc::clone(&result),
+        });
+
+        let mut writer = kvs_writer.lock().await;
+        // An earlier holder of the lock may already have committed this write
+        if let Some(result) = result.take() {
+            return result;
+        }
+
+        let group = self.take_pending_writes();
+        self.write_group(&mut writer, group).await;
+        drop(writer);
+
+        self.maybe_compact();
+
+        result
+            .take()
+            .expect("a write taken off the queue always gets a result")
+    }
+
+    /// Appends `command`, if any, only if `condition` accepts the current value
+    /// of `key`.
+    ///
+    /// The writes queued so far are committed first, then the condition is
+    /// checked and the command appended without releasing the writer lock, so
+    /// that no other write to `key` can come in between.
+    pub(super) async fn commit_if<F>(
+        &self,
+        key: &[u8],
+        condition: F,
+        command: Option<Command>,
+    ) -> Result<()>
+    where
+        F: FnOnce(Option<Vec<u8>>) -> Result<()>,
+    {
+        let mut writer = self.kvs_writer()?.lock().await;
+        let group = self.take_pending_writes();
+        if !group.is_empty() {
+            self.write_group(&mut writer, group).await;
+        }
+
+        let current = self.get_bytes(key.to_vec()).await?;
+        let outcome = match (condition(current), command) {
+            (Ok(()), Some(command)) => {
+                let result = Arc::new(AtomicCell::new(None));
+                let pending_write = PendingWrite {
+                    write: Write::Command(command),
+                    result: Arc::clone(&result),
+                };
+                self.write_group(&mut writer, vec![pending_write]).await;
+                result
+                    .take()
+                    .expect("a write taken off the queue always gets a result")
+            }
+            (outcome, _) => outcome,
+        };
+        drop(writer);
+
+        self.maybe_compact();
+
+        outcome
+    }
+
+    fn take_pending_writes(&self) -> Vec<PendingWrite> {
+        let mut group = Vec::new();
+        while let Ok(pending_write) = self.pending_writes.pop() {
+            group.push(pending_write);
+        }
+        group
+    }
+
+    async fn write_group(&self, writer: &mut KvsWriter, group: Vec<PendingWrite>) {
+        // Whether a key exists once the writes accepted so far are applied
+        let mut exists: HashMap<Vec<u8>, bool> = HashMap::new();
+
+        let mut records = Vec::with_capacity(group.len());
+        let mut results = Vec::with_capacity(group.len());
+        for pending_write in group {
+            let record = match pending_write.write {
+                Write::Command(command) => {
+                    if let Command::Remove { key } = &command {
+                        match self.key_exists(key, &exists).await {
+                            Ok(true) => {}
+                            Ok(false) => {
+                                pending_write.result.store(Some(Err(KvsError::KeyNotFound)));
+                                continue;
+                            }
+                            Err(e) => {
+                                pending_write.result.store(Some(Err(e)));
+                                continue;
+                            }
+                        }
+                    }
+                    writer.encode_record(command).await
+                }
+                Write::Batch(commands) => {
+                    // Removing a missing key within a batch is a no-op, which
+                    // needs no record
+                    match self.filter_batch(commands, &exists).await {
+                        Ok(commands) if commands.is_empty() => {
+                            pending_write.result.store(Some(Ok(())));
+                            continue;
+                        }
+                        Ok(commands) => writer.encode_batch_record(commands).await,
+                        Err(e) => Err(e),
+                    }
+                }
+            };
+
+            match record {
+                Ok(record) => {
+                    for (command, _, _) in &record.commands {
+                        let is_set = !matches!(command, Command::Remove { .. });
+                        exists.insert(command.key().to_vec(), is_set);
+                    }
+                    records.push(record);
+                    results.push(pending_write.result);
+                }
+                Err(e) => pending_write.result.store(Some(Err(e))),
+            }
+        }
+        if records.is_empty() {
+            return;
+        }
+
+        let offsets = match self.append_records(writer, &records).await {
+            Ok(offsets) => offsets,
+            Err(e) => {
+                for result in results {
+                    result.store(Some(Err(replicate_error(&e))));
+                }
+                return;
+            }
+        };
+
+        for ((record, result), offset) in records.into_iter().zip(results).zip(offsets) {
+            for (command, relative_offset, length) in record.commands {
+                let entry = HintEntry::new(&command, offset as usize + relative_offset, length);
+                self.index
+                    .apply(
+                        writer.current_generation,
+                        entry,
+                        &self.expiries,
+                        &self.kvs_reader.cache,
+                        &self.garbage,
+                        &self.versions,
+                    )
+                    .await;
+            }
+            self.sequence.fetch_add(1, Ordering::SeqCst);
+            result.store(Some(Ok(())));
+        }
+
+        if let Err(e) = self.roll_if_oversized(writer).await {
+            error!("Active generation cannot be rolled over: {}", e);
+        }
+    }
+
+    async fn key_exists(&self, key: &[u8], exists: &HashMap<Vec<u8>, bool>) -> Result<bool> {
+        match exists.get(key) {
+            Some(&key_exists) => Ok(key_exists),
+            None => self.is_live(key).await,
+        }
+    }
+
+    /// Leaves out the removals of keys that do not exist by the time they
+    /// come in the batch.
+    async fn filter_batch(
+        &self,
+        commands: Vec<Command>,
+        exists: &HashMap<Vec<u8>, bool>,
+    ) -> Result<Vec<Command>> {
+        let mut batch_exists = exists.clone();
+        let mut logged = Vec::with_capacity(commands.len());
+        for command in commands {
+            match &command {
+                Command::Set { key, .. }
+                | Command::SetExpiring { key, .. }
+                | Command::SetBlob { key, .. } => {
+                    batch_exists.insert(key.clone(), true);
+                }
+                Command::Remove { key } => {
+                    if !self.key_exists(key, &batch_exists).await? {
+                        continue;
+                    }
+                    batch_exists.insert(key.clone(), false);
+                }
+            }
+            logged.push(command);
+        }
+        Ok(logged)
+    }
+
+    pub(super) async fn append_records(
+        &self,
+        writer: &mut KvsWriter,
+        records: &[EncodedRecord],
+    ) -> Result<Vec<u64>> {
+        let sync = self.options.sync_policy == SyncPolicy::Always;
+        let offsets = writer.write_records(records, sync).await?;
+        let length: usize = records.iter().map(|record| record.bytes.len()).sum();
+        self.log_bytes.fetch_add(length, Ordering::SeqCst);
+        Ok(offsets)
+    }
+}
+
+/// Reproduces a failure of the batch for each write that took part in it.
+fn replicate_error(err: &KvsError) -> KvsError {
+    match err {
+        KvsError::Io(e) => KvsError::Io(io::Error::new(e.kind(), e.to_string())),
+        e => KvsError::StringError(e.to_string()),
+    }
+}
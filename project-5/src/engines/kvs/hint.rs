@@ -0,0 +1,172 @@
+use std::{convert::TryInto, path::Path};
+
+use async_std::fs;
+use serde::{Deserialize, Serialize};
+
+use super::{command::Command, constants, log_common::*, log_pointer::LogPointer};
+use crate::Result;
+
+/// The location of a record within a sealed generation, without its value.
+///
+/// A hint file lists these for every record of its generation, in log order,
+/// so that the index can be rebuilt without reading the values back.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum HintEntry {
+    Set {
+        key: Vec<u8>,
+        offset: usize,
+        length: usize,
+    },
+    Remove {
+        key: Vec<u8>,
+        length: usize,
+    },
+    SetExpiring {
+        key: Vec<u8>,
+        offset: usize,
+        length: usize,
+        expires_at: u64,
+    },
+    /// A set whose value lies in a blob file, where `blob` points to it.
+    SetBlob {
+        key: Vec<u8>,
+        offset: usize,
+        length: usize,
+        blob: LogPointer,
+        expires_at: Option<u64>,
+    },
+}
+
+impl HintEntry {
+    /// The hint for `command`, found at `offset` and spanning `length` bytes.
+    pub fn new(command: &Command, offset: usize, length: usize) -> Self {
+        match command {
+            Command::Set { key, .. } => HintEntry::Set {
+                key: key.clone(),
+                offset,
+                length,
+            },
+            Command::Remove { key } => HintEntry::Remove {
+                key: key.clone(),
+                length,
+            },
+            Command::SetExpiring {
+                key, expires_at, ..
+            } => HintEntry::SetExpiring {
+                key: key.clone(),
+                offset,
+                length,
+                expires_at: *expires_at,
+            },
+            Command::SetBlob {
+                key,
+                blob,
+                expires_at,
+            } => HintEntry::SetBlob {
+                key: key.clone(),
+                offset,
+                length,
+                blob: *blob,
+                expires_at: *expires_at,
+            },
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        match self {
+            HintEntry::Set { key, .. }
+            | HintEntry::Remove { key, .. }
+            | HintEntry::SetExpiring { key, .. }
+            | HintEntry::SetBlob { key, .. } => key,
+        }
+    }
+
+    /// The length of the record the entry stands for.
+    pub fn length(&self) -> usize {
+        match self {
+            HintEntry::Set { length, .. }
+            | HintEntry::Remove { length, .. }
+            | HintEntry::SetExpiring { length, .. }
+            | HintEntry::SetBlob { length, .. } => *length,
+        }
+    }
+
+    /// When the value set by the entry expires, if ever.
+    pub fn expires_at(&self) -> Option<u64> {
+        match self {
+            HintEntry::SetExpiring { expires_at, .. } => Some(*expires_at),
+            HintEntry::SetBlob { expires_at, .. } => *expires_at,
+            HintEntry::Set { .. } | HintEntry::Remove { .. } => None,
+        }
+    }
+
+    /// Where the value set by the entry lies within its blob file, if it was
+    /// kept apart in one.
+    pub fn blob(&self) -> Option<LogPointer> {
+        match self {
+            HintEntry::SetBlob { blob, .. } => Some(*blob),
+            _ => None,
+        }
+    }
+
+    /// Where the value set by the entry lies within `generation`, or `None`
+    /// for a removal.
+    pub fn pointer(&self, generation: u64) -> Option<LogPointer> {
+        match self {
+            HintEntry::Set { offset, length, .. }
+            | HintEntry::SetExpiring { offset, length, .. }
+            | HintEntry::SetBlob { offset, length, .. } => Some(LogPointer {
+                generation,
+                offset: *offset,
+                length: *length,
+            }),
+            HintEntry::Remove { .. } => None,
+        }
+    }
+}
+
+/// Writes the hint file of `generation`, whose log file is `log_length` bytes long.
+///
+/// Layout: `[crc32: u32 LE][payload]`, where the payload records `log_length`
+/// alongside the entries so that a hint that no longer matches its log file is
+/// not trusted.
+pub async fn write_hint_file(
+    dir: &Path,
+    generation: u64,
+    log_length: usize,
+    entries: &[HintEntry],
+) -> Result<()> {
+    let payload = bincode::serialize(&(log_length, entries))?;
+    let mut content = Vec::with_capacity(constants::CHECKSUM_BYTES + payload.len());
+    content.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    content.extend_from_slice(&payload);
+    fs::write(hint_path(dir, generation), content).await?;
+    Ok(())
+}
+
+/// Reads the hint file of `generation`, returning `None` if it is missing,
+/// damaged or does not describe a log file of `log_length` bytes.
+pub async fn read_hint_file(
+    dir: &Path,
+    generation: u64,
+    log_length: usize,
+) -> Result<Option<Vec<HintEntry>>> {
+    let hint_path = hint_path(dir, generation);
+    if !hint_path.exists() {
+        return Ok(None);
+    }
+
+    let content = fs::read(hint_path).await?;
+    if content.len() < constants::CHECKSUM_BYTES {
+        return Ok(None);
+    }
+    let (checksum_bytes, payload) = content.split_at(constants::CHECKSUM_BYTES);
+    if u32::from_le_bytes(checksum_bytes.try_into()?) != crc32fast::hash(payload) {
+        return Ok(None);
+    }
+
+    match bincode::deserialize::<(usize, Vec<HintEntry>)>(payload) {
+        Ok((hinted_length, entries)) if hinted_length == log_length => Ok(Some(entries)),
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,482 @@
+use std::{
+    cmp::Reverse,
+    collections::BTreeSet,
+    ops::Bound,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+};
+
+use async_std::sync::Arc;
+use crossbeam_skiplist::SkipMap;
+use log::warn;
+
+use super::{
+    cache::ValueCache,
+    garbage::Garbage,
+    hint::HintEntry,
+    log_pointer::LogPointer,
+    table::{SortedTable, TableCursor, TableWriter},
+    IndexVersions,
+};
+use crate::{
+    engines::{BytesRange, ScanOrder},
+    Result,
+};
+
+/// How a `KvStore` keeps track of where the value of each key lies.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexMode {
+    /// Every key is held in memory.
+    InMemory,
+    /// Only the keys written to the active generation are held in memory.
+    /// Every sealed generation gets a file indexing its keys in sorted order,
+    /// of which a sparse index is held in memory, so that memory use no
+    /// longer grows with the number of keys. Lookups read from disk instead.
+    OnDisk,
+}
+
+/// Where the latest record of each key lies.
+pub(super) enum Index {
+    InMemory(Arc<SkipMap<Vec<u8>, LogPointer>>),
+    OnDisk(Box<SortedIndex>),
+}
+
+/// An index holding the keys of the active generation in memory, and those
+/// of the sealed generations in sorted tables on disk.
+pub(super) struct SortedIndex {
+    path: Arc<PathBuf>,
+    // The latest entry of each key written to the active generation,
+    // removals included, along with its generation
+    active: SkipMap<Vec<u8>, (u64, HintEntry)>,
+    // The number of bytes of the active generation made stale within it
+    active_stale: AtomicUsize,
+    // The tables of the sealed generations, newest first
+    tables: RwLock<Vec<Arc<SortedTable>>>,
+}
+
+impl Index {
+    pub fn new(mode: IndexMode, path: Arc<PathBuf>) -> Self {
+        match mode {
+            IndexMode::InMemory => Index::InMemory(Arc::new(SkipMap::new())),
+            IndexMode::OnDisk => Index::OnDisk(Box::new(SortedIndex {
+                path,
+                active: SkipMap::new(),
+                active_stale: AtomicUsize::new(0),
+                tables: RwLock::new(Vec::new()),
+            })),
+        }
+    }
+
+    pub async fn get(&self, key: &[u8]) -> Result<Option<LogPointer>> {
+        match self {
+            Index::InMemory(index_map) => Ok(index_map.get(key).map(|entry| *entry.value())),
+            Index::OnDisk(sorted) => sorted.get(key).await,
+        }
+    }
+
+    /// Applies `entry`, found in `generation`, to the index, and to `expiries`
+    /// with every key in memory, charging the bytes it made stale to `garbage`.
+    /// The value it replaces is dropped from `cache`, and kept in `versions` for
+    /// the live snapshots.
+    pub async fn apply(
+        &self,
+        generation: u64,
+        entry: HintEntry,
+        expiries: &SkipMap<Vec<u8>, u64>,
+        cache: &ValueCache,
+        garbage: &Garbage,
+        versions: &IndexVersions,
+    ) {
+        let removal = match &entry {
+            HintEntry::Remove { length, .. } => *length,
+            _ => 0,
+        };
+        let blob_ref = entry
+            .blob()
+            .and_then(|blob| Some((entry.pointer(generation)?, blob)));
+
+        let old_pointer = match self {
+            Index::InMemory(index_map) => {
+                match entry.expires_at() {
+                    Some(expires_at) => {
+                        expiries.insert(entry.key().to_vec(), expires_at);
+                    }
+                    None => {
+                        expiries.remove(entry.key());
+                    }
+                }
+                let old_pointer = index_map
+                    .get(entry.key())
+                    .map(|old_command| *old_command.value());
+                versions.record(entry.key(), old_pointer);
+                match entry.pointer(generation) {
+                    Some(log_pointer) => {
+                        index_map.insert(entry.key().to_vec(), log_pointer);
+                    }
+                    None => {
+                        index_map.remove(entry.key());
+                    }
+                }
+                old_pointer
+            }
+            // The expiry of each key stays in its entry, to be read on lookup
+            Index::OnDisk(sorted) => sorted.apply(generation, entry, versions).await,
+        };
+        if let Some(old_pointer) = old_pointer {
+            cache.invalidate(old_pointer);
+        }
+        garbage.add_replaced(old_pointer);
+        garbage.add(generation, removal);
+        if let Some((log_pointer, blob)) = blob_ref {
+            garbage.add_blob_ref(log_pointer, blob);
+        }
+    }
+
+    /// Where the value of `key` lies, along with when it expires if ever.
+    pub async fn get_with_expiry(
+        &self,
+        key: &[u8],
+        expiries: &SkipMap<Vec<u8>, u64>,
+    ) -> Result<Option<(LogPointer, Option<u64>)>> {
+        match self {
+            Index::InMemory(index_map) => Ok(index_map.get(key).map(|entry| {
+                let expires_at = expiries.get(key).map(|expiry| *expiry.value());
+                (*entry.value(), expires_at)
+            })),
+            Index::OnDisk(sorted) => {
+                Ok(sorted.entry(key).await?.and_then(|(generation, entry)| {
+                    let expires_at = entry.expires_at();
+                    entry
+                        .pointer(generation)
+                        .map(|log_pointer| (log_pointer, expires_at))
+                }))
+            }
+        }
+    }
+
+    /// Drops `key` without anything being logged, as the expiry sweep does.
+    ///
+    /// Only possible with every key in memory: a key dropped from the tables
+    /// on disk would reveal an older value.
+    pub fn forget(&self, key: &[u8]) -> Option<LogPointer> {
+        match self {
+            Index::InMemory(index_map) => index_map.remove(key).map(|entry| *entry.value()),
+            Index::OnDisk(_) => None,
+        }
+    }
+
+    /// Finds the first key within `range` in `order`.
+    ///
+    /// With the index on disk, the key may turn out to have been removed.
+    pub async fn first_key(&self, range: &BytesRange, order: ScanOrder) -> Result<Option<Vec<u8>>> {
+        match self {
+            Index::InMemory(index_map) => {
+                let mut keys = index_map.range(range.clone());
+                let entry = match order {
+                    ScanOrder::Forward => keys.next(),
+                    ScanOrder::Reverse => keys.next_back(),
+                };
+                Ok(entry.map(|entry| entry.key().clone()))
+            }
+            Index::OnDisk(sorted) => sorted.first_key(range, order).await,
+        }
+    }
+
+    /// The number of keys, counted by reading every sorted table if the index
+    /// is on disk.
+    pub async fn len(&self) -> Result<usize> {
+        match self {
+            Index::InMemory(index_map) => Ok(index_map.len()),
+            Index::OnDisk(_) => {
+                let mut cursor = self.cursor();
+                let mut len = 0;
+                while cursor.next().await?.is_some() {
+                    len += 1;
+                }
+                Ok(len)
+            }
+        }
+    }
+
+    /// Visits every key in order, along with where its value lies.
+    pub fn cursor(&self) -> IndexCursor {
+        match self {
+            Index::InMemory(index_map) => IndexCursor::InMemory {
+                index_map: Arc::clone(index_map),
+                start: Bound::Unbounded,
+            },
+            Index::OnDisk(sorted) => IndexCursor::OnDisk(sorted.merge_cursor()),
+        }
+    }
+
+    /// Seals the keys of `generation`, which is no longer written to and
+    /// holds `log_length` bytes, into a sorted table if the index is on disk.
+    pub async fn seal(&self, generation: u64, log_length: usize) -> Result<()> {
+        match self {
+            Index::InMemory(_) => Ok(()),
+            Index::OnDisk(sorted) => sorted.seal(generation, log_length).await,
+        }
+    }
+}
+
+impl SortedIndex {
+    async fn get(&self, key: &[u8]) -> Result<Option<LogPointer>> {
+        Ok(self
+            .entry(key)
+            .await?
+            .and_then(|(generation, entry)| entry.pointer(generation)))
+    }
+
+    /// The latest entry of `key`, along with the generation it was found in.
+    async fn entry(&self, key: &[u8]) -> Result<Option<(u64, HintEntry)>> {
+        if let Some(entry) = self.active.get(key) {
+            return Ok(Some(entry.value().clone()));
+        }
+        for table in self.tables() {
+            if let Some(entry) = table.get(key).await? {
+                return Ok(Some((table.generation, entry)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn tables(&self) -> Vec<Arc<SortedTable>> {
+        self.tables.read().unwrap().clone()
+    }
+
+    async fn apply(
+        &self,
+        generation: u64,
+        entry: HintEntry,
+        versions: &IndexVersions,
+    ) -> Option<LogPointer> {
+        let old_pointer = match self.get(entry.key()).await {
+            Ok(old_pointer) => old_pointer,
+            Err(e) => {
+                warn!("Stale bytes cannot be accounted for: {}", e);
+                None
+            }
+        };
+        versions.record(entry.key(), old_pointer);
+        if let Some(active_entry) = self.active.get(entry.key()) {
+            match active_entry.value() {
+                (active_generation, HintEntry::Set { length, .. })
+                | (active_generation, HintEntry::SetExpiring { length, .. })
+                | (active_generation, HintEntry::SetBlob { length, .. })
+                    if *active_generation == generation =>
+                {
+                    self.active_stale.fetch_add(*length, Ordering::SeqCst);
+                }
+                _ => {}
+            }
+        }
+
+        if let HintEntry::Remove { length, .. } = entry {
+            self.active_stale.fetch_add(length, Ordering::SeqCst);
+        }
+        self.active
+            .insert(entry.key().to_vec(), (generation, entry));
+        old_pointer
+    }
+
+    /// Adds the table of a sealed generation.
+    pub fn add_table(&self, table: SortedTable) {
+        let mut tables = self.tables.write().unwrap();
+        tables.push(Arc::new(table));
+        tables.sort_by_key(|table| Reverse(table.generation));
+    }
+
+    /// Swaps the tables of the `compacted` generations for `tables`, those of
+    /// the generations compacted from them.
+    pub fn replace_tables(&self, compacted: &BTreeSet<u64>, tables: Vec<SortedTable>) {
+        {
+            let mut current = self.tables.write().unwrap();
+            current.retain(|table| !compacted.contains(&table.generation));
+            current.extend(tables.into_iter().map(Arc::new));
+            current.sort_by_key(|table| Reverse(table.generation));
+        }
+        // Left behind only if sealing them failed, then compacted all the same
+        for entry in self.active.iter() {
+            if compacted.contains(&entry.value().0) {
+                entry.remove();
+            }
+        }
+    }
+
+    async fn seal(&self, generation: u64, log_length: usize) -> Result<()> {
+        let mut writer = TableWriter::create(&self.path, generation).await?;
+        for entry in self.active.iter() {
+            if entry.value().0 == generation {
+                writer.push(entry.value().1.clone()).await?;
+            }
+        }
+        let stale_bytes = self.active_stale.swap(0, Ordering::SeqCst);
+        self.add_table(writer.finish(log_length, stale_bytes).await?);
+
+        // The table answers for the keys from now on
+        for entry in self.active.iter() {
+            if entry.value().0 == generation {
+                entry.remove();
+            }
+        }
+        Ok(())
+    }
+
+    async fn first_key(&self, range: &BytesRange, order: ScanOrder) -> Result<Option<Vec<u8>>> {
+        let mut keys = Vec::new();
+        {
+            let mut active_keys = self.active.range(range.clone());
+            let entry = match order {
+                ScanOrder::Forward => active_keys.next(),
+                ScanOrder::Reverse => active_keys.next_back(),
+            };
+            keys.extend(entry.map(|entry| entry.key().clone()));
+        }
+        for table in self.tables() {
+            keys.extend(table.first_key(range, order).await?);
+        }
+
+        Ok(match order {
+            ScanOrder::Forward => keys.into_iter().min(),
+            ScanOrder::Reverse => keys.into_iter().max(),
+        })
+    }
+
+    /// Visits every key in order, along with its entry in each generation.
+    pub fn merge_cursor(&self) -> MergeCursor {
+        let active = self
+            .active
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect::<Vec<_>>();
+        let mut sources = vec![MergeSource::Active(active.into_iter())];
+        for table in self.tables() {
+            sources.push(MergeSource::Table(
+                table.generation,
+                TableCursor::new(table),
+            ));
+        }
+        MergeCursor {
+            heads: vec![None; sources.len()],
+            sources,
+            started: false,
+        }
+    }
+
+    /// Charges the bytes made stale across generations to `garbage`, and
+    /// counts the values of blob files still pointed to as live.
+    ///
+    /// Expiries are not restored: they are read from the tables on lookup.
+    pub async fn replay(&self, garbage: &Garbage) -> Result<()> {
+        let mut cursor = self.merge_cursor();
+        while let Some(entries) = cursor.next().await? {
+            if let Some((generation, entry)) = entries.first() {
+                if let (Some(log_pointer), Some(blob)) = (entry.pointer(*generation), entry.blob())
+                {
+                    garbage.add_blob_ref(log_pointer, blob);
+                }
+            }
+            // Removals count as stale within their own generation already
+            for (generation, entry) in entries.into_iter().skip(1) {
+                if !matches!(entry, HintEntry::Remove { .. }) {
+                    garbage.add(generation, entry.length());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Visits the keys of an index in order, each with where its value lies.
+pub(super) enum IndexCursor {
+    InMemory {
+        index_map: Arc<SkipMap<Vec<u8>, LogPointer>>,
+        start: Bound<Vec<u8>>,
+    },
+    OnDisk(MergeCursor),
+}
+
+impl IndexCursor {
+    pub async fn next(&mut self) -> Result<Option<(Vec<u8>, LogPointer)>> {
+        match self {
+            IndexCursor::InMemory { index_map, start } => {
+                let entry = match index_map.range((start.clone(), Bound::Unbounded)).next() {
+                    Some(entry) => entry,
+                    None => return Ok(None),
+                };
+                *start = Bound::Excluded(entry.key().clone());
+                Ok(Some((entry.key().clone(), *entry.value())))
+            }
+            IndexCursor::OnDisk(cursor) => {
+                while let Some(entries) = cursor.next().await? {
+                    let (generation, latest) = &entries[0];
+                    if let Some(log_pointer) = latest.pointer(*generation) {
+                        return Ok(Some((latest.key().to_vec(), log_pointer)));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+enum MergeSource {
+    Active(std::vec::IntoIter<(u64, HintEntry)>),
+    Table(u64, TableCursor),
+}
+
+impl MergeSource {
+    async fn next(&mut self) -> Result<Option<(u64, HintEntry)>> {
+        match self {
+            MergeSource::Active(entries) => Ok(entries.next()),
+            MergeSource::Table(generation, cursor) => {
+                Ok(cursor.next().await?.map(|entry| (*generation, entry)))
+            }
+        }
+    }
+}
+
+/// Merges the sorted entries of the active generation and of the tables,
+/// yielding those of one key at a time, newest generation first.
+pub(super) struct MergeCursor {
+    sources: Vec<MergeSource>,
+    heads: Vec<Option<(u64, HintEntry)>>,
+    started: bool,
+}
+
+impl MergeCursor {
+    pub async fn next(&mut self) -> Result<Option<Vec<(u64, HintEntry)>>> {
+        if !self.started {
+            for (head, source) in self.heads.iter_mut().zip(&mut self.sources) {
+                *head = source.next().await?;
+            }
+            self.started = true;
+        }
+
+        let key = match self
+            .heads
+            .iter()
+            .flatten()
+            .map(|(_, entry)| entry.key())
+            .min()
+        {
+            Some(key) => key.to_vec(),
+            None => return Ok(None),
+        };
+        let mut entries = Vec::new();
+        for (head, source) in self.heads.iter_mut().zip(&mut self.sources) {
+            if head
+                .as_ref()
+                .is_some_and(|(_, entry)| entry.key() == key.as_slice())
+            {
+                entries.extend(head.take());
+                *head = source.next().await?;
+            }
+        }
+        entries.sort_by_key(|(generation, _)| Reverse(*generation));
+        Ok(Some(entries))
+    }
+}
@@ -0,0 +1,305 @@
+use std::{collections::BTreeMap, convert::TryInto, path::Path};
+
+use async_std::{
+    fs::{self, File},
+    prelude::*,
+    sync::Arc,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use super::{
+    command::Command,
+    compression::Compressor,
+    constants,
+    format::{read_file_header, FILE_HEADER_BYTES},
+    get_generations,
+    log_common::*,
+    manifest::Manifest,
+    writer::KvsWriter,
+};
+use crate::{KvsError, Result};
+
+/// The log files of the JSON layout. The store reads the first of them that
+/// exists, and compacts into the other.
+const JSON_GENERATIONS: [u64; 2] = [0, 1];
+
+/// The generation the contents of a JSON store are written to, past both of
+/// its log files.
+const UPGRADED_GENERATION: u64 = 2;
+
+/// How a log file of the JSON layout starts, with the first key of an object.
+const JSON_OBJECT_START: &[u8] = b"{\"";
+
+/// The layouts of the stores written by earlier versions of kvs, which are
+/// not read as they are.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum LegacyLayout {
+    /// String commands logged as a stream of JSON objects into `0.log` or
+    /// `1.log`.
+    Json,
+    /// String commands logged with bincode into generations of log files,
+    /// each preceded by its length but by no checksum, without a manifest.
+    Unchecksummed,
+}
+
+/// A command of a legacy layout.
+#[derive(Deserialize, Serialize)]
+enum LegacyCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// The legacy layout of the store in `dir`, if it has one.
+///
+/// Generation 0 is never written to by the current layout, nor is a log file
+/// opening with a JSON object ever read as one: taken as a record length, its
+/// first bytes are far beyond the size of any record. A `1.log` of the
+/// unchecksummed layout whose first record length happens to read as the
+/// start of a JSON object is told apart by its record. A checksummed log file
+/// is not taken for an unchecksummed one either, unless the checksum of its
+/// first record is 0 or 1, where the variant of a command lies, and the rest
+/// of the record decodes as a command of the same length.
+pub(super) async fn legacy_layout(dir: &Path) -> Result<Option<LegacyLayout>> {
+    if Manifest::load(dir).await?.is_some() {
+        return Ok(None);
+    }
+    if log_path(dir, 0).exists() {
+        return Ok(Some(LegacyLayout::Json));
+    }
+
+    let first_path = log_path(dir, 1);
+    if first_path.exists() && !is_unchecksummed_log(&first_path).await? {
+        let mut start = Vec::with_capacity(JSON_OBJECT_START.len());
+        File::open(&first_path)
+            .await?
+            .take(JSON_OBJECT_START.len() as u64)
+            .read_to_end(&mut start)
+            .await?;
+        if start == JSON_OBJECT_START {
+            return Ok(Some(LegacyLayout::Json));
+        }
+    }
+
+    for generation in get_generations(dir, "log")? {
+        if is_unchecksummed_log(&log_path(dir, generation)).await? {
+            return Ok(Some(LegacyLayout::Unchecksummed));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether the log file at `path` starts with an intact unchecksummed record,
+/// `[payload length: u64 LE][command]`.
+async fn is_unchecksummed_log(path: &Path) -> Result<bool> {
+    if read_file_header(path).await? != 0 {
+        return Ok(false);
+    }
+    let mut file = File::open(path).await?;
+    let file_length = file.metadata().await?.len();
+    let mut length_bytes = [0; constants::USIZE_BYTES];
+    if file_length < length_bytes.len() as u64 {
+        return Ok(false);
+    }
+    file.read_exact(&mut length_bytes).await?;
+    let payload_length = u64::from_le_bytes(length_bytes);
+    if payload_length > file_length - length_bytes.len() as u64 {
+        return Ok(false);
+    }
+
+    let mut payload = vec![0; payload_length as usize];
+    file.read_exact(&mut payload).await?;
+    match bincode::deserialize::<LegacyCommand>(&payload) {
+        Ok(command) => Ok(bincode::serialized_size(&command)? == payload_length),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Rewrites the store of the JSON layout in `dir` into a generation of the
+/// current layout, which the manifest then lists alone.
+///
+/// An interrupted upgrade is started over, the legacy log files being removed
+/// only once the manifest is written.
+pub(super) async fn upgrade_json_layout(dir: &Path, compressor: Compressor) -> Result<()> {
+    let legacy_path = JSON_GENERATIONS
+        .iter()
+        .map(|&generation| log_path(dir, generation))
+        .find(|path| path.exists());
+    let pairs = match &legacy_path {
+        Some(legacy_path) => read_json_log(legacy_path).await?,
+        None => BTreeMap::new(),
+    };
+
+    replace_legacy_logs(
+        dir,
+        pairs,
+        UPGRADED_GENERATION,
+        &JSON_GENERATIONS,
+        compressor,
+    )
+    .await
+}
+
+/// Rewrites the store of the unchecksummed layout in `dir` into a generation
+/// of the current layout past its own, which the manifest then lists alone.
+///
+/// The generations are replayed oldest first, as the legacy store did when
+/// opened. A record torn by a crash is dropped from the tail of the newest
+/// generation, which the legacy store failed to open on, while any other
+/// damage fails with `KvsError::CorruptedLog`. An interrupted upgrade is
+/// started over, the legacy log files being removed only once the manifest
+/// is written.
+pub(super) async fn upgrade_unchecksummed_layout(dir: &Path, compressor: Compressor) -> Result<()> {
+    let mut generations = get_generations(dir, "log")?;
+    // The only log file with a format header is one left by an interrupted
+    // upgrade
+    if let Some(&newest) = generations.last() {
+        if read_file_header(&log_path(dir, newest)).await? == FILE_HEADER_BYTES {
+            generations.pop();
+        }
+    }
+
+    let mut pairs = BTreeMap::new();
+    for (index, &generation) in generations.iter().enumerate() {
+        let is_newest = index + 1 == generations.len();
+        replay_unchecksummed_log(dir, generation, is_newest, &mut pairs).await?;
+    }
+    let upgraded_generation = generations.last().map_or(1, |newest| newest + 1);
+    replace_legacy_logs(dir, pairs, upgraded_generation, &generations, compressor).await
+}
+
+/// Writes `pairs` into `generation` of the current layout, lists it alone in
+/// the manifest and removes the log files of `legacy_generations`.
+async fn replace_legacy_logs(
+    dir: &Path,
+    pairs: BTreeMap<String, String>,
+    generation: u64,
+    legacy_generations: &[u64],
+    compressor: Compressor,
+) -> Result<()> {
+    for leftover_path in &[log_path(dir, generation), hint_path(dir, generation)] {
+        if leftover_path.exists() {
+            fs::remove_file(leftover_path).await?;
+        }
+    }
+    let mut writer = KvsWriter::open(
+        Arc::new(dir.to_path_buf()),
+        generation,
+        constants::DEFAULT_BUFFER_SIZE,
+        compressor,
+    )
+    .await?;
+    let keys = pairs.len();
+    for (key, value) in pairs {
+        let command = Command::Set {
+            key: key.into_bytes(),
+            value: value.into_bytes(),
+        };
+        writer.write_command(command).await?;
+    }
+    writer.seal().await?;
+    Manifest::new(vec![generation]).store(dir).await?;
+
+    for &legacy_generation in legacy_generations {
+        let legacy_path = log_path(dir, legacy_generation);
+        if legacy_path.exists() {
+            fs::remove_file(legacy_path).await?;
+        }
+    }
+    info!(
+        "Upgraded the legacy store at {:?}, holding {} keys",
+        dir, keys
+    );
+    Ok(())
+}
+
+/// Replays the unchecksummed log file of `generation` into `pairs`.
+///
+/// A torn record is tolerated at the tail of the file only if `is_newest` is
+/// set.
+async fn replay_unchecksummed_log(
+    dir: &Path,
+    generation: u64,
+    is_newest: bool,
+    pairs: &mut BTreeMap<String, String>,
+) -> Result<()> {
+    let path = log_path(dir, generation);
+    let content = fs::read(&path).await?;
+    let mut position = 0;
+    while position < content.len() {
+        let corrupted = || KvsError::CorruptedLog {
+            generation,
+            offset: position,
+        };
+        let rest = &content[position..];
+        let payload = if rest.len() < constants::USIZE_BYTES {
+            None
+        } else {
+            let (length_bytes, rest) = rest.split_at(constants::USIZE_BYTES);
+            let payload_length = u64::from_le_bytes(length_bytes.try_into()?);
+            if payload_length > rest.len() as u64 {
+                None
+            } else {
+                Some(&rest[..payload_length as usize])
+            }
+        };
+        let payload = match payload {
+            Some(payload) => payload,
+            // Left by a crash while the record was written
+            None if is_newest => {
+                warn!(
+                    "Discarded {} bytes of a torn record at the tail of {:?}",
+                    rest.len(),
+                    path
+                );
+                break;
+            }
+            None => return Err(corrupted()),
+        };
+
+        let command: LegacyCommand = bincode::deserialize(payload).map_err(|_| corrupted())?;
+        if bincode::serialized_size(&command)? != payload.len() as u64 {
+            return Err(corrupted());
+        }
+        match command {
+            LegacyCommand::Set { key, value } => {
+                pairs.insert(key, value);
+            }
+            LegacyCommand::Remove { key } => {
+                pairs.remove(&key);
+            }
+        }
+        position += constants::USIZE_BYTES + payload.len();
+    }
+    Ok(())
+}
+
+/// Replays the JSON log file at `path` into the pairs it holds.
+async fn read_json_log(path: &Path) -> Result<BTreeMap<String, String>> {
+    let content = fs::read(path).await?;
+    let mut pairs = BTreeMap::new();
+    let mut commands = Deserializer::from_slice(&content).into_iter::<LegacyCommand>();
+    while let Some(command) = commands.next() {
+        match command {
+            Ok(LegacyCommand::Set { key, value }) => {
+                pairs.insert(key, value);
+            }
+            Ok(LegacyCommand::Remove { key }) => {
+                pairs.remove(&key);
+            }
+            // Left by a crash while the command was written, which the legacy
+            // store failed to open on
+            Err(e) if e.is_eof() => {
+                warn!(
+                    "Discarded {} bytes of a torn command at the tail of {:?}",
+                    content.len() - commands.byte_offset(),
+                    path
+                );
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(pairs)
+}
@@ -0,0 +1,116 @@
+use std::{
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, Weak,
+    },
+    time::Duration,
+};
+
+use async_std::{sync::Arc, task};
+use fs2::FileExt;
+
+use super::compaction::RunningCompactions;
+use crate::{KvsError, Result};
+
+const LOCK_FILE_NAME: &str = "LOCK";
+const RELEASE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// The locks this process holds, so that a store reopened once its last handle
+// is dropped can tell the background tasks of the closed one winding down from
+// another writer
+static HELD: Mutex<Vec<Weak<DirLock>>> = Mutex::new(Vec::new());
+
+/// An exclusive advisory lock on the directory of a store, held by the one
+/// process writing to it.
+///
+/// The lock is taken on a `LOCK` file and released along with the file handle
+/// when dropped, or by the operating system if the process dies.
+#[derive(Debug)]
+pub(super) struct DirLock {
+    // Releases the lock once closed
+    _file: File,
+    dir: PathBuf,
+    // Whether the store is closed, the lock being held until its background
+    // tasks stop
+    closed: AtomicBool,
+}
+
+impl DirLock {
+    /// Locks `dir`, failing with `KvsError::Locked` if it already is.
+    pub fn acquire(dir: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(LOCK_FILE_NAME))?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(DirLock {
+                _file: file,
+                dir: dir.canonicalize()?,
+                closed: AtomicBool::new(false),
+            }),
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                Err(KvsError::Locked(dir.to_path_buf()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Locks `dir` for a store opened for writing.
+    ///
+    /// If a store of this process in `dir` has been closed, its background
+    /// tasks still holding the lock as they stop, this waits for them to
+    /// release it. Fails with `KvsError::Locked` if anything else holds it.
+    pub async fn acquire_for_store(dir: &Path) -> Result<Arc<Self>> {
+        loop {
+            match DirLock::acquire(dir) {
+                Ok(dir_lock) => {
+                    let dir_lock = Arc::new(dir_lock);
+                    let mut held = HELD.lock().unwrap();
+                    held.retain(|held| held.strong_count() > 0);
+                    held.push(Arc::downgrade(&dir_lock));
+                    return Ok(dir_lock);
+                }
+                Err(KvsError::Locked(_)) if DirLock::is_closing(dir) => {
+                    task::sleep(RELEASE_POLL_INTERVAL).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Marks the store holding the lock as closed.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the lock on `dir` is held by a closed store of this process.
+    fn is_closing(dir: &Path) -> bool {
+        let dir = match dir.canonicalize() {
+            Ok(dir) => dir,
+            Err(_) => return false,
+        };
+        HELD.lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .any(|held| held.dir == dir && held.closed.load(Ordering::SeqCst))
+    }
+}
+
+/// Held by every handle to a store open for writing, but by none of its
+/// background tasks: once the last handle is dropped, the compactions under
+/// way are cancelled, and the lock is released as soon as they stop.
+pub(super) struct OpenGuard {
+    pub dir_lock: Arc<DirLock>,
+    pub running_compactions: Arc<RunningCompactions>,
+}
+
+impl Drop for OpenGuard {
+    fn drop(&mut self) {
+        self.dir_lock.close();
+        self.running_compactions.cancel_all();
+    }
+}
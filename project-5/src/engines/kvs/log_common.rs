@@ -1,19 +1,48 @@
 use std::path::{Path, PathBuf};
 
-use async_std::fs::{File, OpenOptions};
+use async_std::{
+    fs::{File, OpenOptions},
+    prelude::*,
+};
 
+use super::format::file_header;
 use crate::Result;
 
 pub(super) async fn new_log_file(path: &Path, generation: u64) -> Result<File> {
-    let path = log_path(&path, generation);
-    Ok(OpenOptions::new()
+    open_for_append(&log_path(&path, generation)).await
+}
+
+pub(super) async fn new_blob_file(path: &Path, generation: u64) -> Result<File> {
+    open_for_append(&blob_path(&path, generation)).await
+}
+
+/// Opens the file at `path` for appending, creating it along with its format
+/// header if need be.
+async fn open_for_append(path: &Path) -> Result<File> {
+    let mut file = OpenOptions::new()
         .create(true)
         .write(true)
         .append(true)
         .open(&path)
-        .await?)
+        .await?;
+    if file.metadata().await?.len() == 0 {
+        file.write_all(&file_header()).await?;
+    }
+    Ok(file)
 }
 
 pub(super) fn log_path(dir: &Path, generation: u64) -> PathBuf {
     dir.join(format!("{}.log", generation))
 }
+
+pub(super) fn blob_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{}.blob", generation))
+}
+
+pub(super) fn hint_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{}.hint", generation))
+}
+
+pub(super) fn index_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{}.index", generation))
+}
@@ -1,6 +1,8 @@
 use std::ops::Range;
 
-#[derive(Clone, Copy, Debug)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct LogPointer {
     pub generation: u64,
     pub offset: usize,
@@ -0,0 +1,111 @@
+use std::{collections::BTreeSet, path::Path};
+
+use async_std::{
+    fs::{self, OpenOptions},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+const MANIFEST_TEMP_FILE_NAME: &str = "MANIFEST.tmp";
+
+/// The set of generations and blob files that make up the committed state of
+/// a store.
+///
+/// Log files whose generation is not listed are leftovers of an interrupted
+/// compaction (or of one whose stale files could not be deleted) and must not
+/// be replayed. Likewise, unlisted blob files hold no value the store still
+/// refers to. The manifest is replaced atomically by writing a temporary
+/// file, syncing it and renaming it over the previous one.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    generations: BTreeSet<u64>,
+    #[serde(default)]
+    blobs: BTreeSet<u64>,
+}
+
+impl Manifest {
+    pub fn new(generations: impl IntoIterator<Item = u64>) -> Self {
+        Manifest {
+            generations: generations.into_iter().collect(),
+            blobs: BTreeSet::new(),
+        }
+    }
+
+    /// A manifest listing `generations` instead, along with the same blob files.
+    pub fn with_generations(&self, generations: impl IntoIterator<Item = u64>) -> Self {
+        Manifest {
+            generations: generations.into_iter().collect(),
+            blobs: self.blobs.clone(),
+        }
+    }
+
+    pub fn generations(&self) -> impl Iterator<Item = u64> + '_ {
+        self.generations.iter().copied()
+    }
+
+    pub fn contains(&self, generation: u64) -> bool {
+        self.generations.contains(&generation)
+    }
+
+    pub fn insert(&mut self, generation: u64) {
+        self.generations.insert(generation);
+    }
+
+    pub fn blobs(&self) -> impl Iterator<Item = u64> + '_ {
+        self.blobs.iter().copied()
+    }
+
+    pub fn contains_blob(&self, generation: u64) -> bool {
+        self.blobs.contains(&generation)
+    }
+
+    pub fn insert_blob(&mut self, generation: u64) {
+        self.blobs.insert(generation);
+    }
+
+    pub fn remove_blob(&mut self, generation: u64) {
+        self.blobs.remove(&generation);
+    }
+
+    /// Reads the manifest in `dir`, returning `None` if the store predates it.
+    pub async fn load(dir: &Path) -> Result<Option<Self>> {
+        let manifest_path = dir.join(MANIFEST_FILE_NAME);
+        if !manifest_path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read(manifest_path).await?;
+        Ok(Some(serde_json::from_slice(&content)?))
+    }
+
+    pub async fn store(&self, dir: &Path) -> Result<()> {
+        let temp_path = dir.join(MANIFEST_TEMP_FILE_NAME);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&temp_path)
+            .await?;
+        file.write_all(&serde_json::to_vec(self)?).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        fs::rename(&temp_path, dir.join(MANIFEST_FILE_NAME)).await?;
+        sync_dir(dir)
+    }
+}
+
+/// Makes the entries of `dir` durable, where the platform allows it.
+#[cfg(unix)]
+pub(super) fn sync_dir(dir: &Path) -> Result<()> {
+    std::fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(super) fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
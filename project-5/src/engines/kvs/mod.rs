@@ -1,40 +1,109 @@
 use std::{
-    borrow::Borrow,
-    collections::BTreeMap,
     ffi::OsStr,
+    io,
     path::{Path, PathBuf},
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize},
+    time::Duration,
 };
 
 use async_std::{
-    fs::{self, File},
-    io::{BufReader, SeekFrom},
-    prelude::*,
+    fs::{self, File, OpenOptions},
+    io::BufReader,
     sync::{Arc, Mutex},
+    task,
 };
 use async_trait::async_trait;
+use crossbeam::queue::SegQueue;
 use crossbeam_skiplist::SkipMap;
-use log::error;
+use log::{error, warn};
 
-use super::KvsEngine;
+use super::{
+    expires_at, now_millis, versions::Versions, BatchOperation, BytesRange, EngineStats,
+    KvsBytesScan, KvsEngine, ScanOrder, SyncPolicy, WriteBatch,
+};
 use crate::{KvsError, Result};
+mod admin;
+mod blob;
+mod cache;
+mod checkpoint;
 mod command;
+mod compaction;
+mod compression;
 mod constants;
+mod expiry;
+mod format;
+mod garbage;
+mod group_commit;
+mod hint;
+mod index;
+mod legacy;
+mod lock;
 mod log_common;
 mod log_pointer;
+mod manifest;
+mod options;
 mod reader;
+mod record;
+mod scan;
+mod snapshot;
+mod stats;
+mod table;
 mod writer;
+pub use admin::{
+    DamagedFile, FileCheck, GenerationUsage, KvsAdmin, LogEntry, LogOperation, StoreUsage,
+};
+use blob::BlobWriter;
+pub use cache::CacheStats;
+use cache::ValueCache;
 use command::Command;
+pub use compaction::{CompactionHandle, CompactionMode};
+use compaction::{CompactionHistory, RunningCompactions};
+pub use compression::Compression;
+use expiry::spawn_expiry_sweep;
+use format::read_file_header;
+use garbage::Garbage;
+use group_commit::{PendingWrite, Write};
+use hint::{read_hint_file, write_hint_file, HintEntry};
+use index::Index;
+pub use index::IndexMode;
+use legacy::{legacy_layout, upgrade_json_layout, upgrade_unchecksummed_layout, LegacyLayout};
+use lock::{DirLock, OpenGuard};
 use log_common::*;
-use log_pointer::LogPointer;
-use reader::{deserialize_command, KvsReader};
+use manifest::Manifest;
+pub use options::{KvStoreBuilder, KvStoreOptions};
+use reader::{read_record, read_record_header, KvsReader};
+use record::Record;
+use scan::KvStoreScan;
+pub use snapshot::KvStoreSnapshot;
+use snapshot::PinnedGenerations;
+pub use stats::{GenerationStats, KvStoreStats};
+use table::SortedTable;
 use writer::KvsWriter;
 
-/// The `KvStore` stores string key/value pairs.
+/// Where the values of the keys changed while snapshots are alive lay.
+type IndexVersions = Versions<Option<log_pointer::LogPointer>>;
+
+/// The `KvStore` stores key/value pairs of bytes.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
 /// A skip list in memory stores the keys and the value locations for fast query.
+/// Stale entries are compacted away in the background once they pile up, or on
+/// demand through [`KvStore::compact`]. Large values may be kept apart in blob
+/// files instead, with a `blob` extension name, which are garbage collected
+/// likewise, or through [`KvStore::compact_blobs`].
+///
+/// A single process at a time may open a store for writing: the directory
+/// stays locked until every clone of the store is dropped, and opening it
+/// meanwhile fails with `KvsError::Locked`. A store opened read-only takes no
+/// lock, and may be opened while another process writes to it.
+///
+/// Log and blob files start with the version of their format, and a store
+/// holding a file of a version it does not know of fails to open with
+/// `KvsError::UnsupportedFormat`. A store of a legacy layout, with its
+/// commands logged as JSON into `0.log` or `1.log` or logged without
+/// checksums, is converted to the current one when opened for writing, and
+/// fails to open read-only with `KvsError::OutdatedLayout`.
 ///
 /// ```rust
 /// # use async_std::task;
@@ -58,158 +127,425 @@ use writer::KvsWriter;
 #[derive(Clone)]
 pub struct KvStore {
     path: Arc<PathBuf>,
-    index_map: Arc<SkipMap<String, LogPointer>>,
+    options: Arc<KvStoreOptions>,
+    index: Arc<Index>,
+    // The expiry timestamp of every key holding an expiring value, with every
+    // key in memory
+    expiries: Arc<SkipMap<Vec<u8>, u64>>,
     kvs_reader: KvsReader,
-    kvs_writer: Arc<Mutex<KvsWriter>>,
-    current_generation: u64,
-    uncompacted: Arc<AtomicUsize>,
+    kvs_writer: Option<Arc<Mutex<KvsWriter>>>,
+    pending_writes: Arc<SegQueue<PendingWrite>>,
+    manifest: Arc<Mutex<Manifest>>,
+    garbage: Arc<Garbage>,
+    log_bytes: Arc<AtomicUsize>,
+    compaction_lock: Arc<Mutex<()>>,
+    compacting: Arc<AtomicBool>,
+    compactions: Arc<CompactionHistory>,
+    running_compactions: Arc<RunningCompactions>,
+    // The number of writes committed since the store was opened
+    sequence: Arc<AtomicU64>,
+    pinned: Arc<PinnedGenerations>,
+    versions: Arc<IndexVersions>,
+    // The epoch of the index a snapshot of the store reads as of
+    as_of: Option<u64>,
+    // Held by a store open for writing, its background tasks included
+    _dir_lock: Option<Arc<DirLock>>,
+    // Held by the handles to a store open for writing, but not by its
+    // background tasks, see `KvStore::for_background`
+    _open_guard: Option<Arc<OpenGuard>>,
 }
 
 impl KvStore {
     pub async fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default()).await
+    }
+
+    pub fn builder(path: impl Into<PathBuf>) -> KvStoreBuilder {
+        KvStoreBuilder::new(path.into())
+    }
+
+    pub async fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<KvStore> {
         let path = Arc::new(path.into());
-        fs::create_dir_all(&*path).await?;
+        let read_only = options.read_only;
+        let dir_lock = if read_only {
+            None
+        } else {
+            fs::create_dir_all(&*path).await?;
+            Some(DirLock::acquire_for_store(&path).await?)
+        };
+
+        match legacy_layout(&path).await? {
+            Some(_) if read_only => return Err(KvsError::OutdatedLayout(path.to_path_buf())),
+            Some(LegacyLayout::Json) => upgrade_json_layout(&path, options.compressor()).await?,
+            Some(LegacyLayout::Unchecksummed) => {
+                upgrade_unchecksummed_layout(&path, options.compressor()).await?
+            }
+            None => (),
+        }
 
-        let mut readers = BTreeMap::new();
-        let index_map = Arc::new(SkipMap::new());
+        let index_mode = if read_only {
+            IndexMode::InMemory
+        } else {
+            options.index_mode
+        };
+        // Expired keys cannot be dropped from the index on disk without logging
+        // their removal; compaction leaves them behind instead
+        if index_mode == IndexMode::OnDisk && options.expiry_sweep_interval.is_some() {
+            return Err(KvsError::InvalidOptions(
+                "the expiry sweep needs the index in memory".to_owned(),
+            ));
+        }
+        let index = Index::new(index_mode, Arc::clone(&path));
+        let expiries = Arc::new(SkipMap::new());
+        let cache = Arc::new(ValueCache::new(options.value_cache_size));
+        let versions = Arc::new(IndexVersions::default());
 
-        let generations = get_log_generations(&path)?;
-        let mut uncompacted = 0;
+        let manifest = Manifest::load(&path).await?;
+        if let (Some(manifest), false) = (&manifest, read_only) {
+            remove_orphaned_files(&path, manifest, &PinnedGenerations::default()).await?;
+        }
+        let (generations, blobs) = store_files(&path, manifest.as_ref())?;
+        let garbage = Arc::new(Garbage::default());
+        let mut log_bytes = 0;
 
+        for &blob in &blobs {
+            let blob_path = blob_path(&path, blob);
+            let start = read_file_header(&blob_path).await?;
+            let end_of_file = fs::metadata(&blob_path).await?.len() as usize;
+            garbage.seal_blob(blob, end_of_file - start);
+        }
         for &generation in &generations {
             let log_path = log_path(&path, generation);
-            let mut reader = BufReader::new(File::open(&log_path).await?);
-            let end_of_file = fs::metadata(log_path).await?.len() as usize;
-            uncompacted += load(generation, &mut reader, end_of_file, &*index_map).await?;
-            readers.insert(generation, reader);
+            let start = read_file_header(&log_path).await?;
+            let end_of_file = fs::metadata(&log_path).await?.len() as usize;
+            if let Index::OnDisk(sorted) = &index {
+                if let Some(table) = SortedTable::open(&path, generation, end_of_file).await? {
+                    garbage.add(generation, table.stale_bytes);
+                    sorted.add_table(table);
+                    log_bytes += end_of_file;
+                    continue;
+                }
+            }
+
+            let (entries, valid_end) = match read_hint_file(&path, generation, end_of_file).await? {
+                Some(entries) => (entries, end_of_file),
+                None => {
+                    let file = File::open(&log_path).await?;
+                    let mut reader = BufReader::with_capacity(options.read_buffer_size, file);
+                    let is_newest = Some(&generation) == generations.last();
+                    let (entries, valid_end) =
+                        load(generation, &mut reader, start, end_of_file, is_newest).await?;
+                    if valid_end < end_of_file {
+                        warn!(
+                            "Discarded {} bytes of torn records at the tail of generation {} (offset {})",
+                            end_of_file - valid_end,
+                            generation,
+                            valid_end
+                        );
+                        if !read_only {
+                            truncate_log_file(&log_path, valid_end).await?;
+                        }
+                    }
+                    // Every replayed generation is sealed once the writer opens
+                    // a new one. A sorted table takes the place of the hint
+                    // file if the index is on disk.
+                    if !read_only && index_mode == IndexMode::InMemory {
+                        if let Err(e) =
+                            write_hint_file(&path, generation, valid_end, &entries).await
+                        {
+                            warn!(
+                                "Hint file of generation {} cannot be written: {}",
+                                generation, e
+                            );
+                        }
+                    }
+                    (entries, valid_end)
+                }
+            };
+            match &index {
+                Index::InMemory(_) => {
+                    for entry in entries {
+                        index
+                            .apply(generation, entry, &expiries, &cache, &garbage, &versions)
+                            .await;
+                    }
+                }
+                Index::OnDisk(sorted) => {
+                    let table = SortedTable::build(&path, generation, entries, valid_end).await?;
+                    garbage.add(generation, table.stale_bytes);
+                    sorted.add_table(table);
+                }
+            }
+            log_bytes += valid_end;
+        }
+        if let Index::OnDisk(sorted) = &index {
+            sorted.replay(&garbage).await?;
         }
+        let index = Arc::new(index);
 
         let current_generation = generations.last().unwrap_or(&0) + 1;
-        let pitr = Arc::new(AtomicUsize::new(0));
 
-        let kvs_reader = KvsReader::open(Arc::clone(&path), pitr, readers);
-        let kvs_writer = KvsWriter::open(Arc::clone(&path), current_generation).await?;
+        let mut manifest = Manifest::new(generations);
+        for &blob in &blobs {
+            manifest.insert_blob(blob);
+        }
+        let kvs_writer = if read_only {
+            None
+        } else {
+            manifest.insert(current_generation);
+            // A fresh blob file per open, so that none is appended to past a
+            // value torn by a crash
+            let active_blob = blobs.last().unwrap_or(&0) + 1;
+            if options.blob_threshold.is_some() {
+                manifest.insert_blob(active_blob);
+            }
+            manifest.store(&path).await?;
+            let mut kvs_writer = KvsWriter::open(
+                Arc::clone(&path),
+                current_generation,
+                options.write_buffer_size,
+                options.compressor(),
+            )
+            .await?;
+            if index_mode == IndexMode::OnDisk {
+                kvs_writer.disable_hints();
+            }
+            if let Some(blob_threshold) = options.blob_threshold {
+                let blob_writer =
+                    BlobWriter::open(Arc::clone(&path), active_blob, options.write_buffer_size)
+                        .await?;
+                kvs_writer.blob_writer = Some(blob_writer);
+                kvs_writer.blob_threshold = blob_threshold;
+            }
+            let kvs_writer = Arc::new(Mutex::new(kvs_writer));
+            if let SyncPolicy::Periodic(interval) = options.sync_policy {
+                spawn_periodic_sync(&kvs_writer, interval);
+            }
+            if let Some(interval) = options.expiry_sweep_interval {
+                spawn_expiry_sweep(
+                    &kvs_writer,
+                    Arc::clone(&index),
+                    Arc::clone(&expiries),
+                    Arc::clone(&cache),
+                    Arc::clone(&garbage),
+                    interval,
+                );
+            }
+            Some(kvs_writer)
+        };
+
+        let kvs_reader = KvsReader::open(Arc::clone(&path), cache);
+
+        let running_compactions = Arc::new(RunningCompactions::default());
+        let open_guard = dir_lock.as_ref().map(|dir_lock| {
+            Arc::new(OpenGuard {
+                dir_lock: Arc::clone(dir_lock),
+                running_compactions: Arc::clone(&running_compactions),
+            })
+        });
 
         Ok(KvStore {
             path,
-            index_map,
+            options: Arc::new(options),
+            index,
+            expiries,
             kvs_reader,
-            kvs_writer: Arc::new(Mutex::new(kvs_writer)),
-            current_generation,
-            uncompacted: Arc::new(AtomicUsize::new(uncompacted)),
+            kvs_writer,
+            pending_writes: Arc::new(SegQueue::new()),
+            manifest: Arc::new(Mutex::new(manifest)),
+            garbage,
+            log_bytes: Arc::new(AtomicUsize::new(log_bytes)),
+            compaction_lock: Arc::new(Mutex::new(())),
+            compacting: Arc::new(AtomicBool::new(false)),
+            compactions: Arc::new(CompactionHistory::default()),
+            running_compactions,
+            sequence: Arc::new(AtomicU64::new(0)),
+            pinned: Arc::new(PinnedGenerations::default()),
+            versions,
+            as_of: None,
+            _dir_lock: dir_lock,
+            _open_guard: open_guard,
         })
     }
 
-    async fn run_compaction(&self) -> Result<()> {
-        let compaction_generation = self.current_generation + 1;
-        let mut compaction_writer =
-            KvsWriter::open(Arc::clone(&self.path), compaction_generation).await?;
-
-        let index_map = self.index_map.as_ref();
-        for entry in index_map.clone().iter() {
-            let command = self
-                .kvs_reader
-                .borrow()
-                .read_command(*entry.value())
-                .await?;
-            let (offset, length) = compaction_writer.write_command(&command).await?;
-            self.index_map.insert(
-                entry.key().clone(),
-                (compaction_generation, offset..(offset + length)).into(),
-            );
+    /// A handle for a task running in the background, which does not keep the
+    /// store open: once every other handle is dropped, the task is cancelled,
+    /// and the directory lock it holds on to is released as it stops.
+    fn for_background(&self) -> KvStore {
+        KvStore {
+            _open_guard: None,
+            ..self.clone()
         }
+    }
 
-        self.kvs_reader
-            .pitr
-            .store(compaction_generation as usize, Ordering::SeqCst);
-        self.kvs_reader.close_stale_readers().await;
+    fn kvs_writer(&self) -> Result<&Mutex<KvsWriter>> {
+        self.kvs_writer.as_deref().ok_or(KvsError::ReadOnly)
+    }
 
-        remove_stale_log_files(Arc::clone(&self.path), compaction_generation).await?;
+    /// Seals the active generation and starts a new one once it has grown past
+    /// the maximum segment size, and likewise for the active blob file.
+    async fn roll_if_oversized(&self, writer: &mut KvsWriter) -> Result<()> {
+        if let Some(blob_writer) = &mut writer.blob_writer {
+            if blob_writer.size() >= constants::BLOB_FILE_SIZE {
+                self.garbage
+                    .seal_blob(blob_writer.generation, blob_writer.record_bytes());
+                let blob = blob_writer.generation + 1;
+                let mut manifest = self.manifest.lock().await;
+                manifest.insert_blob(blob);
+                manifest.store(&self.path).await?;
+                blob_writer.refresh(blob).await?;
+            }
+        }
 
-        self.uncompacted.store(0, Ordering::SeqCst);
-        self.kvs_writer
-            .lock()
-            .await
-            .refresh(compaction_generation + 1)
-            .await?;
+        match self.options.max_segment_size {
+            Some(max_segment_size) if writer.size() >= max_segment_size => {
+                let generation = writer.current_generation + 1;
+                let mut manifest = self.manifest.lock().await;
+                manifest.insert(generation);
+                manifest.store(&self.path).await?;
+                self.switch_generation(writer, generation).await
+            }
+            _ => Ok(()),
+        }
+    }
 
-        Ok(())
+    /// Seals the active generation, along with its keys if the index is on
+    /// disk, and moves on to `generation`.
+    async fn switch_generation(&self, writer: &mut KvsWriter, generation: u64) -> Result<()> {
+        let sealed_generation = writer.current_generation;
+        let sealed_bytes = writer.size() as usize;
+        writer.refresh(generation).await?;
+        self.index.seal(sealed_generation, sealed_bytes).await
     }
 }
 
 #[async_trait]
 impl KvsEngine for KvStore {
-    async fn set(&self, key: String, value: String) -> Result<()> {
-        let command = Command::Set { key, value };
-        let mut writer = self.kvs_writer.lock().await;
-        let (offset, length) = writer.write_command(&command).await?;
-        if let Command::Set { key, .. } = command {
-            if let Some(old_command) = self.index_map.get(&key) {
-                self.uncompacted
-                    .fetch_add(old_command.value().length, Ordering::SeqCst);
-            }
-            self.index_map.insert(
-                key,
-                (writer.current_generation, offset..(offset + length)).into(),
-            );
-        }
-
-        if self.uncompacted.load(Ordering::SeqCst) > constants::COMPACTION_THRESHOLD {
-            drop(writer);
-            self.run_compaction().await?;
-        }
+    async fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.commit(Write::Command(Command::Set { key, value }))
+            .await
+    }
 
-        Ok(())
+    async fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let command = Command::SetExpiring {
+            key,
+            value,
+            expires_at: expires_at(ttl),
+        };
+        self.commit(Write::Command(command)).await
     }
 
-    async fn get(&self, key: String) -> Result<Option<String>> {
-        match self.index_map.get(&key) {
-            Some(log_pointer) => {
-                if let Command::Set { value, .. } =
-                    self.kvs_reader.read_command(*log_pointer.value()).await?
-                {
-                    Ok(Some(value))
-                } else {
-                    Err(KvsError::UnexpectedCommandType)
+    async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        loop {
+            let log_pointer = match self.lookup(&key).await? {
+                Some(log_pointer) => log_pointer,
+                None => return Ok(None),
+            };
+            match self.kvs_reader.read_command(log_pointer).await {
+                Ok(Command::Set { value, .. }) => return Ok(Some(value)),
+                Ok(Command::SetExpiring {
+                    value, expires_at, ..
+                }) => return Ok(Some(value).filter(|_| expires_at > now_millis())),
+                Ok(_) => return Err(KvsError::UnexpectedCommandType),
+                // Unless the generation or blob file has been compacted away
+                // since the lookup
+                Err(KvsError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                    if self.lookup(&key).await? == Some(log_pointer) {
+                        return Err(KvsError::Io(e));
+                    }
                 }
+                Err(e) => return Err(e),
             }
-            None => Ok(None),
         }
     }
 
-    async fn remove(&self, key: String) -> Result<()> {
-        if !self.index_map.contains_key(&key) {
+    async fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        if !self.is_live(&key).await? {
             return Err(KvsError::KeyNotFound);
         }
 
-        let command = Command::Remove { key };
-        let mut writer = self.kvs_writer.lock().await;
-        let (_offset, length) = writer.write_command(&command).await?;
-        if let Command::Remove { key } = command {
-            let old_command = self.index_map.remove(&key).unwrap();
-            self.uncompacted
-                .fetch_add(old_command.value().length, Ordering::SeqCst);
-            self.uncompacted
-                .fetch_add(length as usize, Ordering::SeqCst);
-        }
+        self.commit(Write::Command(Command::Remove { key })).await
+    }
 
-        if self.uncompacted.load(Ordering::SeqCst) > constants::COMPACTION_THRESHOLD {
-            drop(writer);
-            self.run_compaction().await?;
-        }
+    async fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let command = match new {
+            Some(value) => Some(Command::Set {
+                key: key.clone(),
+                value,
+            }),
+            None if expected.is_some() => Some(Command::Remove { key: key.clone() }),
+            None => None,
+        };
+        let condition = |current| {
+            if current == expected {
+                Ok(())
+            } else {
+                Err(KvsError::CompareAndSwapMismatch { current })
+            }
+        };
+        self.commit_if(&key, condition, command).await
+    }
+
+    async fn set_bytes_if_present(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let condition = |current: Option<Vec<u8>>| current.map(|_| ()).ok_or(KvsError::KeyNotFound);
+        let command = Command::Set {
+            key: key.clone(),
+            value,
+        };
+        self.commit_if(&key, condition, Some(command)).await
+    }
+
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let commands = batch
+            .into_operations()
+            .into_iter()
+            .map(|operation| match operation {
+                BatchOperation::Set { key, value } => Command::Set { key, value },
+                BatchOperation::Remove { key } => Command::Remove { key },
+            })
+            .collect();
+        self.commit(Write::Batch(commands)).await
+    }
+
+    async fn scan_bytes(
+        &self,
+        range: BytesRange,
+        limit: Option<usize>,
+        order: ScanOrder,
+    ) -> Result<KvsBytesScan> {
+        Ok(Box::pin(KvStoreScan::new(
+            self.clone(),
+            range,
+            limit,
+            order,
+        )))
+    }
 
-        Ok(())
+    async fn checkpoint(&self, dest: PathBuf) -> Result<()> {
+        self.write_checkpoint(dest).await
+    }
+
+    async fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats::Kvs(self.collect_stats().await?))
     }
 }
 
-fn get_log_generations(path: &Path) -> Result<Vec<u64>> {
+/// Lists the generations of the files in `path` with the `extension` extension name.
+fn get_generations(path: &Path, extension: &str) -> Result<Vec<u64>> {
     let mut result: Vec<u64> = std::fs::read_dir(&path)?
         .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .filter(|path| path.is_file() && path.extension() == Some(extension.as_ref()))
         .flat_map(|path| {
-            path.file_name()
+            path.file_stem()
                 .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
                 .map(str::parse::<u64>)
         })
         .flatten()
@@ -219,56 +555,166 @@ fn get_log_generations(path: &Path) -> Result<Vec<u64>> {
     Ok(result)
 }
 
+/// Reads the records of `generation` from `start`, the offset of the first
+/// one, as hint entries.
+///
+/// Returns the entries along with the offset right after the last intact
+/// record. A damaged record is tolerated only if `tolerate_torn_tail` is set,
+/// in which case it and everything after it are left out, and if the file is
+/// known to be checksummed: it has a format header, or a record before the
+/// damaged one is intact. A file written in another layout is never taken
+/// for a torn one.
 async fn load(
     generation: u64,
     reader: &mut BufReader<File>,
+    start: usize,
     end_of_file: usize,
-    index_map: &SkipMap<String, LogPointer>,
-) -> Result<usize> {
-    let mut uncompacted = 0;
-    let mut position = 0;
+    tolerate_torn_tail: bool,
+) -> Result<(Vec<HintEntry>, usize)> {
+    let mut entries = Vec::new();
+    let mut position = start;
     while position < end_of_file {
-        let mut serialized_bytes = [0u8; 8];
-        reader.seek(SeekFrom::Start(position as u64)).await?;
-        reader.read_exact(&mut serialized_bytes).await?;
-        let data_block_size = constants::USIZE_BYTES + usize::from_le_bytes(serialized_bytes);
-        let command = deserialize_command(reader, position, data_block_size).await?;
-        match command {
-            Command::Set { key, .. } => {
-                if let Some(old_command) = index_map.get(&key) {
-                    uncompacted += old_command.value().length;
+        let (record, data_block_size) =
+            match load_record(generation, reader, position, end_of_file).await {
+                Ok(loaded) => loaded,
+                Err(KvsError::CorruptedLog { .. })
+                    if tolerate_torn_tail && (start > 0 || position > start) =>
+                {
+                    break
                 }
-                index_map.insert(
-                    key,
-                    LogPointer {
-                        generation,
-                        offset: position,
-                        length: data_block_size,
-                    },
-                );
+                Err(e) => return Err(e),
+            };
+        match record {
+            Record::Command(command) => {
+                entries.push(HintEntry::new(&command, position, data_block_size))
             }
-            Command::Remove { key } => {
-                if let Some(old_command) = index_map.remove(&key) {
-                    uncompacted += old_command.value().length;
+            Record::Batch(commands) => {
+                for (command, offset, length) in commands {
+                    entries.push(HintEntry::new(&command, position + offset, length));
                 }
-                uncompacted += data_block_size;
             }
         }
         position += data_block_size;
     }
 
-    Ok(uncompacted)
+    Ok((entries, position))
 }
 
-async fn remove_stale_log_files(path: Arc<PathBuf>, compaction_generation: u64) -> Result<()> {
-    let stale_generations = get_log_generations(&path)?
+async fn load_record(
+    generation: u64,
+    reader: &mut BufReader<File>,
+    position: usize,
+    end_of_file: usize,
+) -> Result<(Record, usize)> {
+    let corrupted = || KvsError::CorruptedLog {
+        generation,
+        offset: position,
+    };
+
+    let remaining = end_of_file - position;
+    if remaining < constants::RECORD_HEADER_BYTES {
+        return Err(corrupted());
+    }
+    let header = read_record_header(reader, position).await?;
+    if header.payload_length > remaining - constants::RECORD_HEADER_BYTES {
+        return Err(corrupted());
+    }
+
+    let record = read_record(reader, &header, generation, position).await?;
+    Ok((record, header.data_block_size()))
+}
+
+/// Syncs the active generation every `interval` for as long as the store is open.
+fn spawn_periodic_sync(kvs_writer: &Arc<Mutex<KvsWriter>>, interval: Duration) {
+    let kvs_writer = Arc::downgrade(kvs_writer);
+    task::spawn(async move {
+        loop {
+            task::sleep(interval).await;
+            let kvs_writer = match kvs_writer.upgrade() {
+                Some(kvs_writer) => kvs_writer,
+                None => break,
+            };
+            let synced = kvs_writer.lock().await.sync().await;
+            if let Err(e) = synced {
+                error!("Periodic sync failed: {}", e);
+            }
+        }
+    });
+}
+
+async fn truncate_log_file(log_path: &Path, length: usize) -> Result<()> {
+    let file = OpenOptions::new().write(true).open(log_path).await?;
+    file.set_len(length as u64).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// The generations and blob files making up the store in `path`, oldest
+/// first: those `manifest` lists that exist, or every log file found if the
+/// store predates the manifest.
+///
+/// Files left behind by an interrupted compaction or upgrade are not listed
+/// by the manifest, and are never taken for part of the store.
+fn store_files(path: &Path, manifest: Option<&Manifest>) -> Result<(Vec<u64>, Vec<u64>)> {
+    match manifest {
+        Some(manifest) => {
+            let generations = manifest
+                .generations()
+                .filter(|&generation| log_path(path, generation).exists())
+                .collect();
+            let blobs = manifest
+                .blobs()
+                .filter(|&blob| blob_path(path, blob).exists())
+                .collect();
+            Ok((generations, blobs))
+        }
+        None => Ok((get_generations(path, "log")?, Vec::new())),
+    }
+}
+
+/// Deletes the log and blob files that are neither part of `manifest` nor
+/// still read by a snapshot.
+async fn remove_orphaned_files(
+    path: &Path,
+    manifest: &Manifest,
+    pinned: &PinnedGenerations,
+) -> Result<()> {
+    let orphaned_blobs = get_generations(path, "blob")?
+        .into_iter()
+        .filter(|&blob| !manifest.contains_blob(blob) && !pinned.contains_blob(blob));
+    for orphaned_blob in orphaned_blobs {
+        let orphaned_blob_path = blob_path(path, orphaned_blob);
+        if let Err(e) = fs::remove_file(&orphaned_blob_path).await {
+            error!("{:?} cannot be deleted: {}", orphaned_blob_path, e);
+        }
+    }
+
+    let orphaned_generations = get_generations(path, "log")?
         .into_iter()
-        .filter(|gen| gen < &compaction_generation);
+        .filter(|&generation| !manifest.contains(generation) && !pinned.contains(generation));
+
+    for orphaned_generation in orphaned_generations {
+        // The hint and the sorted table go first so that they never outlive
+        // their log file
+        let mut derived_removed = true;
+        for derived_path in &[
+            hint_path(path, orphaned_generation),
+            index_path(path, orphaned_generation),
+        ] {
+            if derived_path.exists() {
+                if let Err(e) = fs::remove_file(derived_path).await {
+                    error!("{:?} cannot be deleted: {}", derived_path, e);
+                    derived_removed = false;
+                }
+            }
+        }
+        if !derived_removed {
+            continue;
+        }
 
-    for stale_generation in stale_generations {
-        let stale_log_path = log_path(&path, stale_generation);
-        if let Err(e) = fs::remove_file(&stale_log_path).await {
-            error!("{:?} cannot be deleted: {}e", stale_log_path, e);
+        let orphaned_log_path = log_path(path, orphaned_generation);
+        if let Err(e) = fs::remove_file(&orphaned_log_path).await {
+            error!("{:?} cannot be deleted: {}", orphaned_log_path, e);
         }
     }
 
@@ -0,0 +1,211 @@
+use std::{path::PathBuf, time::Duration};
+
+use super::{
+    compaction::CompactionMode,
+    compression::{Compression, Compressor},
+    constants,
+    index::IndexMode,
+    KvStore,
+};
+use crate::{engines::SyncPolicy, Result};
+
+/// Tuning knobs for a `KvStore`.
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    /// The amount of stale bytes that triggers a background compaction.
+    pub compaction_threshold: usize,
+    /// If set, compaction additionally waits until this fraction of the log
+    /// bytes is stale. Set `compaction_threshold` to 0 to rely on it alone.
+    pub compaction_dead_ratio: Option<f64>,
+    /// If set, the active generation is sealed and a new one started once it
+    /// grows past this many bytes, and compaction splits its output the same
+    /// way. Unset, the store grows one generation per open or compaction.
+    pub max_segment_size: Option<u64>,
+    /// Which generations a compaction merges, whether started in the
+    /// background or through [`KvStore::compact`].
+    pub compaction_mode: CompactionMode,
+    pub sync_policy: SyncPolicy,
+    /// Opens the store without writing to its directory nor locking it, so
+    /// that another process may write to it meanwhile. The store is seen as
+    /// of when it was opened, and reads fail once what they need has been
+    /// compacted away. Writes and compactions fail with `KvsError::ReadOnly`.
+    pub read_only: bool,
+    /// The capacity of the buffer behind each log file replayed on open.
+    pub read_buffer_size: usize,
+    /// The capacity of the buffer in front of the active log file.
+    pub write_buffer_size: usize,
+    /// How often expired keys are dropped from the index. Expired keys are
+    /// hidden from reads either way. Only an index held in memory is swept:
+    /// opening a store with `IndexMode::OnDisk` and an interval set fails with
+    /// `KvsError::InvalidOptions`, compaction leaving its expired keys behind
+    /// instead.
+    pub expiry_sweep_interval: Option<Duration>,
+    /// If set, values are compressed with this algorithm as they are logged,
+    /// including when compaction copies them. Logs written with another
+    /// setting, or none, stay readable.
+    pub compression: Option<Compression>,
+    /// The size in bytes below which a record is logged uncompressed.
+    pub compression_threshold: usize,
+    /// If set, values of at least this many bytes are kept in blob files, with
+    /// only a pointer to them in the log, so that compaction does not copy
+    /// them. Blob files are garbage collected in the background once they
+    /// pile up garbage, or on demand through [`KvStore::compact_blobs`].
+    pub blob_threshold: Option<usize>,
+    /// The fraction of a sealed blob file that must be garbage for it to be
+    /// collected, which also starts a collection in the background.
+    pub blob_garbage_ratio: f64,
+    /// Whether the keys of sealed generations are held in memory or looked up
+    /// on disk. A read-only store, which cannot write the sorted tables of the
+    /// latter, always holds them in memory.
+    pub index_mode: IndexMode,
+    /// The number of key and value bytes kept decoded in memory for the most
+    /// recently read values. 0 disables the cache.
+    pub value_cache_size: usize,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction_threshold: constants::COMPACTION_THRESHOLD,
+            compaction_dead_ratio: None,
+            max_segment_size: None,
+            compaction_mode: CompactionMode::Full,
+            sync_policy: SyncPolicy::Never,
+            read_only: false,
+            read_buffer_size: constants::DEFAULT_BUFFER_SIZE,
+            write_buffer_size: constants::DEFAULT_BUFFER_SIZE,
+            expiry_sweep_interval: Some(constants::EXPIRY_SWEEP_INTERVAL),
+            compression: None,
+            compression_threshold: constants::COMPRESSION_THRESHOLD,
+            blob_threshold: None,
+            blob_garbage_ratio: constants::BLOB_GARBAGE_RATIO,
+            index_mode: IndexMode::InMemory,
+            value_cache_size: constants::VALUE_CACHE_SIZE,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub(super) fn compressor(&self) -> Compressor {
+        Compressor {
+            compression: self.compression,
+            threshold: self.compression_threshold,
+        }
+    }
+}
+
+/// Configures and opens a `KvStore`.
+///
+/// ```rust
+/// # use async_std::task;
+/// # use kvs::{KvStore, Result, SyncPolicy};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// task::block_on(async move {
+///     let store = KvStore::builder(current_dir().unwrap())
+///         .compaction_threshold(4 * 1024 * 1024)
+///         .sync_policy(SyncPolicy::Always)
+///         .open()
+///         .await
+///         .unwrap();
+/// });
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvStoreBuilder {
+    path: PathBuf,
+    options: KvStoreOptions,
+}
+
+impl KvStoreBuilder {
+    pub(super) fn new(path: PathBuf) -> Self {
+        KvStoreBuilder {
+            path,
+            options: KvStoreOptions::default(),
+        }
+    }
+
+    pub fn options(mut self, options: KvStoreOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn compaction_threshold(mut self, bytes: usize) -> Self {
+        self.options.compaction_threshold = bytes;
+        self
+    }
+
+    pub fn compaction_dead_ratio(mut self, ratio: f64) -> Self {
+        self.options.compaction_dead_ratio = Some(ratio);
+        self
+    }
+
+    pub fn max_segment_size(mut self, bytes: u64) -> Self {
+        self.options.max_segment_size = Some(bytes);
+        self
+    }
+
+    pub fn compaction_mode(mut self, compaction_mode: CompactionMode) -> Self {
+        self.options.compaction_mode = compaction_mode;
+        self
+    }
+
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.options.sync_policy = sync_policy;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        self.options.read_buffer_size = bytes;
+        self
+    }
+
+    pub fn write_buffer_size(mut self, bytes: usize) -> Self {
+        self.options.write_buffer_size = bytes;
+        self
+    }
+
+    pub fn expiry_sweep_interval(mut self, interval: Option<Duration>) -> Self {
+        self.options.expiry_sweep_interval = interval;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.options.compression = Some(compression);
+        self
+    }
+
+    pub fn compression_threshold(mut self, bytes: usize) -> Self {
+        self.options.compression_threshold = bytes;
+        self
+    }
+
+    pub fn blob_threshold(mut self, bytes: usize) -> Self {
+        self.options.blob_threshold = Some(bytes);
+        self
+    }
+
+    pub fn blob_garbage_ratio(mut self, ratio: f64) -> Self {
+        self.options.blob_garbage_ratio = ratio;
+        self
+    }
+
+    pub fn index_mode(mut self, index_mode: IndexMode) -> Self {
+        self.options.index_mode = index_mode;
+        self
+    }
+
+    pub fn value_cache_size(mut self, bytes: usize) -> Self {
+        self.options.value_cache_size = bytes;
+        self
+    }
+
+    pub async fn open(self) -> Result<KvStore> {
+        KvStore::open_with_options(self.path, self.options).await
+    }
+}
@@ -1,105 +1,202 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
+    convert::TryInto,
+    fs, io,
     path::PathBuf,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
+    sync::{Arc, RwLock},
 };
 
 use async_std::{
     fs::File,
     io::{BufReader, SeekFrom},
     prelude::*,
+    task,
 };
-use crossbeam::atomic::AtomicCell;
 
-use super::{command::Command, constants, log_common::*, log_pointer::LogPointer};
-use crate::Result;
+use super::{
+    blob::read_blob,
+    cache::ValueCache,
+    command::Command,
+    constants,
+    log_common::*,
+    log_pointer::LogPointer,
+    record::{decode_record, Record, RecordHeader},
+};
+use crate::{KvsError, Result};
 
+/// Reads commands from the log files by position.
+///
+/// Each log file is opened once and its handle shared by every clone of the
+/// reader. Reads never move a file cursor, so any number of them may go on at
+/// once, on the same handle or not.
+#[derive(Clone)]
 pub struct KvsReader {
     path: Arc<PathBuf>,
-    pub pitr: Arc<AtomicUsize>,
-    readers: AtomicCell<BTreeMap<u64, BufReader<File>>>,
+    files: Arc<RwLock<OpenFiles>>,
+    pub cache: Arc<ValueCache>,
+}
+
+#[derive(Default)]
+struct OpenFiles {
+    files: BTreeMap<u64, Arc<fs::File>>,
+    // The generations compacted away, which are not to be kept open again
+    closed: BTreeSet<u64>,
 }
 
 impl KvsReader {
-    pub fn open(
-        path: Arc<PathBuf>,
-        pitr: Arc<AtomicUsize>,
-        readers: BTreeMap<u64, BufReader<File>>,
-    ) -> Self {
+    pub fn open(path: Arc<PathBuf>, cache: Arc<ValueCache>) -> Self {
         KvsReader {
             path,
-            pitr,
-            readers: AtomicCell::new(readers),
+            files: Arc::new(RwLock::new(OpenFiles::default())),
+            cache,
         }
     }
 
+    /// Reads the command `log_pointer` points to, with a value kept in a blob
+    /// file read back as part of a `Command::Set` or `Command::SetExpiring`.
+    ///
+    /// The command is served from the value cache if it holds it, and cached
+    /// otherwise.
     pub async fn read_command(&self, log_pointer: LogPointer) -> Result<Command> {
-        self.close_stale_readers().await;
-
-        let mut readers = self.readers.take();
-        let command = self.read_command_helper(&mut readers, log_pointer).await?;
-        self.readers.store(readers);
+        if let Some(command) = self.cache.get(log_pointer) {
+            return Ok(command);
+        }
 
+        let command = self.read_uncached_command(log_pointer).await?;
+        self.cache.insert(log_pointer, &command);
         Ok(command)
     }
 
-    pub async fn close_stale_readers(&self) {
-        let mut readers = self.readers.take();
-        while !readers.is_empty() {
-            let generation = *readers.keys().next().unwrap();
-            if generation >= self.pitr.load(Ordering::SeqCst) as u64 {
-                break;
+    async fn read_uncached_command(&self, log_pointer: LogPointer) -> Result<Command> {
+        match self.read_log_command(log_pointer).await? {
+            Command::SetBlob {
+                key,
+                blob,
+                expires_at,
+            } => {
+                let value = read_blob(&self.path, blob).await?;
+                Ok(match expires_at {
+                    Some(expires_at) => Command::SetExpiring {
+                        key,
+                        value,
+                        expires_at,
+                    },
+                    None => Command::Set { key, value },
+                })
             }
-            readers.remove(&generation);
+            command => Ok(command),
         }
-        self.readers.store(readers);
     }
 
-    async fn read_command_helper(
-        &self,
-        readers: &mut BTreeMap<u64, BufReader<File>>,
-        log_pointer: LogPointer,
-    ) -> Result<Command> {
-        if !readers.contains_key(&log_pointer.generation) {
-            let reader =
-                BufReader::new(File::open(log_path(&self.path, log_pointer.generation)).await?);
-            readers.insert(log_pointer.generation, reader);
+    /// Reads the command `log_pointer` points to as logged.
+    pub async fn read_log_command(&self, log_pointer: LogPointer) -> Result<Command> {
+        let LogPointer {
+            generation,
+            offset,
+            length,
+        } = log_pointer;
+        let corrupted = || KvsError::CorruptedLog { generation, offset };
+        if length < constants::RECORD_HEADER_BYTES {
+            return Err(corrupted());
+        }
+
+        let file = self.file(generation).await?;
+        let bytes = task::spawn_blocking(move || {
+            let mut bytes = vec![0; length];
+            read_exact_at(&file, &mut bytes, offset as u64).map(|_| bytes)
+        })
+        .await?;
+
+        let (header_bytes, payload) = bytes.split_at(constants::RECORD_HEADER_BYTES);
+        let header = RecordHeader::decode(header_bytes.try_into()?)?;
+        if header.data_block_size() != length {
+            return Err(corrupted());
         }
+        match decode_record(&header, payload, generation, offset)? {
+            Record::Command(command) => Ok(command),
+            // The index points to the records within a batch, never to the batch
+            Record::Batch(_) => Err(KvsError::UnexpectedCommandType),
+        }
+    }
 
-        let reader = readers.get_mut(&log_pointer.generation).unwrap();
-        reader
-            .seek(SeekFrom::Start(log_pointer.offset as u64))
-            .await?;
-        let mut serialized_bytes = [0u8; 8];
-        reader.read_exact(&mut serialized_bytes).await?;
-        let data_block_size = constants::USIZE_BYTES + usize::from_le_bytes(serialized_bytes);
-        deserialize_command(reader, log_pointer.offset, data_block_size).await
+    /// Closes the log files of `generations`, compacted away.
+    pub fn close_stale_readers(&self, generations: &BTreeSet<u64>) {
+        let mut open_files = self.files.write().unwrap();
+        for generation in generations {
+            open_files.files.remove(generation);
+            open_files.closed.insert(*generation);
+        }
+    }
+
+    /// Returns the shared handle to the log file of `generation`, opening it
+    /// if need be.
+    async fn file(&self, generation: u64) -> Result<Arc<fs::File>> {
+        if let Some(file) = self.files.read().unwrap().files.get(&generation) {
+            return Ok(Arc::clone(file));
+        }
+
+        let log_path = log_path(&self.path, generation);
+        let file = Arc::new(task::spawn_blocking(move || fs::File::open(log_path)).await?);
+        let mut open_files = self.files.write().unwrap();
+        // A generation compacted away in the meantime is read from once more,
+        // but not kept open
+        if open_files.closed.contains(&generation) {
+            return Ok(file);
+        }
+        Ok(Arc::clone(
+            open_files.files.entry(generation).or_insert(file),
+        ))
     }
 }
 
-impl Clone for KvsReader {
-    fn clone(&self) -> Self {
-        KvsReader {
-            path: Arc::clone(&self.path),
-            pitr: Arc::clone(&self.pitr),
-            readers: AtomicCell::new(BTreeMap::new()),
+#[cfg(unix)]
+fn read_exact_at(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &fs::File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
         }
     }
+    Ok(())
+}
+
+pub(super) async fn read_record_header(
+    reader: &mut BufReader<File>,
+    offset: usize,
+) -> Result<RecordHeader> {
+    let mut header_bytes = [0u8; constants::RECORD_HEADER_BYTES];
+    reader.seek(SeekFrom::Start(offset as u64)).await?;
+    reader.read_exact(&mut header_bytes).await?;
+    RecordHeader::decode(&header_bytes)
 }
 
-pub(super) async fn deserialize_command(
+pub(super) async fn read_record(
     reader: &mut BufReader<File>,
+    header: &RecordHeader,
+    generation: u64,
     offset: usize,
-    data_block_size: usize,
-) -> Result<Command> {
-    let mut buf = vec![0; data_block_size - constants::USIZE_BYTES];
+) -> Result<Record> {
+    let mut payload = vec![0; header.payload_length];
     reader
-        .seek(SeekFrom::Start((offset + constants::USIZE_BYTES) as u64))
+        .seek(SeekFrom::Start(
+            (offset + constants::RECORD_HEADER_BYTES) as u64,
+        ))
         .await?;
-    reader.read_exact(&mut buf).await?;
+    reader.read_exact(&mut payload).await?;
 
-    Ok(bincode::deserialize(&buf)?)
+    decode_record(header, &payload, generation, offset)
 }
@@ -0,0 +1,196 @@
+use std::convert::TryInto;
+
+use super::{
+    command::Command,
+    compression::{decompress, Compressor, UNCOMPRESSED},
+    constants,
+};
+use crate::{KvsError, Result};
+
+/// The kind of payload carried by a record.
+///
+/// The type takes the low four bits of the record type byte, and the codec
+/// the payload is compressed with the high four, zero standing for an
+/// uncompressed payload as in logs written before compression. The byte is
+/// covered by the checksum so that a record cannot be misinterpreted after a
+/// partial overwrite.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum RecordType {
+    Command = 1,
+    /// The payload is a sequence of `Command` records, which are replayed
+    /// all together or not at all.
+    Batch = 2,
+}
+
+impl RecordType {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(RecordType::Command),
+            2 => Some(RecordType::Batch),
+            _ => None,
+        }
+    }
+}
+
+/// The content of a record.
+#[derive(Debug)]
+pub enum Record {
+    Command(Command),
+    /// The commands of a batch, each with the offset of its own record
+    /// relative to the batch record and the length of that record.
+    Batch(Vec<(Command, usize, usize)>),
+}
+
+/// A record ready to be appended to a log file.
+pub struct EncodedRecord {
+    pub bytes: Vec<u8>,
+    /// The commands carried by the record, each with the offset of its own
+    /// record relative to `bytes` and the length of that record.
+    ///
+    /// The record of a command can be read on its own, so the index points
+    /// to it even when it is part of a batch.
+    pub commands: Vec<(Command, usize, usize)>,
+}
+
+/// The fixed-size header preceding every record in a log file.
+///
+/// Layout: `[payload length: usize LE][crc32: u32 LE][record type: u8]`,
+/// where the checksum covers the record type byte and the payload as stored.
+#[derive(Clone, Copy, Debug)]
+pub struct RecordHeader {
+    pub payload_length: usize,
+    checksum: u32,
+    record_type: u8,
+}
+
+impl RecordHeader {
+    pub fn decode(bytes: &[u8; constants::RECORD_HEADER_BYTES]) -> Result<Self> {
+        let (length_bytes, rest) = bytes.split_at(constants::USIZE_BYTES);
+        let (checksum_bytes, type_bytes) = rest.split_at(constants::CHECKSUM_BYTES);
+        Ok(RecordHeader {
+            payload_length: usize::from_le_bytes(length_bytes.try_into()?),
+            checksum: u32::from_le_bytes(checksum_bytes.try_into()?),
+            record_type: type_bytes[0],
+        })
+    }
+
+    pub fn data_block_size(&self) -> usize {
+        constants::RECORD_HEADER_BYTES + self.payload_length
+    }
+}
+
+/// Serializes `command` into a record of its own.
+pub fn encode_record(command: Command, compressor: &Compressor) -> Result<EncodedRecord> {
+    let bytes = encode_command(&command, compressor)?;
+    let length = bytes.len();
+    Ok(EncodedRecord {
+        bytes,
+        commands: vec![(command, 0, length)],
+    })
+}
+
+/// Serializes `commands` into a single batch record.
+pub fn encode_batch_record(
+    commands: Vec<Command>,
+    compressor: &Compressor,
+) -> Result<EncodedRecord> {
+    let mut payload = Vec::new();
+    let mut located = Vec::with_capacity(commands.len());
+    for command in commands {
+        let record = encode_command(&command, compressor)?;
+        located.push((
+            command,
+            constants::RECORD_HEADER_BYTES + payload.len(),
+            record.len(),
+        ));
+        payload.extend_from_slice(&record);
+    }
+
+    Ok(EncodedRecord {
+        bytes: encode(RecordType::Batch, UNCOMPRESSED, &payload),
+        commands: located,
+    })
+}
+
+fn encode_command(command: &Command, compressor: &Compressor) -> Result<Vec<u8>> {
+    let (codec, payload) = compressor.compress(bincode::serialize(command)?)?;
+    Ok(encode(RecordType::Command, codec, &payload))
+}
+
+fn encode(record_type: RecordType, codec: u8, payload: &[u8]) -> Vec<u8> {
+    let record_type = record_type as u8 | codec << 4;
+    let mut record = Vec::with_capacity(constants::RECORD_HEADER_BYTES + payload.len());
+    record.extend_from_slice(&payload.len().to_le_bytes());
+    record.extend_from_slice(&checksum(record_type, payload).to_le_bytes());
+    record.push(record_type);
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Verifies `payload` against `header` and deserializes the record it carries.
+///
+/// Any mismatch is reported as `KvsError::CorruptedLog` at `generation` and `offset`.
+pub fn decode_record(
+    header: &RecordHeader,
+    payload: &[u8],
+    generation: u64,
+    offset: usize,
+) -> Result<Record> {
+    let corrupted = || KvsError::CorruptedLog { generation, offset };
+
+    if checksum(header.record_type, payload) != header.checksum {
+        return Err(corrupted());
+    }
+
+    let codec = header.record_type >> 4;
+    match RecordType::from_byte(header.record_type & 0x0f) {
+        Some(RecordType::Command) => {
+            let payload = decompress(codec, payload).ok_or_else(corrupted)?;
+            bincode::deserialize(&payload)
+                .map(Record::Command)
+                .map_err(|_| corrupted())
+        }
+        Some(RecordType::Batch) if codec == UNCOMPRESSED => {
+            let mut commands = Vec::new();
+            let mut position = 0;
+            while position < payload.len() {
+                let rest = &payload[position..];
+                if rest.len() < constants::RECORD_HEADER_BYTES {
+                    return Err(corrupted());
+                }
+                let (header_bytes, rest) = rest.split_at(constants::RECORD_HEADER_BYTES);
+                let header = RecordHeader::decode(header_bytes.try_into()?)?;
+                if header.payload_length > rest.len() {
+                    return Err(corrupted());
+                }
+
+                let relative_offset = constants::RECORD_HEADER_BYTES + position;
+                let record = decode_record(
+                    &header,
+                    &rest[..header.payload_length],
+                    generation,
+                    offset + relative_offset,
+                )?;
+                match record {
+                    Record::Command(command) => {
+                        commands.push((command, relative_offset, header.data_block_size()))
+                    }
+                    // Batches do not nest
+                    Record::Batch(_) => return Err(corrupted()),
+                }
+                position += header.data_block_size();
+            }
+            Ok(Record::Batch(commands))
+        }
+        // Batches are never compressed as a whole
+        _ => Err(corrupted()),
+    }
+}
+
+fn checksum(record_type: u8, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[record_type]);
+    hasher.update(payload);
+    hasher.finalize()
+}
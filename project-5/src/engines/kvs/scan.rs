@@ -0,0 +1,115 @@
+use std::{
+    ops::Bound,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_std::{future::Future, stream::Stream};
+
+use super::KvStore;
+use crate::{
+    engines::{BytesRange, KvsEngine, ScanOrder},
+    Result,
+};
+
+type NextPair = Pin<Box<dyn Future<Output = Result<Option<(Vec<u8>, Vec<u8>)>>> + Send>>;
+
+/// Walks the index of a `KvStore` one key at a time.
+///
+/// Rather than holding on to an iterator over the index, the scan narrows its
+/// range past each key it visits and looks the next one up afresh, so that it
+/// neither borrows the store nor blocks writers.
+pub(super) struct KvStoreScan {
+    store: KvStore,
+    range: BytesRange,
+    remaining: Option<usize>,
+    order: ScanOrder,
+    next_pair: Option<NextPair>,
+}
+
+impl KvStoreScan {
+    pub(super) fn new(
+        store: KvStore,
+        range: BytesRange,
+        limit: Option<usize>,
+        order: ScanOrder,
+    ) -> Self {
+        KvStoreScan {
+            store,
+            range,
+            remaining: limit,
+            order,
+            next_pair: None,
+        }
+    }
+}
+
+impl Stream for KvStoreScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.remaining == Some(0) {
+            return Poll::Ready(None);
+        }
+
+        if self.next_pair.is_none() {
+            let store = self.store.clone();
+            let range = self.range.clone();
+            let order = self.order;
+            self.next_pair = Some(Box::pin(async move { store.next_pair(range, order).await }));
+        }
+
+        let next_pair = self.next_pair.as_mut().unwrap().as_mut().poll(cx);
+        let pair = match next_pair {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(pair) => pair,
+        };
+        self.next_pair = None;
+
+        match pair {
+            Ok(Some((key, value))) => {
+                match self.order {
+                    ScanOrder::Forward => self.range.0 = Bound::Excluded(key.clone()),
+                    ScanOrder::Reverse => self.range.1 = Bound::Excluded(key.clone()),
+                }
+                if let Some(remaining) = &mut self.remaining {
+                    *remaining -= 1;
+                }
+                Poll::Ready(Some(Ok((key, value))))
+            }
+            Ok(None) => {
+                self.remaining = Some(0);
+                Poll::Ready(None)
+            }
+            Err(e) => {
+                self.remaining = Some(0);
+                Poll::Ready(Some(Err(e)))
+            }
+        }
+    }
+}
+
+impl KvStore {
+    /// Finds the first key within `range` in `order` along with its value.
+    async fn next_pair(
+        &self,
+        mut range: BytesRange,
+        order: ScanOrder,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            let key = match self.first_key(&range, order).await? {
+                Some(key) => key,
+                None => return Ok(None),
+            };
+
+            match self.get_bytes(key.clone()).await? {
+                Some(value) => return Ok(Some((key, value))),
+                // The key has been removed, since the lookup or before
+                None => match order {
+                    ScanOrder::Forward => range.0 = Bound::Excluded(key),
+                    ScanOrder::Reverse => range.1 = Bound::Excluded(key),
+                },
+            }
+        }
+    }
+}
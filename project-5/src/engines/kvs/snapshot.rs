@@ -0,0 +1,314 @@
+use std::{
+    collections::BTreeMap,
+    sync::{atomic::Ordering, Mutex as StdMutex},
+};
+
+use async_std::{
+    sync::{Arc, Mutex},
+    task,
+};
+use log::error;
+
+use super::{
+    log_pointer::LogPointer, manifest::Manifest, reader::KvsReader, remove_orphaned_files,
+    IndexVersions, KvStore,
+};
+use crate::{
+    engines::{BytesRange, KeyRange, KvsBytesScan, KvsEngine, KvsScan, ScanOrder},
+    Result,
+};
+
+/// The generations and blob files that live snapshots may still read from,
+/// each with the number of snapshots pinning it.
+///
+/// A pinned file outlives its removal from the manifest, and is only deleted
+/// once the last snapshot reading from it is gone. A file removed while a
+/// snapshot is alive is pinned by it as well: the snapshot may come to read a
+/// value that was moved there after it was taken.
+#[derive(Debug, Default)]
+pub(super) struct PinnedGenerations(StdMutex<Pins>);
+
+#[derive(Debug, Default)]
+struct Pins {
+    // The files each live pin keeps, by pin
+    pins: BTreeMap<u64, PinnedFiles>,
+    next_pin: u64,
+    generations: BTreeMap<u64, usize>,
+    blobs: BTreeMap<u64, usize>,
+}
+
+#[derive(Debug, Default)]
+struct PinnedFiles {
+    generations: Vec<u64>,
+    blobs: Vec<u64>,
+}
+
+impl PinnedGenerations {
+    fn pin(&self, generations: Vec<u64>, blobs: Vec<u64>) -> u64 {
+        let mut pins = self.0.lock().unwrap();
+        pin(&mut pins.generations, &generations);
+        pin(&mut pins.blobs, &blobs);
+        let id = pins.next_pin;
+        pins.next_pin += 1;
+        pins.pins.insert(id, PinnedFiles { generations, blobs });
+        id
+    }
+
+    fn unpin(&self, id: u64) {
+        let mut pins = self.0.lock().unwrap();
+        if let Some(files) = pins.pins.remove(&id) {
+            unpin(&mut pins.generations, &files.generations);
+            unpin(&mut pins.blobs, &files.blobs);
+        }
+    }
+
+    /// Pins `generations` and `blobs`, removed from the manifest, on behalf
+    /// of every live pin.
+    pub(super) fn retire(&self, generations: &[u64], blobs: &[u64]) {
+        let mut pins = self.0.lock().unwrap();
+        let Pins {
+            pins,
+            generations: pinned_generations,
+            blobs: pinned_blobs,
+            ..
+        } = &mut *pins;
+        for files in pins.values_mut() {
+            pin(pinned_generations, generations);
+            pin(pinned_blobs, blobs);
+            files.generations.extend_from_slice(generations);
+            files.blobs.extend_from_slice(blobs);
+        }
+    }
+
+    pub(super) fn contains(&self, generation: u64) -> bool {
+        self.0.lock().unwrap().generations.contains_key(&generation)
+    }
+
+    pub(super) fn contains_blob(&self, generation: u64) -> bool {
+        self.0.lock().unwrap().blobs.contains_key(&generation)
+    }
+}
+
+fn pin(pinned: &mut BTreeMap<u64, usize>, generations: &[u64]) {
+    for &generation in generations {
+        *pinned.entry(generation).or_insert(0) += 1;
+    }
+}
+
+fn unpin(pinned: &mut BTreeMap<u64, usize>, generations: &[u64]) {
+    for generation in generations {
+        if let Some(count) = pinned.get_mut(generation) {
+            *count -= 1;
+            if *count == 0 {
+                pinned.remove(generation);
+            }
+        }
+    }
+}
+
+/// A read-only view of a `KvStore` as of the moment it was taken.
+///
+/// Writes made to the store afterwards are not visible through the snapshot,
+/// and compaction keeps the log files it reads from until every clone of the
+/// snapshot has been dropped. Values set with a TTL still expire on schedule.
+///
+/// ```rust
+/// # use async_std::task;
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// task::block_on(async move {
+///     let store = KvStore::open(current_dir().unwrap()).await.unwrap();
+///     store.set("key".to_owned(), "old".to_owned()).await.unwrap();
+///     let snapshot = store.snapshot().await.unwrap();
+///     store.set("key".to_owned(), "new".to_owned()).await.unwrap();
+///     let val = snapshot.get("key".to_owned()).await.unwrap();
+///     assert_eq!(val, Some("old".to_owned()));
+/// });
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct KvStoreSnapshot {
+    // A read-only view of the store as of the snapshot
+    store: KvStore,
+    sequence: u64,
+    _pin: Arc<SnapshotPin>,
+}
+
+impl KvStoreSnapshot {
+    /// The number of writes committed to the store since it was opened, as of
+    /// the snapshot. A batch counts as a single write.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.store.get_bytes(key).await
+    }
+
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.get(key).await
+    }
+
+    /// Visits the keys within `range` in `order` as of the snapshot, stopping
+    /// after `limit` pairs if given.
+    pub async fn scan_bytes(
+        &self,
+        range: BytesRange,
+        limit: Option<usize>,
+        order: ScanOrder,
+    ) -> Result<KvsBytesScan> {
+        self.store.scan_bytes(range, limit, order).await
+    }
+
+    /// Visits the keys starting with `prefix` in `order` as of the snapshot.
+    pub async fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+        order: ScanOrder,
+    ) -> Result<KvsBytesScan> {
+        self.store.scan_prefix_bytes(prefix, order).await
+    }
+
+    pub async fn scan(
+        &self,
+        range: KeyRange,
+        limit: Option<usize>,
+        order: ScanOrder,
+    ) -> Result<KvsScan> {
+        self.store.scan(range, limit, order).await
+    }
+
+    pub async fn scan_prefix(&self, prefix: String, order: ScanOrder) -> Result<KvsScan> {
+        self.store.scan_prefix(prefix, order).await
+    }
+}
+
+/// Keeps the generations and blob files a snapshot, or a checkpoint under
+/// way, reads from until it is dropped, along with the versions of the index
+/// the snapshot reads.
+pub(super) struct SnapshotPin {
+    path: Arc<std::path::PathBuf>,
+    id: u64,
+    pinned: Arc<PinnedGenerations>,
+    // The epoch of the snapshot, if the pin is one's
+    epoch: Option<u64>,
+    versions: Arc<IndexVersions>,
+    manifest: Arc<Mutex<Manifest>>,
+    compaction_lock: Arc<Mutex<()>>,
+}
+
+impl Drop for SnapshotPin {
+    fn drop(&mut self) {
+        if let Some(epoch) = self.epoch {
+            self.versions.close_snapshot(epoch);
+        }
+        self.pinned.unpin(self.id);
+
+        // Deletes the generations compacted away while they were pinned. The
+        // compaction lock keeps the output of a compaction under way, which is
+        // not part of the manifest yet, from being mistaken for an orphan.
+        let path = Arc::clone(&self.path);
+        let pinned = Arc::clone(&self.pinned);
+        let manifest = Arc::clone(&self.manifest);
+        let compaction_lock = Arc::clone(&self.compaction_lock);
+        task::spawn(async move {
+            let _compaction_guard = compaction_lock.lock().await;
+            let manifest = manifest.lock().await;
+            if let Err(e) = remove_orphaned_files(&path, &manifest, &pinned).await {
+                error!("Log files released by a snapshot cannot be deleted: {}", e);
+            }
+        });
+    }
+}
+
+impl KvStore {
+    /// Takes a consistent, read-only snapshot of the store.
+    ///
+    /// Writes are only held off while a new epoch of the index is started and
+    /// the files of the store are pinned, so taking a snapshot costs little
+    /// whatever the number of keys. From then on, every key changed keeps its
+    /// earlier version in memory for as long as the snapshot is alive.
+    pub async fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        let writer = match &self.kvs_writer {
+            Some(kvs_writer) => Some(kvs_writer.lock().await),
+            None => None,
+        };
+
+        let epoch = self.versions.open_snapshot();
+        let sequence = self.sequence.load(Ordering::SeqCst);
+
+        // Every generation and blob file the index may point to is in the
+        // manifest, which compaction only changes under the writer lock
+        let (generations, blobs): (Vec<u64>, Vec<u64>) = if writer.is_some() {
+            let manifest = self.manifest.lock().await;
+            (manifest.generations().collect(), manifest.blobs().collect())
+        } else {
+            (Vec::new(), Vec::new())
+        };
+        let mut pin = self.pin(generations, blobs);
+        pin.epoch = Some(epoch);
+        drop(writer);
+
+        let kvs_reader =
+            KvsReader::open(Arc::clone(&self.path), Arc::clone(&self.kvs_reader.cache));
+        let store = KvStore {
+            kvs_reader,
+            kvs_writer: None,
+            as_of: Some(epoch),
+            ..self.clone()
+        };
+        Ok(KvStoreSnapshot {
+            store,
+            sequence,
+            _pin: Arc::new(pin),
+        })
+    }
+
+    /// Keeps `generations` and `blobs` from being deleted until the returned
+    /// pin is dropped.
+    pub(super) fn pin(&self, generations: Vec<u64>, blobs: Vec<u64>) -> SnapshotPin {
+        SnapshotPin {
+            path: Arc::clone(&self.path),
+            id: self.pinned.pin(generations, blobs),
+            pinned: Arc::clone(&self.pinned),
+            epoch: None,
+            versions: Arc::clone(&self.versions),
+            manifest: Arc::clone(&self.manifest),
+            compaction_lock: Arc::clone(&self.compaction_lock),
+        }
+    }
+
+    /// Where the value of `key` lies, as of the snapshot if the store is one.
+    pub(super) async fn lookup(&self, key: &[u8]) -> Result<Option<LogPointer>> {
+        // The index goes first: a change made after it is read has kept the
+        // version as of the snapshot by the time the versions are read
+        let log_pointer = self.index.get(key).await?;
+        match self.as_of {
+            Some(epoch) => Ok(self.versions.get(key, epoch).unwrap_or(log_pointer)),
+            None => Ok(log_pointer),
+        }
+    }
+
+    /// Finds the first key within `range` in `order`, as of the snapshot if
+    /// the store is one.
+    ///
+    /// The key may turn out to have been removed, or not to have existed yet
+    /// as of the snapshot.
+    pub(super) async fn first_key(
+        &self,
+        range: &BytesRange,
+        order: ScanOrder,
+    ) -> Result<Option<Vec<u8>>> {
+        let key = self.index.first_key(range, order).await?;
+        if self.as_of.is_none() {
+            return Ok(key);
+        }
+        let keys = key.into_iter().chain(self.versions.first_key(range, order));
+        Ok(match order {
+            ScanOrder::Forward => keys.min(),
+            ScanOrder::Reverse => keys.max(),
+        })
+    }
+}
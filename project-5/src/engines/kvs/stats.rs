@@ -0,0 +1,84 @@
+use async_std::fs;
+use serde::{Deserialize, Serialize};
+
+use super::{cache::CacheStats, log_common::*, KvStore};
+use crate::Result;
+
+/// The contents and the disk usage of a `KvStore`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct KvStoreStats {
+    /// The number of keys, counting those expired but not swept yet.
+    pub keys: usize,
+    /// The stale bytes of the log files, which compaction reclaims.
+    pub garbage_bytes: usize,
+    /// The generations making up the store, oldest first.
+    pub generations: Vec<GenerationStats>,
+    /// The bytes of the blob files.
+    pub blob_bytes: u64,
+    /// The number of compactions committed since the store was opened.
+    pub compactions: u64,
+    /// The time spent in those compactions, in milliseconds.
+    pub compaction_millis: u64,
+    /// When the last compaction was committed, in milliseconds since the Unix
+    /// epoch.
+    pub last_compaction_at: Option<u64>,
+    pub cache: CacheStats,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct GenerationStats {
+    pub generation: u64,
+    pub bytes: u64,
+    /// The stale bytes of the generation.
+    pub garbage_bytes: usize,
+}
+
+impl KvStore {
+    /// Reports on the store, as `KvsEngine::stats` does.
+    ///
+    /// Every key is counted through the index, which means reading every
+    /// sorted table if the index is on disk.
+    pub(super) async fn collect_stats(&self) -> Result<KvStoreStats> {
+        let writer = match &self.kvs_writer {
+            Some(kvs_writer) => Some(kvs_writer.lock().await),
+            None => None,
+        };
+        let manifest = self.manifest.lock().await;
+        let garbage = self.garbage.by_generation();
+        let mut generations = Vec::new();
+        for generation in manifest.generations() {
+            let bytes = match &writer {
+                Some(writer) if writer.current_generation == generation => writer.size(),
+                _ => fs::metadata(log_path(&self.path, generation)).await?.len(),
+            };
+            generations.push(GenerationStats {
+                generation,
+                bytes,
+                garbage_bytes: garbage.get(&generation).copied().unwrap_or(0),
+            });
+        }
+        let mut blob_bytes = 0;
+        for blob in manifest.blobs() {
+            blob_bytes += match writer
+                .as_ref()
+                .and_then(|writer| writer.blob_writer.as_ref())
+            {
+                Some(blob_writer) if blob_writer.generation == blob => blob_writer.size(),
+                _ => fs::metadata(blob_path(&self.path, blob)).await?.len(),
+            };
+        }
+        drop(manifest);
+        drop(writer);
+
+        Ok(KvStoreStats {
+            keys: self.index.len().await?,
+            garbage_bytes: self.garbage.total(),
+            generations,
+            blob_bytes,
+            compactions: self.compactions.count(),
+            compaction_millis: self.compactions.millis(),
+            last_compaction_at: self.compactions.last_committed_at(),
+            cache: self.cache_stats(),
+        })
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::TryInto,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, SeekFrom},
+    prelude::*,
+    sync::Mutex,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{constants, hint::HintEntry, log_common::*};
+use crate::{
+    engines::{BytesRange, ScanOrder},
+    KvsError, Result,
+};
+
+const FOOTER_LENGTH_BYTES: usize = std::mem::size_of::<u64>();
+
+/// The first key of a block, along with where the block lies in the table.
+#[derive(Debug, Deserialize, Serialize)]
+struct Fence {
+    first_key: Vec<u8>,
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Footer {
+    fences: Vec<Fence>,
+    log_length: usize,
+    stale_bytes: usize,
+}
+
+/// The hint entries of a sealed generation sorted by key, keeping the last
+/// entry of each key only. Removals are kept too, so that they go on hiding
+/// the values of older generations.
+///
+/// Layout: blocks of up to `INDEX_BLOCK_ENTRIES` entries, then a footer
+/// listing the first key of every block, each as `[crc32: u32 LE][payload]`,
+/// and finally the length of the footer as a `u64 LE`. Only the footer, a
+/// sparse index of the table, is held in memory, so that a lookup reads a
+/// single block.
+#[derive(Debug)]
+pub struct SortedTable {
+    pub generation: u64,
+    file: Mutex<File>,
+    fences: Vec<Fence>,
+    /// The number of bytes of the generation made stale within it.
+    pub stale_bytes: usize,
+}
+
+impl SortedTable {
+    /// Opens the sorted table of `generation`, returning `None` if it is
+    /// missing, damaged or does not describe a log file of `log_length` bytes.
+    pub async fn open(dir: &Path, generation: u64, log_length: usize) -> Result<Option<Self>> {
+        let index_path = index_path(dir, generation);
+        if !index_path.exists() {
+            return Ok(None);
+        }
+
+        let mut file = File::open(&index_path).await?;
+        let file_length = file.metadata().await?.len();
+        if file_length < FOOTER_LENGTH_BYTES as u64 {
+            return Ok(None);
+        }
+        let mut length_bytes = [0u8; FOOTER_LENGTH_BYTES];
+        file.seek(SeekFrom::Start(file_length - FOOTER_LENGTH_BYTES as u64))
+            .await?;
+        file.read_exact(&mut length_bytes).await?;
+        let footer_length = u64::from_le_bytes(length_bytes);
+        if footer_length > file_length - FOOTER_LENGTH_BYTES as u64 {
+            return Ok(None);
+        }
+
+        let footer_offset = file_length - FOOTER_LENGTH_BYTES as u64 - footer_length;
+        let footer = match read_checked(&mut file, footer_offset, footer_length).await? {
+            Some(payload) => bincode::deserialize::<Footer>(&payload).ok(),
+            None => None,
+        };
+        match footer {
+            Some(footer) if footer.log_length == log_length => Ok(Some(SortedTable {
+                generation,
+                file: Mutex::new(file),
+                fences: footer.fences,
+                stale_bytes: footer.stale_bytes,
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    /// Writes the sorted table of `generation` out of its hint entries, in log
+    /// order.
+    pub async fn build(
+        dir: &Path,
+        generation: u64,
+        entries: Vec<HintEntry>,
+        log_length: usize,
+    ) -> Result<Self> {
+        let mut stale_bytes = 0;
+        let mut latest = BTreeMap::new();
+        for entry in entries {
+            if let HintEntry::Remove { length, .. } = entry {
+                stale_bytes += length;
+            }
+            match latest.insert(entry.key().to_vec(), entry) {
+                Some(HintEntry::Remove { .. }) | None => {}
+                Some(old_entry) => stale_bytes += old_entry.length(),
+            }
+        }
+
+        let mut writer = TableWriter::create(dir, generation).await?;
+        for entry in latest.into_values() {
+            writer.push(entry).await?;
+        }
+        writer.finish(log_length, stale_bytes).await
+    }
+
+    /// Looks up the entry of `key`.
+    pub async fn get(&self, key: &[u8]) -> Result<Option<HintEntry>> {
+        let block = match self
+            .fences
+            .partition_point(|fence| fence.first_key.as_slice() <= key)
+        {
+            0 => return Ok(None),
+            following => following - 1,
+        };
+
+        let mut entries = self.read_block(block).await?;
+        Ok(entries
+            .binary_search_by(|entry| entry.key().cmp(key))
+            .ok()
+            .map(|found| entries.swap_remove(found)))
+    }
+
+    /// Finds the first key within `range` in `order`, including removed ones.
+    pub async fn first_key(&self, range: &BytesRange, order: ScanOrder) -> Result<Option<Vec<u8>>> {
+        match order {
+            ScanOrder::Forward => {
+                let first_block = match &range.0 {
+                    Bound::Included(start) | Bound::Excluded(start) => self
+                        .fences
+                        .partition_point(|fence| fence.first_key <= *start)
+                        .saturating_sub(1),
+                    Bound::Unbounded => 0,
+                };
+                for block in first_block..self.fences.len() {
+                    let entries = self.read_block(block).await?;
+                    if let Some(entry) = entries
+                        .iter()
+                        .find(|entry| after_start(&range.0, entry.key()))
+                    {
+                        let key = entry.key();
+                        return Ok(Some(key.to_vec()).filter(|_| before_end(&range.1, key)));
+                    }
+                }
+            }
+            ScanOrder::Reverse => {
+                let end_block = match &range.1 {
+                    Bound::Included(end) => {
+                        self.fences.partition_point(|fence| fence.first_key <= *end)
+                    }
+                    Bound::Excluded(end) => {
+                        self.fences.partition_point(|fence| fence.first_key < *end)
+                    }
+                    Bound::Unbounded => self.fences.len(),
+                };
+                for block in (0..end_block).rev() {
+                    let entries = self.read_block(block).await?;
+                    if let Some(entry) = entries
+                        .iter()
+                        .rev()
+                        .find(|entry| before_end(&range.1, entry.key()))
+                    {
+                        let key = entry.key();
+                        return Ok(Some(key.to_vec()).filter(|_| after_start(&range.0, key)));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    async fn read_block(&self, block: usize) -> Result<Vec<HintEntry>> {
+        let fence = &self.fences[block];
+        let corrupted = || KvsError::CorruptedIndex {
+            generation: self.generation,
+            offset: fence.offset as usize,
+        };
+
+        let mut file = self.file.lock().await;
+        let payload = read_checked(&mut file, fence.offset, fence.length)
+            .await?
+            .ok_or_else(corrupted)?;
+        bincode::deserialize(&payload).map_err(|_| corrupted())
+    }
+}
+
+/// Writes the sorted table of a generation, entry by entry in key order.
+pub struct TableWriter {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    generation: u64,
+    block: Vec<HintEntry>,
+    fences: Vec<Fence>,
+    position: u64,
+}
+
+impl TableWriter {
+    pub async fn create(dir: &Path, generation: u64) -> Result<Self> {
+        let path = index_path(dir, generation);
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .await?;
+        Ok(TableWriter {
+            writer: BufWriter::new(file),
+            path,
+            generation,
+            block: Vec::with_capacity(constants::INDEX_BLOCK_ENTRIES),
+            fences: Vec::new(),
+            position: 0,
+        })
+    }
+
+    /// Appends `entry`, whose key must come after that of the previous one.
+    pub async fn push(&mut self, entry: HintEntry) -> Result<()> {
+        self.block.push(entry);
+        if self.block.len() >= constants::INDEX_BLOCK_ENTRIES {
+            self.write_block().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes the footer and syncs the table, which describes a log file of
+    /// `log_length` bytes.
+    pub async fn finish(mut self, log_length: usize, stale_bytes: usize) -> Result<SortedTable> {
+        self.write_block().await?;
+        let footer = Footer {
+            fences: self.fences,
+            log_length,
+            stale_bytes,
+        };
+        let footer_length = write_checked(&mut self.writer, &bincode::serialize(&footer)?).await?;
+        self.writer.write_all(&footer_length.to_le_bytes()).await?;
+        self.writer.flush().await?;
+        self.writer.get_ref().sync_data().await?;
+
+        Ok(SortedTable {
+            generation: self.generation,
+            file: Mutex::new(File::open(&self.path).await?),
+            fences: footer.fences,
+            stale_bytes,
+        })
+    }
+
+    async fn write_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        let first_key = self.block[0].key().to_vec();
+        let length = write_checked(&mut self.writer, &bincode::serialize(&self.block)?).await?;
+        self.fences.push(Fence {
+            first_key,
+            offset: self.position,
+            length,
+        });
+        self.position += length;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+/// Visits the entries of a sorted table in key order, a block at a time.
+pub struct TableCursor {
+    table: Arc<SortedTable>,
+    next_block: usize,
+    entries: VecDeque<HintEntry>,
+}
+
+impl TableCursor {
+    pub fn new(table: Arc<SortedTable>) -> Self {
+        TableCursor {
+            table,
+            next_block: 0,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub async fn next(&mut self) -> Result<Option<HintEntry>> {
+        while self.entries.is_empty() {
+            if self.next_block == self.table.fences.len() {
+                return Ok(None);
+            }
+            self.entries = self.table.read_block(self.next_block).await?.into();
+            self.next_block += 1;
+        }
+        Ok(self.entries.pop_front())
+    }
+}
+
+/// Writes `payload` as `[crc32: u32 LE][payload]`, returning the number of
+/// bytes written.
+async fn write_checked(writer: &mut BufWriter<File>, payload: &[u8]) -> Result<u64> {
+    writer
+        .write_all(&crc32fast::hash(payload).to_le_bytes())
+        .await?;
+    writer.write_all(payload).await?;
+    Ok((constants::CHECKSUM_BYTES + payload.len()) as u64)
+}
+
+/// Reads what `write_checked` wrote at `offset`, returning `None` if it does
+/// not match its checksum.
+async fn read_checked(file: &mut File, offset: u64, length: u64) -> Result<Option<Vec<u8>>> {
+    if length < constants::CHECKSUM_BYTES as u64 {
+        return Ok(None);
+    }
+
+    let mut content = vec![0; length as usize];
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.read_exact(&mut content).await?;
+    let (checksum_bytes, payload) = content.split_at(constants::CHECKSUM_BYTES);
+    if u32::from_le_bytes(checksum_bytes.try_into()?) != crc32fast::hash(payload) {
+        return Ok(None);
+    }
+    Ok(Some(payload.to_vec()))
+}
+
+fn after_start(start: &Bound<Vec<u8>>, key: &[u8]) -> bool {
+    match start {
+        Bound::Included(start) => key >= start.as_slice(),
+        Bound::Excluded(start) => key > start.as_slice(),
+        Bound::Unbounded => true,
+    }
+}
+
+fn before_end(end: &Bound<Vec<u8>>, key: &[u8]) -> bool {
+    match end {
+        Bound::Included(end) => key <= end.as_slice(),
+        Bound::Excluded(end) => key < end.as_slice(),
+        Bound::Unbounded => true,
+    }
+}
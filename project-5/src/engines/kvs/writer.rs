@@ -5,48 +5,282 @@ use async_std::{
     io::{BufWriter, SeekFrom},
     prelude::*,
 };
+use log::error;
 
-use super::{command::Command, constants, log_common::*};
-use crate::Result;
+use super::{
+    blob::BlobWriter,
+    command::Command,
+    compression::Compressor,
+    format::FILE_HEADER_BYTES,
+    hint::{write_hint_file, HintEntry},
+    log_common::*,
+    record::{encode_batch_record, encode_record, EncodedRecord},
+};
+use crate::{KvsError, Result};
 
 #[derive(Debug)]
 pub struct KvsWriter {
     pub writer: BufWriter<File>,
     path: Arc<PathBuf>,
+    buffer_size: usize,
+    pub compressor: Compressor,
+    // Where values of at least `blob_threshold` bytes go instead of the log,
+    // if anywhere
+    pub blob_writer: Option<BlobWriter>,
+    pub blob_threshold: usize,
     pub current_generation: u64,
+    size: u64,
+    dirty: bool,
+    // The hints of every record in the current generation, or `None` if the
+    // generation already held records this writer did not write
+    hint_entries: Option<Vec<HintEntry>>,
+    write_hints: bool,
+    // Whether a failed write could not be rolled back, leaving partial
+    // records at the end of the current generation
+    poisoned: bool,
 }
 
 impl KvsWriter {
-    pub async fn open(path: Arc<PathBuf>, generation: u64) -> Result<Self> {
+    pub async fn open(
+        path: Arc<PathBuf>,
+        generation: u64,
+        buffer_size: usize,
+        compressor: Compressor,
+    ) -> Result<Self> {
         let mut file = new_log_file(&*path, generation).await?;
-        file.seek(SeekFrom::Current(0)).await?;
+        let size = file.seek(SeekFrom::End(0)).await?;
         Ok(KvsWriter {
-            writer: BufWriter::new(file),
+            writer: BufWriter::with_capacity(buffer_size, file),
             path: Arc::clone(&path),
+            buffer_size,
+            compressor,
+            blob_writer: None,
+            blob_threshold: 0,
             current_generation: generation,
+            size,
+            dirty: false,
+            hint_entries: if holds_no_record(size) {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            write_hints: true,
+            poisoned: false,
         })
     }
 
-    pub async fn write_command(&mut self, command: &Command) -> Result<(u64, u64)> {
-        let serialized = bincode::serialize(command)?;
-        let serialized_bytes = serialized.len();
-        let current_position = self.writer.seek(SeekFrom::End(0)).await?;
-        self.writer
-            .write_all(&serialized_bytes.to_le_bytes())
-            .await?;
-        self.writer.write_all(&serialized).await?;
+    /// Stops writing hint files, for a store whose sorted tables take their place.
+    pub fn disable_hints(&mut self) {
+        self.write_hints = false;
+        self.hint_entries = None;
+    }
+
+    /// Appends `command` as a record of its own, returning its hint.
+    pub async fn write_command(&mut self, command: Command) -> Result<HintEntry> {
+        let record = encode_record(command, &self.compressor)?;
+        let offset = self
+            .write_records(std::slice::from_ref(&record), false)
+            .await?[0];
+        let (command, _, length) = &record.commands[0];
+        Ok(HintEntry::new(command, offset as usize, *length))
+    }
+
+    /// Serializes `command` into a record of its own, moving its value to the
+    /// blob file first if it is large enough.
+    pub async fn encode_record(&mut self, command: Command) -> Result<EncodedRecord> {
+        let command = self.separate_value(command).await?;
+        encode_record(command, &self.compressor)
+    }
+
+    /// Serializes `commands` into a single batch record, moving their values
+    /// to the blob file first if they are large enough.
+    pub async fn encode_batch_record(&mut self, commands: Vec<Command>) -> Result<EncodedRecord> {
+        let mut separated = Vec::with_capacity(commands.len());
+        for command in commands {
+            separated.push(self.separate_value(command).await?);
+        }
+        encode_batch_record(separated, &self.compressor)
+    }
+
+    /// Appends the value of `command` to the blob file and returns a
+    /// `Command::SetBlob` pointing to it in place of `command`, if the value
+    /// is large enough.
+    async fn separate_value(&mut self, command: Command) -> Result<Command> {
+        let blob_writer = match &mut self.blob_writer {
+            Some(blob_writer) => blob_writer,
+            None => return Ok(command),
+        };
+        let expires_at = command.expires_at();
+        let (key, value) = match command {
+            Command::Set { key, value } | Command::SetExpiring { key, value, .. }
+                if value.len() >= self.blob_threshold =>
+            {
+                (key, value)
+            }
+            command => return Ok(command),
+        };
+
+        let record = encode_record(
+            Command::Set {
+                key: key.clone(),
+                value,
+            },
+            &self.compressor,
+        )?;
+        let blob = blob_writer.append(&record).await?;
+        Ok(Command::SetBlob {
+            key,
+            blob,
+            expires_at,
+        })
+    }
+
+    /// Appends encoded records in a single write, returning the offset of each,
+    /// and syncs them to disk if `sync` is set.
+    ///
+    /// A failed write or sync is rolled back, so that the records written
+    /// next do not follow partial ones, which a reopened store would take
+    /// for a torn tail and drop along with them. If the rollback fails too,
+    /// the writer is poisoned and every later write fails with
+    /// `KvsError::Poisoned`.
+    pub async fn write_records(
+        &mut self,
+        records: &[EncodedRecord],
+        sync: bool,
+    ) -> Result<Vec<u64>> {
+        if self.poisoned {
+            return Err(KvsError::Poisoned);
+        }
+        let start = self.writer.seek(SeekFrom::End(0)).await?;
+        let hints = self.hint_entries.as_ref().map(Vec::len);
+        let mut position = start;
+        let mut offsets = Vec::with_capacity(records.len());
+        let mut content = Vec::new();
+        for record in records {
+            offsets.push(position);
+            position += record.bytes.len() as u64;
+            content.extend_from_slice(&record.bytes);
+        }
+
+        if let Err(e) = self.write_content(&content).await {
+            self.roll_back(start).await;
+            return Err(e);
+        }
+        self.size = position;
+        self.dirty = true;
+
+        if let Some(hint_entries) = &mut self.hint_entries {
+            for (record, &offset) in records.iter().zip(&offsets) {
+                for (command, relative_offset, length) in &record.commands {
+                    let offset = offset as usize + relative_offset;
+                    hint_entries.push(HintEntry::new(command, offset, *length));
+                }
+            }
+        }
+
+        if sync {
+            if let Err(e) = self.sync().await {
+                self.roll_back(start).await;
+                if let (Some(hint_entries), Some(hints)) = (&mut self.hint_entries, hints) {
+                    hint_entries.truncate(hints);
+                }
+                return Err(e);
+            }
+        }
+        Ok(offsets)
+    }
+
+    async fn write_content(&mut self, content: &[u8]) -> Result<()> {
+        self.writer.write_all(content).await?;
         self.writer.flush().await?;
-        let data_block_size = constants::USIZE_BYTES + serialized_bytes;
+        Ok(())
+    }
+
+    /// Cuts the current generation back to `position`, dropping whatever a
+    /// failed write left in it or in the buffer, or poisons the writer if it
+    /// cannot.
+    async fn roll_back(&mut self, position: u64) {
+        let truncated: Result<File> = async {
+            let file = new_log_file(&self.path, self.current_generation).await?;
+            file.set_len(position).await?;
+            file.sync_data().await?;
+            Ok(file)
+        }
+        .await;
+        match truncated {
+            Ok(file) => {
+                self.writer = BufWriter::with_capacity(self.buffer_size, file);
+                self.size = position;
+            }
+            Err(e) => {
+                error!(
+                    "Generation {} cannot be cut back to offset {} after a failed write: {}",
+                    self.current_generation, position, e
+                );
+                self.poisoned = true;
+            }
+        }
+    }
+
+    /// Forces the current generation to disk, unless nothing was written
+    /// since the last sync.
+    ///
+    /// The blob file goes first, so that no record outlives the value it
+    /// points to.
+    pub async fn sync(&mut self) -> Result<()> {
+        if self.poisoned {
+            return Err(KvsError::Poisoned);
+        }
+        if let Some(blob_writer) = &mut self.blob_writer {
+            blob_writer.sync().await?;
+        }
+        if self.dirty {
+            self.writer.flush().await?;
+            self.writer.get_ref().sync_data().await?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// The number of bytes in the current generation.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
 
-        Ok((current_position, data_block_size as u64))
+    /// Syncs the current generation and writes its hint file, so that it can
+    /// be loaded without replaying it once no longer written to.
+    pub async fn seal(&mut self) -> Result<()> {
+        self.sync().await?;
+        if let Some(hint_entries) = &self.hint_entries {
+            write_hint_file(
+                &self.path,
+                self.current_generation,
+                self.size as usize,
+                hint_entries,
+            )
+            .await?;
+        }
+        Ok(())
     }
 
     pub async fn refresh(&mut self, generation: u64) -> Result<()> {
+        // The sealed generation must be as durable as the writes it acknowledged
+        self.seal().await?;
         let mut file = new_log_file(&self.path, generation).await?;
-        file.seek(SeekFrom::Current(0)).await?;
-        self.writer = BufWriter::new(file);
+        self.size = file.seek(SeekFrom::End(0)).await?;
+        self.writer = BufWriter::with_capacity(self.buffer_size, file);
         self.current_generation = generation;
+        self.hint_entries = if self.write_hints && holds_no_record(self.size) {
+            Some(Vec::new())
+        } else {
+            None
+        };
 
         Ok(())
     }
 }
+
+/// Whether a log file of `size` bytes is made of its header alone.
+fn holds_no_record(size: u64) -> bool {
+    size == FILE_HEADER_BYTES as u64
+}
@@ -1,18 +1,260 @@
+use std::{
+    fs,
+    ops::Bound,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_std::stream::{Stream, StreamExt};
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use super::error::{KvsError, Result};
+
+/// When an acknowledged write is forced to stable storage.
+///
+/// A write is acknowledged, i.e. `set` or `remove` returns, once the chosen
+/// durability point is reached.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    /// Writes are acknowledged once handed over to the operating system, which
+    /// flushes them to disk at its own pace.
+    Never,
+    /// Writes are acknowledged once handed over to the operating system, and
+    /// synced to disk in the background at the given interval. At most one
+    /// interval worth of acknowledged writes may be lost on power failure.
+    Periodic(Duration),
+    /// Every write is synced to disk before it is acknowledged.
+    Always,
+}
+
+/// The direction in which a scan walks the keys.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ScanOrder {
+    /// From the smallest key to the largest.
+    Forward,
+    /// From the largest key to the smallest.
+    Reverse,
+}
+
+/// The bounds of the keys a scan visits.
+pub type KeyRange = (Bound<String>, Bound<String>);
+
+/// The bounds of the keys a byte-oriented scan visits.
+pub type BytesRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// A report on a store, as returned by [`KvsEngine::stats`], depending on its
+/// engine.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "engine", rename_all = "lowercase")]
+pub enum EngineStats {
+    Kvs(KvStoreStats),
+    Sled(SledStats),
+}
+
+/// The key/value pairs visited by a scan, in scan order.
+pub type KvsScan = Pin<Box<dyn Stream<Item = Result<(String, String)>> + Send>>;
 
-use super::error::Result;
+/// The key/value pairs visited by a byte-oriented scan, in scan order.
+pub type KvsBytesScan = Pin<Box<dyn Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>>;
 
+/// A key/value store.
+///
+/// Keys and values are arbitrary bytes, and keys are ordered bytewise. The
+/// methods taking and returning `String`s wrap their byte-oriented
+/// counterparts, and fail with `KvsError::Utf8` on bytes that are not valid
+/// UTF-8.
 #[async_trait]
 pub trait KvsEngine: Clone + Send + 'static {
-    async fn set(&self, key: String, value: String) -> Result<()>;
+    async fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Sets `key` to `value` for `ttl`, after which the key is gone as if
+    /// removed.
+    async fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    async fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Sets `key` to `new`, or removes it if `new` is `None`, provided its
+    /// current value is `expected`, `None` standing for a missing key.
+    ///
+    /// The comparison and the write happen atomically. On mismatch, nothing is
+    /// written and `KvsError::CompareAndSwapMismatch` reports the current value.
+    async fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()>;
+
+    /// Sets `key` provided it does not exist yet, failing with
+    /// `KvsError::CompareAndSwapMismatch` otherwise.
+    async fn set_bytes_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.compare_and_swap_bytes(key, None, Some(value)).await
+    }
+
+    /// Sets `key` provided it already exists, failing with
+    /// `KvsError::KeyNotFound` otherwise.
+    async fn set_bytes_if_present(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Applies every write of `batch` atomically.
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Visits the keys within `range` in `order`, stopping after `limit` pairs
+    /// if given.
+    ///
+    /// The scan is not a snapshot: a key written while the scan is under way
+    /// may or may not be visited, with either its old or its new value.
+    async fn scan_bytes(
+        &self,
+        range: BytesRange,
+        limit: Option<usize>,
+        order: ScanOrder,
+    ) -> Result<KvsBytesScan>;
+
+    /// Visits the keys starting with `prefix` in `order`.
+    async fn scan_prefix_bytes(&self, prefix: Vec<u8>, order: ScanOrder) -> Result<KvsBytesScan> {
+        let end = prefix_end(&prefix);
+        self.scan_bytes((Bound::Included(prefix), end), None, order)
+            .await
+    }
+
+    /// Writes a consistent copy of the store to the directory `dest`, which
+    /// can be opened like any other store once this returns. `dest` is created
+    /// if need be, and must be empty.
+    ///
+    /// Writes may go on while the copy is made, but none acknowledged after
+    /// the copy started is part of it.
+    async fn checkpoint(&self, dest: PathBuf) -> Result<()>;
+
+    /// Reports on the contents and the disk usage of the store.
+    async fn stats(&self) -> Result<EngineStats>;
 
-    async fn get(&self, key: String) -> Result<Option<String>>;
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes()).await
+    }
+
+    async fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+            .await
+    }
+
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        utf8_value(self.get_bytes(key.into_bytes()).await?)
+    }
+
+    async fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes()).await
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        let expected = expected.map(String::into_bytes);
+        let new = new.map(String::into_bytes);
+        self.compare_and_swap_bytes(key.into_bytes(), expected, new)
+            .await
+    }
+
+    async fn set_if_absent(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes_if_absent(key.into_bytes(), value.into_bytes())
+            .await
+    }
+
+    async fn set_if_present(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes_if_present(key.into_bytes(), value.into_bytes())
+            .await
+    }
+
+    async fn scan(
+        &self,
+        range: KeyRange,
+        limit: Option<usize>,
+        order: ScanOrder,
+    ) -> Result<KvsScan> {
+        let scan = self
+            .scan_bytes(range_into_bytes(range), limit, order)
+            .await?;
+        Ok(utf8_scan(scan))
+    }
+
+    async fn scan_prefix(&self, prefix: String, order: ScanOrder) -> Result<KvsScan> {
+        let scan = self.scan_prefix_bytes(prefix.into_bytes(), order).await?;
+        Ok(utf8_scan(scan))
+    }
+}
+
+/// The current time in milliseconds since the Unix epoch, as used for expiry
+/// timestamps.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// The expiry timestamp of a value set now for `ttl`.
+fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Creates the directory a checkpoint goes to, unless it exists already, in
+/// which case it must be empty.
+fn prepare_checkpoint_dir(dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(KvsError::CheckpointDirNotEmpty(dest.to_path_buf()));
+    }
+    Ok(())
+}
+
+/// The smallest bound above every key starting with `prefix`.
+fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
+}
+
+/// The bytes of a string range. UTF-8 preserves the order of strings, so the
+/// range spans the same keys.
+pub(crate) fn range_into_bytes((start, end): KeyRange) -> BytesRange {
+    let into_bytes = |bound| match bound {
+        Bound::Included(key) => Bound::Included(String::into_bytes(key)),
+        Bound::Excluded(key) => Bound::Excluded(String::into_bytes(key)),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (into_bytes(start), into_bytes(end))
+}
+
+fn utf8_value(value: Option<Vec<u8>>) -> Result<Option<String>> {
+    Ok(value.map(String::from_utf8).transpose()?)
+}
 
-    async fn remove(&self, key: String) -> Result<()>;
+fn utf8_scan(scan: KvsBytesScan) -> KvsScan {
+    Box::pin(scan.map(|pair| {
+        let (key, value) = pair?;
+        Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+    }))
 }
 
+mod batch;
 mod kvs;
 mod sled;
+mod versions;
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+pub use self::batch::{BatchOperation, WriteBatch};
+pub use self::kvs::{
+    CacheStats, CompactionHandle, CompactionMode, Compression, DamagedFile, FileCheck,
+    GenerationStats, GenerationUsage, IndexMode, KvStore, KvStoreBuilder, KvStoreOptions,
+    KvStoreSnapshot, KvStoreStats, KvsAdmin, LogEntry, LogOperation, StoreUsage,
+};
+pub use self::sled::{SledKvsEngine, SledSnapshot, SledStats};
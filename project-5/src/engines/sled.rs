@@ -1,40 +1,569 @@
+use std::{
+    convert::TryInto,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_std::{stream, sync::RwLock, task};
 use async_trait::async_trait;
-use sled::{Db, Tree};
+use log::error;
+use serde::{Deserialize, Serialize};
+use sled::{
+    ConflictableTransactionResult, Db, IVec, TransactionError, Transactional, TransactionalTree,
+    Tree,
+};
 
-use super::KvsEngine;
+use super::{
+    expires_at, now_millis, prefix_end, prepare_checkpoint_dir, range_into_bytes, utf8_scan,
+    utf8_value, versions::Versions, BatchOperation, BytesRange, EngineStats, KeyRange,
+    KvsBytesScan, KvsEngine, KvsScan, ScanOrder, SyncPolicy, WriteBatch,
+};
 use crate::{KvsError, Result};
 
+/// The tree holding the expiry timestamp of every key set with a TTL.
+const EXPIRIES_TREE: &str = "kvs_expiries";
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The value of a key along with its expiry, if it had one.
+type SledVersion = Option<(Vec<u8>, Option<u64>)>;
+
 #[derive(Clone)]
-pub struct SledKvsEngine(Db);
+pub struct SledKvsEngine {
+    db: Db,
+    expiries: Tree,
+    sync_policy: SyncPolicy,
+    // Writes hold it shared, so that a snapshot holding it exclusively sees
+    // no write half done
+    snapshot_gate: Arc<RwLock<()>>,
+    // The values replaced while snapshots are alive
+    versions: Arc<Versions<SledVersion>>,
+    // Stops the expiry sweep once the last clone is dropped
+    _sweep_guard: Arc<()>,
+}
 
 impl SledKvsEngine {
-    pub fn new(db: Db) -> Self {
-        SledKvsEngine(db)
+    /// Wraps `db`, flushing it on every write.
+    pub fn new(db: Db) -> Result<Self> {
+        SledKvsEngine::with_sync_policy(db, SyncPolicy::Always)
+    }
+
+    /// Wraps `db`, flushing it on every write only with `SyncPolicy::Always`.
+    ///
+    /// Periodic flushing is up to the way `db` was configured, see
+    /// `sled::Config::flush_every_ms`.
+    pub fn with_sync_policy(db: Db, sync_policy: SyncPolicy) -> Result<Self> {
+        let expiries = db.open_tree(EXPIRIES_TREE)?;
+        let sweep_guard = Arc::new(());
+        spawn_expiry_sweep(&db, &expiries, &sweep_guard);
+        Ok(SledKvsEngine {
+            db,
+            expiries,
+            sync_policy,
+            snapshot_gate: Arc::new(RwLock::new(())),
+            versions: Arc::new(Versions::default()),
+            _sweep_guard: sweep_guard,
+        })
+    }
+
+    /// Opens a database at `path` configured for `sync_policy`.
+    pub fn open(path: impl AsRef<Path>, sync_policy: SyncPolicy) -> Result<Self> {
+        let flush_every_ms = match sync_policy {
+            SyncPolicy::Periodic(interval) => Some(interval.as_millis() as u64),
+            SyncPolicy::Never | SyncPolicy::Always => None,
+        };
+        let db = sled::Config::new()
+            .path(path)
+            .flush_every_ms(flush_every_ms)
+            .open()?;
+        SledKvsEngine::with_sync_policy(db, sync_policy)
+    }
+
+    fn sync(&self) -> Result<()> {
+        if self.sync_policy == SyncPolicy::Always {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Runs `f` over the tree of the pairs and that of their expiries as a
+    /// single transaction, so that no crash or concurrent write ever leaves
+    /// a value with the expiry of another.
+    ///
+    /// `f` may be run more than once, should the transaction conflict with
+    /// another.
+    fn transaction<A>(
+        &self,
+        f: impl Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<A>,
+    ) -> Result<A> {
+        let tree: &Tree = &self.db;
+        (tree, &self.expiries)
+            .transaction(|(tree, expiries)| f(tree, expiries))
+            .map_err(|e| match e {
+                TransactionError::Storage(e) => KvsError::Sled(e),
+                // `f` reports its outcome through its result instead
+                TransactionError::Abort(()) => unreachable!(),
+            })
+    }
+
+    fn purge_if_expired(&self, key: &[u8]) -> Result<()> {
+        purge_if_expired(&self.db, &self.expiries, key)
+    }
+
+    /// Keeps the versions of `keys` as they are for the live snapshots, ahead
+    /// of a write to them.
+    ///
+    /// To be called with `snapshot_gate` held shared.
+    fn record_versions<'a>(&self, keys: impl IntoIterator<Item = &'a [u8]>) -> Result<()> {
+        if !self.versions.has_snapshots() {
+            return Ok(());
+        }
+        for key in keys {
+            let version = read_version(&self.db, &self.expiries, key)?;
+            self.versions.record(key, version);
+        }
+        Ok(())
+    }
+
+    /// Takes a consistent, read-only snapshot of the database.
+    ///
+    /// sled offers no snapshots of its own, so the snapshot reads the live
+    /// database, save for the keys written since it was taken: their earlier
+    /// values are kept in memory until no snapshot reads them any longer.
+    /// Taking one is cheap, but holding it costs memory in proportion to the
+    /// writes made in the meantime.
+    pub async fn snapshot(&self) -> Result<SledSnapshot> {
+        let _gate = self.snapshot_gate.write().await;
+        let epoch = self.versions.open_snapshot();
+        let epoch = Arc::new(SnapshotEpoch {
+            epoch,
+            versions: Arc::clone(&self.versions),
+        });
+
+        Ok(SledSnapshot {
+            sequence: self.db.generate_id()?,
+            tree: (*self.db).clone(),
+            expiries: self.expiries.clone(),
+            epoch,
+        })
+    }
+}
+
+/// The contents and the disk usage of a `SledKvsEngine`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SledStats {
+    /// The number of keys, counting those expired but not swept yet.
+    pub keys: usize,
+    /// As reported by `sled::Db::size_on_disk`.
+    pub size_on_disk: u64,
+}
+
+/// A read-only view of a `SledKvsEngine` as of the moment it was taken.
+///
+/// Keys expire in the snapshot as they do in the engine.
+#[derive(Clone)]
+pub struct SledSnapshot {
+    sequence: u64,
+    tree: Tree,
+    expiries: Tree,
+    epoch: Arc<SnapshotEpoch>,
+}
+
+/// The epoch a snapshot reads as of, closed once its last clone is dropped.
+struct SnapshotEpoch {
+    epoch: u64,
+    versions: Arc<Versions<SledVersion>>,
+}
+
+impl Drop for SnapshotEpoch {
+    fn drop(&mut self) {
+        self.versions.close_snapshot(self.epoch);
+    }
+}
+
+impl SledSnapshot {
+    /// An id drawn from `sled::Db::generate_id` when the snapshot was taken,
+    /// so that later snapshots of the same database have greater ones.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.lookup(&key)
+    }
+
+    fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // The live version goes first: a write changing it after the snapshot
+        // keeps the version as of the snapshot by the time the versions are read
+        let live = read_version(&self.tree, &self.expiries, key)?;
+        let version = self
+            .epoch
+            .versions
+            .get(key, self.epoch.epoch)
+            .unwrap_or(live);
+        Ok(version.and_then(|(value, expires_at)| match expires_at {
+            Some(expires_at) if expires_at <= now_millis() => None,
+            _ => Some(value),
+        }))
+    }
+
+    /// Finds the first key within `range` in `order` that may have existed as
+    /// of the snapshot.
+    fn first_key(&self, range: &BytesRange, order: ScanOrder) -> Result<Option<Vec<u8>>> {
+        let mut keys = self.tree.range::<Vec<u8>, _>(range.clone()).keys();
+        let live = match order {
+            ScanOrder::Forward => keys.next(),
+            ScanOrder::Reverse => keys.next_back(),
+        };
+        let live = live.transpose()?.map(|key| key.to_vec());
+        let replaced = self.epoch.versions.first_key(range, order);
+        Ok(match order {
+            ScanOrder::Forward => live.into_iter().chain(replaced).min(),
+            ScanOrder::Reverse => live.into_iter().chain(replaced).max(),
+        })
+    }
+
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        utf8_value(self.get_bytes(key.into_bytes()).await?)
+    }
+
+    /// Visits the keys within `range` in `order` as of the snapshot, stopping
+    /// after `limit` pairs if given.
+    pub async fn scan_bytes(
+        &self,
+        range: BytesRange,
+        limit: Option<usize>,
+        order: ScanOrder,
+    ) -> Result<KvsBytesScan> {
+        // As with the engine, the range is narrowed past each visited key
+        let snapshot = self.clone();
+        let mut range = range;
+        let mut remaining = limit.unwrap_or(usize::MAX);
+        Ok(Box::pin(stream::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            loop {
+                let pair = snapshot.first_key(&range, order).and_then(|key| match key {
+                    Some(key) => Ok(Some((snapshot.lookup(&key)?, key))),
+                    None => Ok(None),
+                });
+                match pair {
+                    Ok(Some((value, key))) => {
+                        match order {
+                            ScanOrder::Forward => range.0 = Bound::Excluded(key.clone()),
+                            ScanOrder::Reverse => range.1 = Bound::Excluded(key.clone()),
+                        }
+                        if let Some(value) = value {
+                            remaining -= 1;
+                            return Some(Ok((key, value)));
+                        }
+                    }
+                    Ok(None) => return None,
+                    Err(e) => {
+                        remaining = 0;
+                        return Some(Err(e));
+                    }
+                }
+            }
+        })))
+    }
+
+    /// Visits the keys starting with `prefix` in `order` as of the snapshot.
+    pub async fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+        order: ScanOrder,
+    ) -> Result<KvsBytesScan> {
+        let end = prefix_end(&prefix);
+        self.scan_bytes((Bound::Included(prefix), end), None, order)
+            .await
+    }
+
+    pub async fn scan(
+        &self,
+        range: KeyRange,
+        limit: Option<usize>,
+        order: ScanOrder,
+    ) -> Result<KvsScan> {
+        let scan = self
+            .scan_bytes(range_into_bytes(range), limit, order)
+            .await?;
+        Ok(utf8_scan(scan))
+    }
+
+    pub async fn scan_prefix(&self, prefix: String, order: ScanOrder) -> Result<KvsScan> {
+        let scan = self.scan_prefix_bytes(prefix.into_bytes(), order).await?;
+        Ok(utf8_scan(scan))
     }
 }
 
 #[async_trait]
 impl KvsEngine for SledKvsEngine {
-    async fn set(&self, key: String, value: String) -> Result<()> {
-        let tree: &Tree = &self.0;
-        tree.insert(key, value.into_bytes()).map(|_| ())?;
-        tree.flush()?;
-        Ok(())
+    async fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let _gate = self.snapshot_gate.read().await;
+        self.record_versions(Some(key.as_slice()))?;
+        self.transaction(|tree, expiries| {
+            expiries.remove(key.as_slice())?;
+            tree.insert(key.as_slice(), value.as_slice())?;
+            Ok(())
+        })?;
+        self.sync()
+    }
+
+    async fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let _gate = self.snapshot_gate.read().await;
+        self.record_versions(Some(key.as_slice()))?;
+        let expires_at = expires_at(ttl).to_be_bytes();
+        self.transaction(|tree, expiries| {
+            expiries.insert(key.as_slice(), &expires_at[..])?;
+            tree.insert(key.as_slice(), value.as_slice())?;
+            Ok(())
+        })?;
+        self.sync()
+    }
+
+    async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if is_expired(&self.expiries, &key)? {
+            return Ok(None);
+        }
+        let tree: &Tree = &self.db;
+        Ok(tree.get(key)?.map(|i_vec| i_vec.to_vec()))
     }
 
-    async fn get(&self, key: String) -> Result<Option<String>> {
-        let tree: &Tree = &self.0;
-        Ok(tree
-            .get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+    async fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let _gate = self.snapshot_gate.read().await;
+        self.purge_if_expired(&key)?;
+        self.record_versions(Some(key.as_slice()))?;
+        let removed = self.transaction(|tree, expiries| {
+            expiries.remove(key.as_slice())?;
+            Ok(tree.remove(key.as_slice())?.is_some())
+        })?;
+        if !removed {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.sync()
     }
 
-    async fn remove(&self, key: String) -> Result<()> {
-        let tree: &Tree = &self.0;
-        tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
-        tree.flush()?;
+    async fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let _gate = self.snapshot_gate.read().await;
+        self.purge_if_expired(&key)?;
+        self.record_versions(Some(key.as_slice()))?;
+        let swapped = self.transaction(|tree, expiries| {
+            let current = tree.get(key.as_slice())?;
+            if current.as_deref() != expected.as_deref() {
+                return Ok(Err(current.map(|i_vec| i_vec.to_vec())));
+            }
+            match &new {
+                Some(new) => tree.insert(key.as_slice(), new.as_slice())?,
+                None => tree.remove(key.as_slice())?,
+            };
+            expiries.remove(key.as_slice())?;
+            Ok(Ok(()))
+        })?;
+        if let Err(current) = swapped {
+            return Err(KvsError::CompareAndSwapMismatch { current });
+        }
+        self.sync()
+    }
+
+    async fn set_bytes_if_present(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let _gate = self.snapshot_gate.read().await;
+        self.purge_if_expired(&key)?;
+        self.record_versions(Some(key.as_slice()))?;
+        let present = self.transaction(|tree, expiries| {
+            if tree.get(key.as_slice())?.is_none() {
+                return Ok(false);
+            }
+            tree.insert(key.as_slice(), value.as_slice())?;
+            expiries.remove(key.as_slice())?;
+            Ok(true)
+        })?;
+        if !present {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.sync()
+    }
+
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let _gate = self.snapshot_gate.read().await;
+        let operations = batch.into_operations();
+        self.record_versions(operations.iter().map(|operation| match operation {
+            BatchOperation::Set { key, .. } | BatchOperation::Remove { key } => key.as_slice(),
+        }))?;
+        self.transaction(|tree, expiries| {
+            for operation in &operations {
+                match operation {
+                    BatchOperation::Set { key, value } => {
+                        tree.insert(key.as_slice(), value.as_slice())?;
+                        expiries.remove(key.as_slice())?;
+                    }
+                    BatchOperation::Remove { key } => {
+                        tree.remove(key.as_slice())?;
+                        expiries.remove(key.as_slice())?;
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        self.sync()
+    }
+
+    async fn scan_bytes(
+        &self,
+        range: BytesRange,
+        limit: Option<usize>,
+        order: ScanOrder,
+    ) -> Result<KvsBytesScan> {
+        // `sled::Iter` cannot be sent across threads, so the range is narrowed
+        // past each visited key and looked up afresh instead
+        let tree: Tree = (*self.db).clone();
+        let expiries = self.expiries.clone();
+        let mut range = range;
+        let mut remaining = limit.unwrap_or(usize::MAX);
+        Ok(Box::pin(stream::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            loop {
+                let mut pairs = tree.range::<Vec<u8>, _>(range.clone());
+                let pair = match order {
+                    ScanOrder::Forward => pairs.next()?,
+                    ScanOrder::Reverse => pairs.next_back()?,
+                };
+                let pair = pair.map_err(KvsError::from).and_then(|(key, value)| {
+                    let expired = is_expired(&expiries, &key)?;
+                    Ok(((key.to_vec(), value.to_vec()), expired))
+                });
+                match pair {
+                    Ok(((key, value), expired)) => {
+                        match order {
+                            ScanOrder::Forward => range.0 = Bound::Excluded(key.clone()),
+                            ScanOrder::Reverse => range.1 = Bound::Excluded(key.clone()),
+                        }
+                        if !expired {
+                            remaining -= 1;
+                            return Some(Ok((key, value)));
+                        }
+                    }
+                    Err(e) => {
+                        remaining = 0;
+                        return Some(Err(e));
+                    }
+                }
+            }
+        })))
+    }
+
+    async fn checkpoint(&self, dest: PathBuf) -> Result<()> {
+        prepare_checkpoint_dir(&dest)?;
+        // The live pairs are copied into a database of their own while writes
+        // are held off, along with the expiry of those set with a TTL
+        let _gate = self.snapshot_gate.write().await;
+        let checkpoint = sled::open(&dest)?;
+        let checkpoint_expiries = checkpoint.open_tree(EXPIRIES_TREE)?;
+        let tree: &Tree = &self.db;
+        for pair in tree.iter() {
+            let (key, value) = pair?;
+            match expiry_of(&self.expiries, &key)? {
+                Some(expires_at) if expires_at <= now_millis() => continue,
+                Some(expires_at) => {
+                    checkpoint_expiries.insert(&key, &expires_at.to_be_bytes())?;
+                }
+                None => (),
+            }
+            checkpoint.insert(key, value)?;
+        }
+        checkpoint.flush()?;
         Ok(())
     }
+
+    async fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats::Sled(SledStats {
+            keys: self.db.len(),
+            size_on_disk: self.db.size_on_disk()?,
+        }))
+    }
+}
+
+fn is_expired(expiries: &Tree, key: &[u8]) -> Result<bool> {
+    Ok(expiry_of(expiries, key)?.is_some_and(|expires_at| expires_at <= now_millis()))
+}
+
+fn expiry_of(expiries: &Tree, key: &[u8]) -> Result<Option<u64>> {
+    expiries.get(key)?.map(decode_expiry).transpose()
+}
+
+fn decode_expiry(expires_at: IVec) -> Result<u64> {
+    let expires_at: [u8; 8] = expires_at.as_ref().try_into()?;
+    Ok(u64::from_be_bytes(expires_at))
+}
+
+/// Reads the value of `key` along with its expiry, both as of the same moment.
+fn read_version(tree: &Tree, expiries: &Tree, key: &[u8]) -> Result<SledVersion> {
+    let (value, expires_at) = (tree, expiries)
+        .transaction(|(tree, expiries)| Ok((tree.get(key)?, expiries.get(key)?)))
+        .map_err(|e: TransactionError<()>| match e {
+            TransactionError::Storage(e) => KvsError::Sled(e),
+            TransactionError::Abort(()) => unreachable!(),
+        })?;
+    match value {
+        Some(value) => Ok(Some((
+            value.to_vec(),
+            expires_at.map(decode_expiry).transpose()?,
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Removes `key` if its value has expired.
+///
+/// The value is only removed if the expiry still applies to it, so that a
+/// value set concurrently survives.
+fn purge_if_expired(tree: &Tree, expiries: &Tree, key: &[u8]) -> Result<()> {
+    let expires_at = match expiry_of(expiries, key)? {
+        Some(expires_at) if expires_at <= now_millis() => expires_at,
+        _ => return Ok(()),
+    };
+    let value = tree.get(key)?;
+    let expires_at = expires_at.to_be_bytes();
+    if expiries
+        .compare_and_swap(key, Some(&expires_at[..]), None as Option<IVec>)?
+        .is_ok()
+    {
+        if let Some(value) = value {
+            // A mismatch means the key has been set again in the meantime
+            let _ = tree.compare_and_swap(key, Some(value), None as Option<IVec>)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes expired keys every `EXPIRY_SWEEP_INTERVAL` for as long as
+/// `sweep_guard` is alive.
+fn spawn_expiry_sweep(tree: &Tree, expiries: &Tree, sweep_guard: &Arc<()>) {
+    let tree = tree.clone();
+    let expiries = expiries.clone();
+    let sweep_guard = Arc::downgrade(sweep_guard);
+    task::spawn(async move {
+        loop {
+            task::sleep(EXPIRY_SWEEP_INTERVAL).await;
+            if sweep_guard.upgrade().is_none() {
+                break;
+            }
+            let swept = expiries
+                .iter()
+                .keys()
+                .try_for_each(|key| purge_if_expired(&tree, &expiries, &key?));
+            if let Err(e) = swept {
+                error!("Expiry sweep failed: {}", e);
+            }
+        }
+    });
 }
@@ -0,0 +1,114 @@
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crossbeam_skiplist::SkipMap;
+
+use super::{BytesRange, ScanOrder};
+
+/// The earlier versions of the keys changed while snapshots are alive, which
+/// lets a snapshot read the live state it shares with its engine as of the
+/// moment it was taken.
+///
+/// Time is counted in epochs, a new one starting with every snapshot. A change
+/// keeps the version `V` of its key from before it, under the epoch it was
+/// made in, for as long as a snapshot of an earlier epoch is alive. What makes
+/// a change is up to the engine: moving a value around, as the compaction of
+/// a `KvStore` does, is none, the snapshot reading the current copy of the
+/// value.
+#[derive(Debug, Default)]
+pub(super) struct Versions<V> {
+    epoch: AtomicU64,
+    // The version of each key before its first change of each epoch, keyed by
+    // key and epoch
+    replaced: SkipMap<(Vec<u8>, u64), V>,
+    // The epochs of the live snapshots, each with the number of them
+    snapshots: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl<V: Clone + Send + 'static> Versions<V> {
+    /// Starts a new epoch for a snapshot of the live state as it is, returning
+    /// the epoch the snapshot reads as of.
+    ///
+    /// To be called with writes held off, as `record` is.
+    pub fn open_snapshot(&self) -> u64 {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        if snapshots.is_empty() {
+            // Left behind by changes made as the last snapshot was dropped
+            self.replaced.clear();
+        }
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst) + 1;
+        *snapshots.entry(epoch).or_insert(0) += 1;
+        epoch
+    }
+
+    /// Forgets a snapshot of `epoch`, along with the versions no live snapshot
+    /// reads any longer.
+    pub fn close_snapshot(&self, epoch: u64) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        if let Some(count) = snapshots.get_mut(&epoch) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.remove(&epoch);
+            }
+        }
+
+        // A snapshot reads the versions replaced from its own epoch on
+        let oldest = snapshots.keys().next().copied().unwrap_or(u64::MAX);
+        for entry in self.replaced.iter() {
+            if entry.key().1 < oldest {
+                entry.remove();
+            }
+        }
+    }
+
+    /// Whether a snapshot is alive, for which changes are to be recorded.
+    pub fn has_snapshots(&self) -> bool {
+        !self.snapshots.lock().unwrap().is_empty()
+    }
+
+    /// Keeps `old`, the version of `key`, as `key` is changed.
+    ///
+    /// Nothing is kept while there is no snapshot.
+    pub fn record(&self, key: &[u8], old: V) {
+        if !self.has_snapshots() {
+            return;
+        }
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        self.replaced.get_or_insert((key.to_vec(), epoch), old);
+    }
+
+    /// The version of `key` as of `epoch`, if it has changed since.
+    pub fn get(&self, key: &[u8], epoch: u64) -> Option<V> {
+        self.replaced
+            .range((key.to_vec(), epoch)..=(key.to_vec(), u64::MAX))
+            .next()
+            .map(|entry| entry.value().clone())
+    }
+
+    /// Finds the first key within `range` in `order` that has changed while
+    /// a snapshot was alive, and that may thus have existed as of it.
+    pub fn first_key(&self, range: &BytesRange, order: ScanOrder) -> Option<Vec<u8>> {
+        let start = match &range.0 {
+            Bound::Included(key) => Bound::Included((key.clone(), 0)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), u64::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match &range.1 {
+            Bound::Included(key) => Bound::Included((key.clone(), u64::MAX)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), 0)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let mut versions = self.replaced.range((start, end));
+        let entry = match order {
+            ScanOrder::Forward => versions.next(),
+            ScanOrder::Reverse => versions.next_back(),
+        };
+        entry.map(|entry| entry.key().0.clone())
+    }
+}
@@ -1,4 +1,4 @@
-use std::{array, io, net, string::FromUtf8Error, sync::PoisonError};
+use std::{array, io, net, path::PathBuf, string::FromUtf8Error, sync::PoisonError};
 
 use failure::Fail;
 
@@ -7,24 +7,71 @@ pub enum KvsError {
     #[fail(display = "Bincode error: {}", _0)]
     Bincode(bincode::Error),
 
+    #[fail(display = "Checkpoint directory {:?} lies within the store", _0)]
+    CheckpointDirInStore(PathBuf),
+
+    #[fail(display = "Checkpoint directory {:?} is not empty", _0)]
+    CheckpointDirNotEmpty(PathBuf),
+
+    #[fail(display = "Compaction cancelled")]
+    CompactionCancelled,
+
+    #[fail(display = "Value mismatch, the current value is {:?}", current)]
+    CompareAndSwapMismatch { current: Option<Vec<u8>> },
+
     #[fail(display = "Concurrent error when a lock is acquired")]
     ConcurrentError,
 
+    #[fail(
+        display = "Corrupted index block of generation {} at offset {}",
+        generation, offset
+    )]
+    CorruptedIndex { generation: u64, offset: usize },
+
+    #[fail(
+        display = "Corrupted log record in generation {} at offset {}",
+        generation, offset
+    )]
+    CorruptedLog { generation: u64, offset: usize },
+
+    #[fail(display = "Invalid options: {}", _0)]
+    InvalidOptions(String),
+
     #[fail(display = "IO error: {}", _0)]
     Io(io::Error),
 
     #[fail(display = "Key not found")]
     KeyNotFound,
 
+    #[fail(display = "The store at {:?} is opened by another process", _0)]
+    Locked(PathBuf),
+
     #[fail(display = "{}", _0)]
     Net(net::AddrParseError),
 
+    #[fail(
+        display = "The store at {:?} predates the current layout, and must be upgraded by opening it for writing or with `kvs-admin upgrade`",
+        _0
+    )]
+    OutdatedLayout(PathBuf),
+
+    #[fail(
+        display = "A failed write to the log could not be rolled back, and the store must be reopened"
+    )]
+    Poisoned,
+
+    #[fail(display = "The store is opened read-only")]
+    ReadOnly,
+
     #[fail(display = "serde_json error: {}", _0)]
     Serde(serde_json::Error),
 
     #[fail(display = "sled error: {}", _0)]
     Sled(sled::Error),
 
+    #[fail(display = "No store found at {:?}", _0)]
+    StoreNotFound(PathBuf),
+
     #[fail(display = "{}", _0)]
     StringError(String),
 
@@ -34,6 +81,15 @@ pub enum KvsError {
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
 
+    #[fail(display = "Unexpected response from the server")]
+    UnexpectedResponse,
+
+    #[fail(
+        display = "{:?} is in format version {}, which this version of kvs cannot read",
+        path, version
+    )]
+    UnsupportedFormat { path: PathBuf, version: u32 },
+
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(FromUtf8Error),
 }
@@ -1,5 +1,11 @@
 pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use engines::{
+    BatchOperation, BytesRange, CacheStats, CompactionHandle, CompactionMode, Compression,
+    DamagedFile, EngineStats, FileCheck, GenerationStats, GenerationUsage, IndexMode, KeyRange,
+    KvStore, KvStoreBuilder, KvStoreOptions, KvStoreSnapshot, KvStoreStats, KvsAdmin, KvsBytesScan,
+    KvsEngine, KvsScan, LogEntry, LogOperation, ScanOrder, SledKvsEngine, SledSnapshot, SledStats,
+    StoreUsage, SyncPolicy, WriteBatch,
+};
 pub use error::{KvsError, Result};
 pub use protocol::{Request, Response};
 pub use server::KvsServer;
@@ -1,8 +1,51 @@
+use std::{path::PathBuf, time::Duration};
+
 use serde::{Deserialize, Serialize};
 
+use crate::engines::{BytesRange, ScanOrder, WriteBatch};
+
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    SetWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    SetIfPresent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Batch {
+        batch: WriteBatch,
+    },
+    Scan {
+        range: BytesRange,
+        limit: Option<usize>,
+        order: ScanOrder,
+    },
+    ScanPrefix {
+        prefix: Vec<u8>,
+        order: ScanOrder,
+    },
+    /// Writes a checkpoint of the store to `dest`, a directory on the server.
+    Checkpoint {
+        dest: PathBuf,
+    },
+    /// Reports on the store, answered with `EngineStats` as JSON.
+    Stats,
 }
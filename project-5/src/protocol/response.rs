@@ -2,6 +2,11 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Response {
-    Ok(Option<String>),
+    Ok(Option<Vec<u8>>),
     Err(String),
+    /// A conditional write was refused, along with the current value.
+    Mismatch(Option<Vec<u8>>),
+    /// A key/value pair visited by a scan. A scan sends one per pair, followed
+    /// by `Ok(None)` once complete or `Err` if it fails midway.
+    Pair(Vec<u8>, Vec<u8>),
 }
@@ -6,11 +6,16 @@ use async_std::{
 use log::{debug, error};
 
 use crate::{
-    error::Result,
+    error::{KvsError, Result},
     protocol::{KvsStream, Request, Response},
-    KvsEngine,
+    KvsBytesScan, KvsEngine,
 };
 
+/// Serves a `KvsEngine` over TCP.
+///
+/// A write is acknowledged to the client only once the engine call returns,
+/// that is once the write reached the durability point chosen for the engine
+/// through its `SyncPolicy`.
 pub struct KvsServer<E: KvsEngine> {
     engine: E,
     addr: SocketAddr,
@@ -43,7 +48,7 @@ impl<E: KvsEngine + Sync> KvsServer<E> {
     }
 }
 
-async fn serve<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
+async fn serve<E: KvsEngine + Sync>(engine: E, stream: TcpStream) -> Result<()> {
     let peer_addr = stream.peer_addr()?;
     let mut kvs_stream = KvsStream::new(stream);
 
@@ -58,20 +63,88 @@ async fn serve<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
     while let Some(request) = kvs_stream.next().await {
         let request = request?;
         match request {
-            Request::Set { key, value } => send_response!(match engine.set(key, value).await {
+            Request::Set { key, value } => {
+                send_response!(match engine.set_bytes(key, value).await {
+                    Ok(()) => Response::Ok(None),
+                    Err(e) => Response::Err(format!("{}", e)),
+                })
+            }
+            Request::Get { key } => send_response!(match engine.get_bytes(key).await {
+                Ok(value) => Response::Ok(value),
+                Err(e) => Response::Err(format!("{}", e)),
+            }),
+            Request::SetWithTtl { key, value, ttl } => {
+                send_response!(match engine.set_bytes_with_ttl(key, value, ttl).await {
+                    Ok(()) => Response::Ok(None),
+                    Err(e) => Response::Err(format!("{}", e)),
+                })
+            }
+            Request::Remove { key } => send_response!(match engine.remove_bytes(key).await {
                 Ok(()) => Response::Ok(None),
                 Err(e) => Response::Err(format!("{}", e)),
             }),
-            Request::Get { key } => send_response!(match engine.get(key).await {
-                Ok(value) => Response::Ok(value),
+            Request::CompareAndSwap { key, expected, new } => {
+                send_response!(
+                    match engine.compare_and_swap_bytes(key, expected, new).await {
+                        Ok(()) => Response::Ok(None),
+                        Err(KvsError::CompareAndSwapMismatch { current }) =>
+                            Response::Mismatch(current),
+                        Err(e) => Response::Err(format!("{}", e)),
+                    }
+                )
+            }
+            Request::SetIfPresent { key, value } => {
+                send_response!(match engine.set_bytes_if_present(key, value).await {
+                    Ok(()) => Response::Ok(None),
+                    Err(e) => Response::Err(format!("{}", e)),
+                })
+            }
+            Request::Batch { batch } => send_response!(match engine.write_batch(batch).await {
+                Ok(()) => Response::Ok(None),
                 Err(e) => Response::Err(format!("{}", e)),
             }),
-            Request::Remove { key } => send_response!(match engine.remove(key).await {
+            Request::Scan {
+                range,
+                limit,
+                order,
+            } => {
+                let scan = engine.scan_bytes(range, limit, order).await;
+                send_scan(&mut kvs_stream, scan).await?;
+                debug!("Scan sent to {}", peer_addr);
+            }
+            Request::ScanPrefix { prefix, order } => {
+                let scan = engine.scan_prefix_bytes(prefix, order).await;
+                send_scan(&mut kvs_stream, scan).await?;
+                debug!("Scan sent to {}", peer_addr);
+            }
+            Request::Checkpoint { dest } => send_response!(match engine.checkpoint(dest).await {
                 Ok(()) => Response::Ok(None),
                 Err(e) => Response::Err(format!("{}", e)),
             }),
+            Request::Stats => send_response!(match stats_json(&engine).await {
+                Ok(stats) => Response::Ok(Some(stats)),
+                Err(e) => Response::Err(format!("{}", e)),
+            }),
         };
     }
 
     Ok(())
 }
+
+async fn stats_json<E: KvsEngine>(engine: &E) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&engine.stats().await?)?)
+}
+
+async fn send_scan(kvs_stream: &mut KvsStream<Request>, scan: Result<KvsBytesScan>) -> Result<()> {
+    let mut scan = match scan {
+        Ok(scan) => scan,
+        Err(e) => return kvs_stream.send(Response::Err(format!("{}", e))).await,
+    };
+    while let Some(pair) = scan.next().await {
+        match pair {
+            Ok((key, value)) => kvs_stream.send(Response::Pair(key, value)).await?,
+            Err(e) => return kvs_stream.send(Response::Err(format!("{}", e))).await,
+        }
+    }
+    kvs_stream.send(Response::Ok(None)).await
+}
@@ -1,6 +1,10 @@
 use assert_cmd::prelude::*;
+use predicates::boolean::PredicateBooleanExt;
 use predicates::str::{contains, is_empty};
+use std::ffi::OsStr;
 use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -212,6 +216,309 @@ fn cli_wrong_engine() {
     }
 }
 
+// `kvs-server --read-only` should serve reads without writing to the store
+// directory, the engine file included
+#[test]
+fn cli_read_only() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4019";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    fs::remove_file(temp_dir.path().join("engine")).unwrap();
+    let entries = || fs::read_dir(temp_dir.path()).unwrap().count();
+    let entries_before = entries();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--read-only"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "value2", "--addr", addr])
+        .assert()
+        .failure();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert!(!temp_dir.path().join("engine").exists());
+    assert_eq!(entries(), entries_before);
+
+    // sled cannot open its database without writing to it
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "sled", "--addr", addr, "--read-only"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--read-only is only supported by the kvs engine"));
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+fn log_bytes(dir: &std::path::Path) -> u64 {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(OsStr::new("log")))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+// `kvs-server --compression` should compress the values it logs
+#[test]
+fn cli_compression() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4020";
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr, "--compression", "gzip"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&[
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--compression",
+            "zstd",
+            "--compression-threshold",
+            "64",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let value = "a".repeat(4096);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", &value, "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(format!("{}\n", value));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert!(log_bytes(temp_dir.path()) < 1024);
+}
+
+// `kvs-server --blob-threshold` should keep large values out of the log
+#[test]
+fn cli_blob() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4021";
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr, "--blob-garbage-ratio", "half"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&[
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--blob-threshold",
+            "1024",
+            "--blob-garbage-ratio",
+            "0.25",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let value = "a".repeat(4096);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", &value, "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(format!("{}\n", value));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert!(log_bytes(temp_dir.path()) < 1024);
+    assert!(temp_dir.path().join("1.blob").exists());
+}
+
+// `kvs-server --index-mode on-disk` should look the keys of sealed generations
+// up in sorted tables, which are not swept
+#[test]
+fn cli_index_mode() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4022";
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--addr",
+            addr,
+            "--index-mode",
+            "on-disk",
+            "--expiry-sweep-interval",
+            "100",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&[
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--index-mode",
+            "on-disk",
+            "--max-segment-size",
+            "256",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for key_id in 0..20 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&[
+                "set",
+                &format!("key{}", key_id),
+                &format!("value{}", key_id),
+                "--addr",
+                addr,
+            ])
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert!(temp_dir.path().join("1.index").exists());
+}
+
+// `kvs-server --compaction-mode incremental` should leave the active generation
+// alone, which a full compaction merges
+#[test]
+fn cli_compaction_mode() {
+    let addr = "127.0.0.1:4024";
+    for (compaction_mode, compactions) in &[("full", 1), ("incremental", 0)] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(&[
+                "--engine",
+                "kvs",
+                "--addr",
+                addr,
+                "--compaction-threshold",
+                "1",
+                "--compaction-mode",
+                compaction_mode,
+                "--compaction-min-garbage-ratio",
+                "0.1",
+            ])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        for value in &["value1", "value2"] {
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["set", "key1", value, "--addr", addr])
+                .assert()
+                .success();
+        }
+        thread::sleep(Duration::from_millis(500));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["stats", "--addr", addr])
+            .assert()
+            .success()
+            .stdout(contains(format!("\"compactions\": {}", compactions)));
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+}
+
+// `kvs-server --value-cache-size 0` should serve every read from disk
+#[test]
+fn cli_value_cache_size() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4023";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--value-cache-size", "0"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    for _ in 0..2 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .assert()
+            .success()
+            .stdout("value1\n");
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("\"hits\": 0"))
+        .stdout(contains("\"entries\": 0"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -335,3 +642,622 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+fn cli_scan(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for key in &["b", "a:1", "c", "a:2"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, &format!("value-{}", key), "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a:1\tvalue-a:1\na:2\tvalue-a:2\nb\tvalue-b\nc\tvalue-c\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--start", "a:2", "--end", "c", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a:2\tvalue-a:2\nb\tvalue-b\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--reverse", "--limit", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("c\tvalue-c\nb\tvalue-b\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "a:", "--reverse", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a:2\tvalue-a:2\na:1\tvalue-a:1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--limit", "many", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_scan_kvs_engine() {
+    cli_scan("kvs", "127.0.0.1:4006");
+}
+
+#[test]
+fn cli_scan_sled_engine() {
+    cli_scan("sled", "127.0.0.1:4007");
+}
+
+fn cli_ttl(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--ttl", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    thread::sleep(Duration::from_millis(1500));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--ttl", "soon", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_ttl_kvs_engine() {
+    cli_ttl("kvs", "127.0.0.1:4008");
+}
+
+#[test]
+fn cli_ttl_sled_engine() {
+    cli_ttl("sled", "127.0.0.1:4009");
+}
+
+fn cli_binary(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "00ff", "deadbeef", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let value_dir = TempDir::new().unwrap();
+    let value_path = value_dir.path().join("value");
+    fs::write(&value_path, b"\x80\x81\n").unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "0001", "--hex", "--addr", addr, "--file"])
+        .arg(&value_path)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "00ff", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("deadbeef\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "00", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("0001\t80810a\n00ff\tdeadbeef\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "0g", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid hex"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_binary_kvs_engine() {
+    cli_binary("kvs", "127.0.0.1:4010");
+}
+
+#[test]
+fn cli_binary_sled_engine() {
+    cli_binary("sled", "127.0.0.1:4011");
+}
+
+fn cli_backup(engine: &str, addr: &str, backup_addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let backup_dir = TempDir::new().unwrap();
+    let dest = backup_dir.path().join("backup");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["backup", "--addr", addr, "--dest"])
+        .arg(&dest)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["backup", "--addr", addr, "--dest"])
+        .arg(&dest)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not empty"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    // The backup is served like any other store
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", backup_addr])
+        .current_dir(&dest)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", backup_addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_backup_kvs_engine() {
+    cli_backup("kvs", "127.0.0.1:4012", "127.0.0.1:4013");
+}
+
+#[test]
+fn cli_backup_sled_engine() {
+    cli_backup("sled", "127.0.0.1:4014", "127.0.0.1:4015");
+}
+
+#[test]
+fn cli_admin() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4016";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for args in &[["set", "key1", "value1"], ["set", "key1", "value2"]] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(&["--addr", addr])
+            .assert()
+            .success();
+    }
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // A record torn by a crash
+    let mut log = fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))
+        .unwrap();
+    log.write_all(&[0xff; 10]).unwrap();
+    drop(log);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("1.log: 2 records, damaged from offset"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump", "1", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("set \"key1\" = \"value1\""))
+        .stdout(contains("set \"key1\" = \"value2\""))
+        .stdout(contains("damaged from offset"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1.log: cut 10 damaged bytes"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1.log: 2 records, intact"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 1"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&[
+            "compact",
+            "--compression",
+            "zstd",
+            "--index-mode",
+            "on-disk",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Generation 1 not found"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 1"));
+}
+
+#[test]
+fn cli_admin_repair_sealed() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4025";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&[
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--max-segment-size",
+            "64",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for key_id in 0..4 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", &format!("key{}", key_id), "value"])
+            .args(&["--addr", addr])
+            .assert()
+            .success();
+    }
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // A flipped byte in a sealed generation
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path).unwrap();
+    let length = log.len();
+    log[length - 1] ^= 0xff;
+    fs::write(&log_path, &log).unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("1.log: sealed, damaged from offset"))
+        .stderr(contains("--truncate-sealed"));
+    assert_eq!(fs::metadata(&log_path).unwrap().len() as usize, length);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["repair", "--truncate-sealed"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1.log: cut"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+}
+
+#[test]
+fn cli_admin_compact_no_store() {
+    let temp_dir = TempDir::new().unwrap();
+    let missing_dir = temp_dir.path().join("missing");
+
+    // A mistyped directory
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["compact", "--dir"])
+        .arg(&missing_dir)
+        .assert()
+        .failure()
+        .stderr(contains("No store found"));
+    assert!(!missing_dir.exists());
+
+    // A directory that holds no store
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("No store found"));
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+
+    // An invalid option
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["compact", "--blob-threshold", "many"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid value for --blob-threshold"));
+}
+
+// `kvs-admin upgrade` should convert a store of the legacy layout, which the
+// other subcommands refuse to read
+#[test]
+fn cli_admin_upgrade() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("0.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("kvs-admin upgrade"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["upgrade"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("upgraded from the legacy layout"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["upgrade"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("already in the current layout"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("set \"key1\" = \"value1\""));
+
+    // A store written by kvs-server before records were checksummed
+    let temp_dir = TempDir::new().unwrap();
+    let fixture_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/baseline");
+    for entry in fs::read_dir(fixture_dir).unwrap() {
+        let fixture_path = entry.unwrap().path();
+        fs::copy(
+            &fixture_path,
+            temp_dir.path().join(fixture_path.file_name().unwrap()),
+        )
+        .unwrap();
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["upgrade"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("upgraded from the legacy layout"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump", "4"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("set \"key0\" = \"value3\""))
+        .stdout(contains("set \"key2\" = \"value2\""))
+        .stdout(contains("key1").not());
+}
+
+fn cli_stats(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!("\"engine\": \"{}\"", engine)))
+        .stdout(contains("\"keys\": 1"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_stats_kvs_engine() {
+    cli_stats("kvs", "127.0.0.1:4017");
+}
+
+#[test]
+fn cli_stats_sled_engine() {
+    cli_stats("sled", "127.0.0.1:4018");
+}
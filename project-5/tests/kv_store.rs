@@ -1,11 +1,26 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    ops::Bound,
+    time::Duration,
+};
+
 use async_std::{
+    prelude::*,
     sync::{Arc, Barrier},
     task,
 };
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CacheStats, CompactionMode, Compression, DamagedFile, EngineStats, IndexMode, KvStore,
+    KvStoreOptions, KvsAdmin, KvsEngine, KvsError, KvsScan, LogOperation, Result, ScanOrder,
+    SledKvsEngine, SyncPolicy, WriteBatch,
+};
+
+// The length of the format header every log and blob file starts with
+const FILE_HEADER_BYTES: u64 = 8;
 
 // Should get previously stored value
 #[async_std::test]
@@ -108,6 +123,223 @@ async fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Should discard a torn record at the tail of the newest log file on open
+#[async_std::test]
+async fn recover_from_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+    drop(store);
+
+    // Simulate a crash in the middle of appending a record
+    let log_path = temp_dir.path().join("1.log");
+    let intact_length = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .append(true)
+        .open(&log_path)?
+        .write_all(&[0x2a; 30])?;
+
+    let store = KvStore::open(temp_dir.path()).await?;
+    assert_eq!(fs::metadata(&log_path)?.len(), intact_length);
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+
+    store.set("key3".to_owned(), "value3".to_owned()).await?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).await?;
+    assert_eq!(
+        store.get("key3".to_owned()).await?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should apply every write of a batch, also after reopening and compaction
+#[async_std::test]
+async fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.set("key2".to_owned(), "value4".to_owned());
+    batch.remove("missing".to_owned());
+    store.write_batch(batch).await?;
+
+    for _ in 0..2 {
+        assert_eq!(store.get("key1".to_owned()).await?, None);
+        assert_eq!(
+            store.get("key2".to_owned()).await?,
+            Some("value4".to_owned())
+        );
+        assert_eq!(
+            store.get("key3".to_owned()).await?,
+            Some("value3".to_owned())
+        );
+        store.compact().await?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).await?;
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+    assert_eq!(
+        store.get("key2".to_owned()).await?,
+        Some("value4".to_owned())
+    );
+    assert_eq!(
+        store.get("key3".to_owned()).await?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should drop a torn batch as a whole on recovery
+#[async_std::test]
+async fn recover_from_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value2".to_owned());
+    batch.set("key2".to_owned(), "value2".to_owned());
+    store.write_batch(batch).await?;
+    drop(store);
+
+    // Simulate a crash in the middle of appending the batch record
+    let log_path = temp_dir.path().join("1.log");
+    let length = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(length - 5)?;
+
+    let store = KvStore::open(temp_dir.path()).await?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).await?, None);
+
+    Ok(())
+}
+
+// Should report corruption in a sealed log file, on read when the index is
+// rebuilt from its hint file and on open otherwise
+#[async_std::test]
+async fn detect_corruption_in_sealed_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).await?;
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log_path)?;
+    *content.last_mut().unwrap() ^= 0xff;
+    fs::write(&log_path, content)?;
+
+    let store = KvStore::open(temp_dir.path()).await?;
+    match store.get("key1".to_owned()).await {
+        Err(KvsError::CorruptedLog { generation, offset }) => {
+            assert_eq!(generation, 1);
+            assert_eq!(offset, FILE_HEADER_BYTES as usize);
+        }
+        _ => panic!("corruption in a sealed generation should be reported"),
+    }
+    drop(store);
+
+    fs::remove_file(temp_dir.path().join("1.hint"))?;
+    match KvStore::open(temp_dir.path()).await {
+        Err(KvsError::CorruptedLog { generation, offset }) => {
+            assert_eq!(generation, 1);
+            assert_eq!(offset, FILE_HEADER_BYTES as usize);
+        }
+        _ => panic!("corruption in a sealed generation should be reported"),
+    }
+
+    Ok(())
+}
+
+// Should rebuild the index from hint files, falling back to the log files
+// when a hint file cannot be trusted
+#[async_std::test]
+async fn open_with_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+    store.set("key1".to_owned(), "value3".to_owned()).await?;
+    store.remove("key2".to_owned()).await?;
+    drop(store);
+
+    let check = |store: KvStore| async move {
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value3".to_owned())
+        );
+        assert_eq!(store.get("key2".to_owned()).await?, None);
+        Result::Ok(())
+    };
+
+    // The first reopen replays the log and writes the hint file
+    let store = KvStore::open(temp_dir.path()).await?;
+    let hint_path = temp_dir.path().join("1.hint");
+    assert!(hint_path.exists());
+    check(store).await?;
+
+    let store = KvStore::open(temp_dir.path()).await?;
+    check(store).await?;
+
+    fs::write(&hint_path, b"garbage")?;
+    let store = KvStore::open(temp_dir.path()).await?;
+    check(store).await?;
+
+    Ok(())
+}
+
+// Should ignore and clean up log files that are not committed in the manifest,
+// e.g. the output of a compaction interrupted by a crash
+#[async_std::test]
+async fn ignore_orphaned_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    drop(store);
+
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_store = KvStore::open(other_dir.path()).await?;
+    other_store
+        .set("key1".to_owned(), "stale".to_owned())
+        .await?;
+    drop(other_store);
+
+    let orphaned_log_path = temp_dir.path().join("9.log");
+    fs::copy(other_dir.path().join("1.log"), &orphaned_log_path)?;
+
+    let store = KvStore::open(temp_dir.path()).await?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert!(!orphaned_log_path.exists());
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[async_std::test]
@@ -154,92 +386,2278 @@ async fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Should compact on demand without losing writes made while compacting
 #[async_std::test]
-async fn concurrent_set() -> Result<()> {
+async fn explicit_compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path()).await?;
-    let barrier = Arc::new(Barrier::new(1001));
-    for i in 0..1000 {
-        let store = store.clone();
-        let barrier = barrier.clone();
-        task::spawn(async move {
+
+    for iter in 0..100 {
+        for key_id in 0..10 {
             store
-                .set(format!("key{}", i), format!("value{}", i))
-                .await
-                .unwrap();
-            barrier.wait().await;
-        });
+                .set(format!("key{}", key_id), format!("{}", iter))
+                .await?;
+        }
     }
-    barrier.wait().await;
+    store.remove("key0".to_owned()).await?;
 
-    for i in 0..1000 {
+    let compaction = store.compact();
+    store.set("key1".to_owned(), "new".to_owned()).await?;
+    compaction.await?;
+
+    let check = |store: KvStore| async move {
+        assert_eq!(store.get("key0".to_owned()).await?, None);
+        assert_eq!(store.get("key1".to_owned()).await?, Some("new".to_owned()));
+        for key_id in 2..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some("99".to_owned())
+            );
+        }
+        Result::Ok(())
+    };
+    check(store.clone()).await?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).await?;
+    check(store).await?;
+
+    Ok(())
+}
+
+// Should seal the active log file once it exceeds the maximum segment size
+#[async_std::test]
+async fn roll_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .max_segment_size(1024)
+        .sync_policy(SyncPolicy::Always)
+        .open()
+        .await?;
+    for key_id in 0..100 {
+        store
+            .set(format!("key{}", key_id), format!("value{}", key_id))
+            .await?;
+    }
+
+    let log_files: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .collect();
+    assert!(log_files.len() > 1);
+    for log_file in log_files {
+        assert!(fs::metadata(log_file)?.len() < 2048);
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).await?;
+    for key_id in 0..100 {
         assert_eq!(
-            store.get(format!("key{}", i)).await?,
-            Some(format!("value{}", i))
+            store.get(format!("key{}", key_id)).await?,
+            Some(format!("value{}", key_id))
         );
     }
 
-    // Open from disk again and check persistent data
+    Ok(())
+}
+
+// Should split the compaction output into segments, each with a hint file
+#[async_std::test]
+async fn compact_into_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .max_segment_size(1024)
+        .open()
+        .await?;
+    for iter in 0..5 {
+        for key_id in 0..100 {
+            store
+                .set(format!("key{}", key_id), format!("{}", iter))
+                .await?;
+        }
+    }
+    store.compact().await?;
+
+    let log_files: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .collect();
+    let sealed_files: Vec<_> = log_files
+        .iter()
+        .filter(|path| fs::metadata(path).unwrap().len() > FILE_HEADER_BYTES)
+        .collect();
+    assert!(sealed_files.len() > 1);
+    for log_file in sealed_files {
+        assert!(fs::metadata(log_file)?.len() < 2048);
+        assert!(log_file.with_extension("hint").exists());
+    }
+
     drop(store);
     let store = KvStore::open(temp_dir.path()).await?;
-    for i in 0..1000 {
+    for key_id in 0..100 {
         assert_eq!(
-            store.get(format!("key{}", i)).await?,
-            Some(format!("value{}", i))
+            store.get(format!("key{}", key_id)).await?,
+            Some("4".to_owned())
         );
     }
 
     Ok(())
 }
 
+// Should keep acknowledged writes with every sync policy
 #[async_std::test]
-async fn concurrent_get() -> Result<()> {
+async fn sync_policies() -> Result<()> {
+    for &sync_policy in &[
+        SyncPolicy::Never,
+        SyncPolicy::Periodic(Duration::from_millis(10)),
+        SyncPolicy::Always,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder(temp_dir.path())
+            .sync_policy(sync_policy)
+            .open()
+            .await?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        store.set("key2".to_owned(), "value2".to_owned()).await?;
+        store.remove("key1".to_owned()).await?;
+        task::sleep(Duration::from_millis(50)).await;
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(store.get("key1".to_owned()).await?, None);
+        assert_eq!(
+            store.get("key2".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// Should serve reads but reject writes and leave the directory untouched
+#[async_std::test]
+async fn read_only() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path()).await?;
-    for i in 0..100 {
-        store
-            .set(format!("key{}", i), format!("value{}", i))
-            .await
-            .unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    drop(store);
+
+    let entries = || fs::read_dir(temp_dir.path()).unwrap().count();
+    let entries_before = entries();
+
+    let store = KvStore::builder(temp_dir.path())
+        .read_only(true)
+        .open()
+        .await?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    match store.set("key2".to_owned(), "value2".to_owned()).await {
+        Err(KvsError::ReadOnly) => {}
+        _ => panic!("writes to a read-only store should be rejected"),
     }
+    match store.remove("key1".to_owned()).await {
+        Err(KvsError::ReadOnly) => {}
+        _ => panic!("writes to a read-only store should be rejected"),
+    }
+    assert_eq!(entries(), entries_before);
 
-    let mut handles = Vec::new();
-    for task_id in 0..100 {
-        let store = store.clone();
-        let handle = task::spawn(async move {
-            for i in 0..100 {
-                let key_id = (i + task_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).await.unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
+    Ok(())
+}
+
+// A record of a log file written before records were checksummed:
+// `[payload length: u64 LE][command]`, the command being serialized with
+// bincode as `Set { key: String, value: String }` or `Remove { key: String }`
+fn unchecksummed_record(key: &str, value: Option<&str>) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&(value.is_none() as u32).to_le_bytes());
+    for field in std::iter::once(key).chain(value) {
+        payload.extend_from_slice(&(field.len() as u64).to_le_bytes());
+        payload.extend_from_slice(field.as_bytes());
     }
-    for handle in handles {
-        handle.await;
+    let mut record = (payload.len() as u64).to_le_bytes().to_vec();
+    record.extend_from_slice(&payload);
+    record
+}
+
+// Copies the store written by kvs-server before records were checksummed into
+// `dir`, returning its log files and their content
+//
+// key0 and key1 are set to value0 and value1 in 1.log, then key1 is removed and
+// key2 and key0 are set to value2 and value3 in 2.log, 3.log being left empty
+// by the last restart of the server.
+fn copy_baseline_store(dir: &std::path::Path) -> Vec<(std::path::PathBuf, Vec<u8>)> {
+    let fixture_dir =
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/baseline");
+    let mut files = Vec::new();
+    for entry in fs::read_dir(fixture_dir).unwrap() {
+        let fixture_path = entry.unwrap().path();
+        let path = dir.join(fixture_path.file_name().unwrap());
+        fs::copy(&fixture_path, &path).unwrap();
+        if path.extension() == Some("log".as_ref()) {
+            files.push((path, fs::read(&fixture_path).unwrap()));
+        }
     }
+    files.sort();
+    files
+}
 
-    // Open from disk again and check persistent data
-    drop(store);
-    let store = KvStore::open(temp_dir.path()).await?;
-    let mut handles = Vec::new();
-    for task_id in 0..100 {
-        let store = store.clone();
-        let handle = task::spawn(async move {
-            for i in 0..100 {
-                let key_id = (i + task_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).await.unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
+// Should convert a store whose records are not checksummed when opened for
+// writing, rather than take its records for torn ones
+#[async_std::test]
+async fn upgrade_unchecksummed_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let files = copy_baseline_store(temp_dir.path());
+    assert_eq!(
+        files[0].1,
+        [
+            unchecksummed_record("key0", Some("value0")),
+            unchecksummed_record("key1", Some("value1")),
+        ]
+        .concat()
+    );
+
+    match KvStore::builder(temp_dir.path())
+        .read_only(true)
+        .open()
+        .await
+    {
+        Err(KvsError::OutdatedLayout(_)) => {}
+        _ => panic!("a store without checksums should not be opened read-only"),
     }
-    for handle in handles {
-        handle.await;
+    assert!(matches!(
+        KvsAdmin::new(temp_dir.path()).generations().await,
+        Err(KvsError::OutdatedLayout(_))
+    ));
+    for (path, content) in &files {
+        assert_eq!(&fs::read(path)?, content);
+    }
+    assert!(!temp_dir.path().join("MANIFEST").exists());
+
+    let check_upgraded = |store: KvStore| async move {
+        assert_eq!(
+            store.get("key0".to_owned()).await?,
+            Some("value3".to_owned())
+        );
+        assert_eq!(store.get("key1".to_owned()).await?, None);
+        assert_eq!(
+            store.get("key2".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+        Result::Ok(())
+    };
+    check_upgraded(KvStore::open(temp_dir.path()).await?).await?;
+    for (path, _) in &files {
+        assert!(!path.exists());
+    }
+    check_upgraded(KvStore::open(temp_dir.path()).await?).await?;
+
+    // With kvs-admin, over the leftover of an interrupted upgrade
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    copy_baseline_store(temp_dir.path());
+    let mut leftover = b"KVS\0\x02\0\0\0".to_vec();
+    leftover.extend_from_slice(&[0xff; 10]);
+    fs::write(temp_dir.path().join("4.log"), &leftover)?;
+    let admin = KvsAdmin::new(temp_dir.path());
+    assert!(admin.upgrade().await?);
+    assert!(!admin.upgrade().await?);
+    assert_eq!(admin.generations().await?, vec![4]);
+    assert!(admin.verify().await?.iter().all(|check| check.is_intact()));
+    check_upgraded(KvStore::open(temp_dir.path()).await?).await?;
+
+    // A record torn by a crash is dropped from the tail of the newest
+    // generation
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let files = copy_baseline_store(temp_dir.path());
+    fs::remove_file(&files[2].0)?;
+    let mut torn = unchecksummed_record("key1", Some("torn"));
+    torn.truncate(torn.len() - 1);
+    OpenOptions::new()
+        .append(true)
+        .open(&files[1].0)?
+        .write_all(&torn)?;
+    check_upgraded(KvStore::open(temp_dir.path()).await?).await?;
+
+    // But not from a sealed one
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let files = copy_baseline_store(temp_dir.path());
+    let (sealed_path, content) = &files[0];
+    fs::write(sealed_path, &content[..content.len() - 1])?;
+    match KvStore::open(temp_dir.path()).await {
+        Err(KvsError::CorruptedLog { generation, offset }) => {
+            assert_eq!(generation, 1);
+            assert_eq!(offset, content.len() / 2);
+        }
+        _ => panic!("a damaged sealed generation should not be upgraded"),
+    }
+    assert_eq!(fs::read(sealed_path)?, &content[..content.len() - 1]);
+    assert!(!temp_dir.path().join("MANIFEST").exists());
+
+    // Nor is a log file whose first record is damaged taken for a torn one,
+    // unless it has a format header
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let mut content = unchecksummed_record("key0", Some("value0"));
+    content.truncate(content.len() - 1);
+    fs::write(&log_path, &content)?;
+    match KvStore::open(temp_dir.path()).await {
+        Err(KvsError::CorruptedLog { generation, offset }) => {
+            assert_eq!(generation, 1);
+            assert_eq!(offset, 0);
+        }
+        _ => panic!("a log file of an unknown layout should not be truncated"),
+    }
+    assert_eq!(fs::read(&log_path)?, content);
+
+    Ok(())
+}
+
+// Should convert a store of the legacy layout, whose commands are logged as
+// JSON into 0.log or 1.log, when opened for writing
+#[async_std::test]
+async fn upgrade_legacy_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("0.log"),
+        concat!(
+            r#"{"Set":{"key":"key1","value":"value1"}}"#,
+            r#"{"Set":{"key":"key2","value":"value2"}}"#,
+            r#"{"Remove":{"key":"key1"}}"#,
+            r#"{"Set":{"key":"key2","value":"value3"}}"#,
+            r#"{"Set":{"key":"torn","#,
+        ),
+    )?;
+
+    match KvStore::builder(temp_dir.path())
+        .read_only(true)
+        .open()
+        .await
+    {
+        Err(KvsError::OutdatedLayout(_)) => {}
+        _ => panic!("a legacy store should not be opened read-only"),
+    }
+    match KvsAdmin::new(temp_dir.path()).generations().await {
+        Err(KvsError::OutdatedLayout(_)) => {}
+        _ => panic!("a legacy store should not be read as records"),
+    }
+
+    let store = KvStore::open(temp_dir.path()).await?;
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+    assert_eq!(
+        store.get("key2".to_owned()).await?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(store.get("torn".to_owned()).await?, None);
+    assert!(!temp_dir.path().join("0.log").exists());
+    assert!(!temp_dir.path().join("1.log").exists());
+    store.set("key1".to_owned(), "value4".to_owned()).await?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path()).await?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value4".to_owned())
+    );
+    assert_eq!(
+        store.get("key2".to_owned()).await?,
+        Some("value3".to_owned())
+    );
+    drop(store);
+
+    // Through kvs-admin instead, from 1.log
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+    )?;
+    let admin = KvsAdmin::new(temp_dir.path());
+    assert!(admin.upgrade().await?);
+    assert!(!admin.upgrade().await?);
+    assert!(admin.verify().await?.iter().all(|check| check.is_intact()));
+    let store = KvStore::builder(temp_dir.path())
+        .read_only(true)
+        .open()
+        .await?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should read log files written before the format header was introduced,
+// whose records start right away
+#[async_std::test]
+async fn read_headerless_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+    store.remove("key1".to_owned()).await?;
+    drop(store);
+
+    // The hint file no longer matches, and must be left aside
+    let log_path = temp_dir.path().join("1.log");
+    let content = fs::read(&log_path)?;
+    fs::write(&log_path, &content[FILE_HEADER_BYTES as usize..])?;
+
+    for _ in 0..2 {
+        let store = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(store.get("key1".to_owned()).await?, None);
+        assert_eq!(
+            store.get("key2".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+    }
+    let (entries, check) = KvsAdmin::new(temp_dir.path()).dump(1).await?;
+    assert!(check.is_intact());
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].offset, 0);
+
+    let store = KvStore::open(temp_dir.path()).await?;
+    store.compact().await?;
+    assert_eq!(
+        store.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should refuse to open a store with a file of a format version it does not
+// know of
+#[async_std::test]
+async fn reject_unknown_format_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log_path)?;
+    content[4..8].copy_from_slice(&99u32.to_le_bytes());
+    fs::write(&log_path, content)?;
+
+    for read_only in &[false, true] {
+        match KvStore::builder(temp_dir.path())
+            .read_only(*read_only)
+            .open()
+            .await
+        {
+            Err(KvsError::UnsupportedFormat { path, version }) => {
+                assert_eq!(path, log_path);
+                assert_eq!(version, 99);
+            }
+            _ => panic!("a file of an unknown format version should be rejected"),
+        }
+    }
+    assert!(matches!(
+        KvsAdmin::new(temp_dir.path()).verify().await,
+        Err(KvsError::UnsupportedFormat { .. })
+    ));
+
+    Ok(())
+}
+
+// A store open for writing locks its directory against another writer, until
+// every clone of it is dropped
+#[async_std::test]
+async fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+
+    match KvStore::open(temp_dir.path()).await {
+        Err(KvsError::Locked(path)) => assert_eq!(path, temp_dir.path()),
+        _ => panic!("a locked store should not be opened for writing"),
+    }
+    match KvsAdmin::new(temp_dir.path()).repair(false).await {
+        Err(KvsError::Locked(_)) => {}
+        _ => panic!("a locked store should not be repaired"),
+    }
+
+    let reader = KvStore::builder(temp_dir.path())
+        .read_only(true)
+        .open()
+        .await?;
+    assert_eq!(
+        reader.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()).await,
+        Err(KvsError::Locked(_))
+    ));
+    drop(clone);
+
+    let store = KvStore::open(temp_dir.path()).await?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// Dropping the last handle to a store cancels its compactions, so that the
+// store can be opened again right away
+#[async_std::test]
+async fn reopen_while_compacting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder(temp_dir.path())
+            .compaction_threshold(1024)
+            .open()
+    };
+    let check = |store: KvStore| async move {
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some("99".to_owned())
+            );
+        }
+        Result::Ok(())
+    };
+
+    // A compaction started on demand
+    let store = open().await?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store
+                .set(format!("key{}", key_id), format!("{}", iter))
+                .await?;
+        }
+    }
+    let compaction = store.compact();
+    drop(store);
+    let store = open().await?;
+    check(store.clone()).await?;
+    match compaction.await {
+        Ok(()) | Err(KvsError::CompactionCancelled) => {}
+        Err(e) => panic!("unexpected compaction failure: {}", e),
+    }
+
+    // A compaction started by the last write
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store
+                .set(format!("key{}", key_id), format!("{}", iter))
+                .await?;
+        }
+    }
+    drop(store);
+    let store = open().await?;
+    check(store).await?;
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    let barrier = Arc::new(Barrier::new(1001));
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        task::spawn(async move {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .await
+                .unwrap();
+            barrier.wait().await;
+        });
+    }
+    barrier.wait().await;
+
+    for i in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", i)).await?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).await?;
+    for i in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", i)).await?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    Ok(())
+}
+
+// Should acknowledge each write of a group commit with its own outcome
+#[async_std::test]
+async fn concurrent_group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .sync_policy(SyncPolicy::Always)
+        .open()
+        .await?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .await?;
+    }
+
+    let mut handles = Vec::new();
+    for i in 0..100 {
+        let writer = store.clone();
+        handles.push(task::spawn(async move {
+            if i % 2 == 0 {
+                writer.remove(format!("key{}", i)).await
+            } else {
+                writer.set(format!("key{}", i), format!("new{}", i)).await
+            }
+        }));
+        let remover = store.clone();
+        handles.push(task::spawn(async move {
+            match remover.remove(format!("missing{}", i)).await {
+                Err(KvsError::KeyNotFound) => Ok(()),
+                _ => panic!("removing a missing key should fail"),
+            }
+        }));
+    }
+    for handle in handles {
+        handle.await?;
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).await?;
+    for i in 0..100 {
+        let expected = if i % 2 == 0 {
+            None
+        } else {
+            Some(format!("new{}", i))
+        };
+        assert_eq!(store.get(format!("key{}", i)).await?, expected);
+    }
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .await
+            .unwrap();
+    }
+
+    let mut handles = Vec::new();
+    for task_id in 0..100 {
+        let store = store.clone();
+        let handle = task::spawn(async move {
+            for i in 0..100 {
+                let key_id = (i + task_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).await.unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.await;
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).await?;
+    let mut handles = Vec::new();
+    for task_id in 0..100 {
+        let store = store.clone();
+        let handle = task::spawn(async move {
+            for i in 0..100 {
+                let key_id = (i + task_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).await.unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.await;
+    }
+
+    Ok(())
+}
+
+// Should serve concurrent reads through one store, across generations and
+// while they are compacted away
+#[async_std::test]
+async fn concurrent_get_through_one_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .max_segment_size(1024)
+        .value_cache_size(0)
+        .open()
+        .await?;
+    for i in 0..200 {
+        store
+            .set(format!("key{}", i % 100), format!("value{}", i % 100))
+            .await?;
+    }
+
+    let store = Arc::new(store);
+    let mut handles = Vec::new();
+    for task_id in 0..50 {
+        let store = Arc::clone(&store);
+        handles.push(task::spawn(async move {
+            for i in 0..200 {
+                let key_id = (i + task_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).await.unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        }));
+    }
+    store.compact().await?;
+    for handle in handles {
+        handle.await;
+    }
+
+    Ok(())
+}
+
+// Should write only when the current value matches, reporting it otherwise
+#[async_std::test]
+async fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+
+    store
+        .set_if_absent("key1".to_owned(), "value1".to_owned())
+        .await?;
+    match store
+        .set_if_absent("key1".to_owned(), "value2".to_owned())
+        .await
+    {
+        Err(KvsError::CompareAndSwapMismatch { current }) => {
+            assert_eq!(current, Some(b"value1".to_vec()))
+        }
+        _ => panic!("setting an existing key should fail"),
+    }
+
+    store
+        .compare_and_swap(
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+            Some("value3".to_owned()),
+        )
+        .await?;
+    match store
+        .compare_and_swap(
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+            Some("value4".to_owned()),
+        )
+        .await
+    {
+        Err(KvsError::CompareAndSwapMismatch { current }) => {
+            assert_eq!(current, Some(b"value3".to_vec()))
+        }
+        _ => panic!("swapping from a stale value should fail"),
+    }
+
+    store
+        .set_if_present("key1".to_owned(), "value5".to_owned())
+        .await?;
+    match store
+        .set_if_present("key2".to_owned(), "value5".to_owned())
+        .await
+    {
+        Err(KvsError::KeyNotFound) => {}
+        _ => panic!("setting a missing key should fail"),
+    }
+    assert_eq!(store.get("key2".to_owned()).await?, None);
+
+    store
+        .compare_and_swap("key1".to_owned(), Some("value5".to_owned()), None)
+        .await?;
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+    store
+        .compare_and_swap("key1".to_owned(), None, None)
+        .await?;
+
+    Ok(())
+}
+
+// Should not lose any update when swapping concurrently
+#[async_std::test]
+async fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    store.set("counter".to_owned(), "0".to_owned()).await?;
+
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(task::spawn(async move {
+            for _ in 0..20 {
+                let mut current = store.get("counter".to_owned()).await?;
+                loop {
+                    let next = current.as_ref().unwrap().parse::<u32>().unwrap() + 1;
+                    match store
+                        .compare_and_swap("counter".to_owned(), current, Some(next.to_string()))
+                        .await
+                    {
+                        Ok(()) => break,
+                        Err(KvsError::CompareAndSwapMismatch { current: actual }) => {
+                            current = actual.map(String::from_utf8).transpose()?
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.await?;
+    }
+
+    assert_eq!(
+        store.get("counter".to_owned()).await?,
+        Some("160".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should hide a key once its TTL has passed, also after reopening and compaction
+#[async_std::test]
+async fn expire_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path()).await?;
+    let ttl = Duration::from_millis(300);
+    store
+        .set_with_ttl("key1".to_owned(), "value1".to_owned(), ttl)
+        .await?;
+    store
+        .set_with_ttl("key2".to_owned(), "value2".to_owned(), ttl)
+        .await?;
+    store.set("key2".to_owned(), "value3".to_owned()).await?;
+    store
+        .set_with_ttl("key3".to_owned(), "value4".to_owned(), ttl * 100)
+        .await?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    task::sleep(ttl * 2).await;
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+    match store.remove("key1".to_owned()).await {
+        Err(KvsError::KeyNotFound) => {}
+        _ => panic!("removing an expired key should fail"),
+    }
+    let mut scan = store
+        .scan(
+            (Bound::Unbounded, Bound::Unbounded),
+            None,
+            ScanOrder::Forward,
+        )
+        .await?;
+    let mut keys = Vec::new();
+    while let Some(pair) = scan.next().await {
+        keys.push(pair?.0);
+    }
+    assert_eq!(keys, vec!["key2", "key3"]);
+    // The scan holds on to the store, and to the lock on its directory
+    drop(scan);
+
+    for _ in 0..2 {
+        drop(store);
+        let reopened = KvStore::open(temp_dir.path()).await?;
+        assert_eq!(reopened.get("key1".to_owned()).await?, None);
+        assert_eq!(
+            reopened.get("key2".to_owned()).await?,
+            Some("value3".to_owned())
+        );
+        assert_eq!(
+            reopened.get("key3".to_owned()).await?,
+            Some("value4".to_owned())
+        );
+        reopened.compact().await?;
+        store = reopened;
+    }
+
+    Ok(())
+}
+
+// Should drop the TTL of a key set again in sled, also within a batch, so
+// that the sweep leaves the new value alone
+#[async_std::test]
+async fn sled_expire_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path(), SyncPolicy::Never)?;
+    let ttl = Duration::from_millis(300);
+    for key in &["key1", "key2", "key3"] {
+        engine
+            .set_with_ttl(key.to_string(), "expiring".to_owned(), ttl)
+            .await?;
+    }
+    engine.set("key2".to_owned(), "value2".to_owned()).await?;
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value3".to_owned());
+    engine.write_batch(batch).await?;
+
+    // Past the TTL and a sweep
+    task::sleep(ttl + Duration::from_millis(1500)).await;
+    assert_eq!(engine.get("key1".to_owned()).await?, None);
+    match engine.remove("key1".to_owned()).await {
+        Err(KvsError::KeyNotFound) => {}
+        _ => panic!("removing an expired key should fail"),
+    }
+    assert_eq!(
+        engine.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(
+        engine.get("key3".to_owned()).await?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+async fn collect_keys(mut scan: KvsScan) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    while let Some(pair) = scan.next().await {
+        let (key, value) = pair?;
+        assert_eq!(value, format!("value-{}", key));
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+// Should visit keys in order within a range, in both directions
+#[async_std::test]
+async fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    for key in &["d", "b", "e", "a", "c"] {
+        store.set(key.to_string(), format!("value-{}", key)).await?;
+    }
+    store.remove("c".to_owned()).await?;
+
+    let all = (Bound::Unbounded, Bound::Unbounded);
+    let scan = store.scan(all.clone(), None, ScanOrder::Forward).await?;
+    assert_eq!(collect_keys(scan).await?, vec!["a", "b", "d", "e"]);
+    let scan = store.scan(all, Some(3), ScanOrder::Reverse).await?;
+    assert_eq!(collect_keys(scan).await?, vec!["e", "d", "b"]);
+
+    let range = (
+        Bound::Included("b".to_owned()),
+        Bound::Excluded("e".to_owned()),
+    );
+    let scan = store.scan(range.clone(), None, ScanOrder::Forward).await?;
+    assert_eq!(collect_keys(scan).await?, vec!["b", "d"]);
+    let scan = store.scan(range, Some(1), ScanOrder::Reverse).await?;
+    assert_eq!(collect_keys(scan).await?, vec!["d"]);
+
+    Ok(())
+}
+
+// Should visit exactly the keys starting with a prefix
+#[async_std::test]
+async fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    for key in &["user", "user:1", "user:2", "user;", "users", "usea"] {
+        store.set(key.to_string(), format!("value-{}", key)).await?;
+    }
+
+    let scan = store
+        .scan_prefix("user:".to_owned(), ScanOrder::Forward)
+        .await?;
+    assert_eq!(collect_keys(scan).await?, vec!["user:1", "user:2"]);
+    let scan = store
+        .scan_prefix("user".to_owned(), ScanOrder::Reverse)
+        .await?;
+    assert_eq!(
+        collect_keys(scan).await?,
+        vec!["users", "user;", "user:2", "user:1", "user"]
+    );
+
+    Ok(())
+}
+
+// Should keep reading the values as of the snapshot while the store changes
+#[async_std::test]
+async fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    for key in &["a", "b", "c"] {
+        store.set(key.to_string(), format!("value-{}", key)).await?;
+    }
+
+    let snapshot = store.snapshot().await?;
+    assert_eq!(snapshot.sequence(), 3);
+    store.set("a".to_owned(), "new".to_owned()).await?;
+    store.remove("b".to_owned()).await?;
+    store.set("d".to_owned(), "value-d".to_owned()).await?;
+
+    assert_eq!(
+        snapshot.get("a".to_owned()).await?,
+        Some("value-a".to_owned())
+    );
+    assert_eq!(
+        snapshot.get("b".to_owned()).await?,
+        Some("value-b".to_owned())
+    );
+    assert_eq!(snapshot.get("d".to_owned()).await?, None);
+    let scan = snapshot
+        .scan(
+            (Bound::Unbounded, Bound::Unbounded),
+            None,
+            ScanOrder::Forward,
+        )
+        .await?;
+    assert_eq!(collect_keys(scan).await?, vec!["a", "b", "c"]);
+
+    assert_eq!(store.snapshot().await?.sequence(), 6);
+    assert_eq!(store.get("a".to_owned()).await?, Some("new".to_owned()));
+
+    Ok(())
+}
+
+// Should keep the log files a snapshot reads from until it is dropped
+#[async_std::test]
+async fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    for key_id in 0..10 {
+        store
+            .set(format!("key{}", key_id), "old".to_owned())
+            .await?;
+    }
+
+    let snapshot = store.snapshot().await?;
+    for key_id in 0..10 {
+        store
+            .set(format!("key{}", key_id), "new".to_owned())
+            .await?;
+    }
+    store.compact().await?;
+    assert!(temp_dir.path().join("1.log").exists());
+
+    for key_id in 0..10 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id)).await?,
+            Some("old".to_owned())
+        );
+        assert_eq!(
+            store.get(format!("key{}", key_id)).await?,
+            Some("new".to_owned())
+        );
+    }
+
+    drop(snapshot);
+    for _ in 0..100 {
+        if !temp_dir.path().join("1.log").exists() {
+            return Ok(());
+        }
+        task::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("log file released by the snapshot was not deleted");
+}
+
+// Should keep each of several snapshots at its own version of the keys while
+// they are rewritten, removed and moved around by compaction
+#[async_std::test]
+async fn snapshot_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    for key in &["a", "b", "c"] {
+        store.set(key.to_string(), "first".to_owned()).await?;
+    }
+
+    let first = store.snapshot().await?;
+    store.set("a".to_owned(), "second".to_owned()).await?;
+    store.remove("c".to_owned()).await?;
+    store.compact().await?;
+    let second = store.snapshot().await?;
+    // Moves the values the second snapshot reads after it was taken
+    store.compact().await?;
+    store.set("a".to_owned(), "third".to_owned()).await?;
+    store.remove("b".to_owned()).await?;
+    store.set("d".to_owned(), "third".to_owned()).await?;
+    store.compact().await?;
+    store.set("a".to_owned(), "fourth".to_owned()).await?;
+    store.compact().await?;
+
+    let expected = [
+        (&first, vec![("a", "first"), ("b", "first"), ("c", "first")]),
+        (&second, vec![("a", "second"), ("b", "first")]),
+    ];
+    for (snapshot, pairs) in expected.iter() {
+        for key in &["a", "b", "c", "d"] {
+            let value = pairs
+                .iter()
+                .find(|(expected_key, _)| expected_key == key)
+                .map(|(_, value)| value.to_string());
+            assert_eq!(snapshot.get(key.to_string()).await?, value);
+        }
+        let mut scan = snapshot
+            .scan(
+                (Bound::Unbounded, Bound::Unbounded),
+                None,
+                ScanOrder::Reverse,
+            )
+            .await?;
+        let mut scanned = Vec::new();
+        while let Some(pair) = scan.next().await {
+            scanned.push(pair?);
+        }
+        let pairs: Vec<(String, String)> = pairs
+            .iter()
+            .rev()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(scanned, pairs);
+    }
+    drop(first);
+    assert_eq!(second.get("a".to_owned()).await?, Some("second".to_owned()));
+    drop(second);
+
+    assert_eq!(store.get("a".to_owned()).await?, Some("fourth".to_owned()));
+    assert_eq!(store.get("b".to_owned()).await?, None);
+    let snapshot = store.snapshot().await?;
+    assert_eq!(
+        snapshot.get("d".to_owned()).await?,
+        Some("third".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should snapshot a sled database the same way
+#[async_std::test]
+async fn sled_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path(), SyncPolicy::Never)?;
+    for key in &["a", "b"] {
+        engine
+            .set(key.to_string(), format!("value-{}", key))
+            .await?;
+    }
+
+    let snapshot = engine.snapshot().await?;
+    engine.set("a".to_owned(), "new".to_owned()).await?;
+    engine.remove("b".to_owned()).await?;
+    engine.set("c".to_owned(), "value-c".to_owned()).await?;
+
+    assert_eq!(
+        snapshot.get("a".to_owned()).await?,
+        Some("value-a".to_owned())
+    );
+    let scan = snapshot
+        .scan_prefix(String::new(), ScanOrder::Reverse)
+        .await?;
+    assert_eq!(collect_keys(scan).await?, vec!["b", "a"]);
+    assert!(engine.snapshot().await?.sequence() > snapshot.sequence());
+
+    Ok(())
+}
+
+// Should keep each sled snapshot at a single batch of its own, batches going on
+#[async_std::test]
+async fn sled_snapshot_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path(), SyncPolicy::Never)?;
+    let write_round = |round: usize| {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..20 {
+            batch.set(format!("key{}", key_id), format!("round{}", round));
+        }
+        batch
+    };
+    engine.write_batch(write_round(0)).await?;
+
+    let writing = {
+        let engine = engine.clone();
+        task::spawn(async move {
+            for round in 1..50 {
+                engine.write_batch(write_round(round)).await?;
+            }
+            Ok::<_, KvsError>(())
+        })
+    };
+    let mut snapshots = Vec::new();
+    for _ in 0..10 {
+        snapshots.push(engine.snapshot().await?);
+        task::yield_now().await;
+    }
+    writing.await?;
+    engine.remove("key0".to_owned()).await?;
+
+    for snapshot in &snapshots {
+        let mut scan = snapshot
+            .scan_prefix("key".to_owned(), ScanOrder::Forward)
+            .await?;
+        let mut values = Vec::new();
+        while let Some(pair) = scan.next().await {
+            values.push(pair?.1);
+        }
+        assert_eq!(values.len(), 20);
+        assert!(values.iter().all(|value| *value == values[0]));
+        assert_eq!(
+            snapshot.get("key0".to_owned()).await?,
+            Some(values[0].clone())
+        );
+    }
+    drop(snapshots);
+    let snapshot = engine.snapshot().await?;
+    assert_eq!(snapshot.get("key0".to_owned()).await?, None);
+    assert_eq!(
+        snapshot.get("key1".to_owned()).await?,
+        Some("round49".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should write a copy of the store that opens as it was, writes going on
+#[async_std::test]
+async fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    let large = |key_id: usize| format!("{}", key_id).repeat(2_000);
+    let store = KvStore::builder(temp_dir.path())
+        .max_segment_size(1024)
+        .blob_threshold(1024)
+        .open()
+        .await?;
+    for key_id in 0..100 {
+        store
+            .set(format!("key{}", key_id), format!("value{}", key_id))
+            .await?;
+    }
+    for key_id in 0..10 {
+        store.set(format!("large{}", key_id), large(key_id)).await?;
+    }
+    store.remove("key0".to_owned()).await?;
+    store
+        .set_with_ttl(
+            "expiring".to_owned(),
+            "value".to_owned(),
+            Duration::from_secs(3600),
+        )
+        .await?;
+
+    let writing = {
+        let store = store.clone();
+        task::spawn(async move {
+            for key_id in 1..100 {
+                store
+                    .set(format!("key{}", key_id), "new".to_owned())
+                    .await?;
+            }
+            Ok::<_, KvsError>(())
+        })
+    };
+    let dest = checkpoint_dir.path().join("checkpoint");
+    store.checkpoint(dest.clone()).await?;
+    writing.await?;
+    assert!(matches!(
+        store.checkpoint(dest.clone()).await,
+        Err(KvsError::CheckpointDirNotEmpty(_))
+    ));
+    // Nor may the copy go within the store
+    let nested = temp_dir.path().join("nested").join("checkpoint");
+    assert!(matches!(
+        store.checkpoint(nested).await,
+        Err(KvsError::CheckpointDirInStore(_))
+    ));
+    assert!(!temp_dir.path().join("nested").exists());
+
+    // The copy shares no file with the store, so that truncating one leaves
+    // the other as it is
+    let other = checkpoint_dir.path().join("other");
+    store.checkpoint(other.clone()).await?;
+    let log_length = fs::metadata(temp_dir.path().join("1.log"))?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(other.join("1.log"))?
+        .set_len(FILE_HEADER_BYTES)?;
+    assert_eq!(
+        fs::metadata(temp_dir.path().join("1.log"))?.len(),
+        log_length
+    );
+
+    // The copy outlives the files compacted away
+    store.compact().await?;
+    drop(store);
+    let copy = KvStore::open(&dest).await?;
+    assert_eq!(copy.get("key0".to_owned()).await?, None);
+    // The writes are applied in order, so the copy holds a prefix of them
+    let mut old_seen = false;
+    for key_id in 1..100 {
+        match copy.get(format!("key{}", key_id)).await? {
+            Some(value) if value == "new" => assert!(!old_seen),
+            value => {
+                assert_eq!(value, Some(format!("value{}", key_id)));
+                old_seen = true;
+            }
+        }
+    }
+    for key_id in 0..10 {
+        assert_eq!(
+            copy.get(format!("large{}", key_id)).await?,
+            Some(large(key_id))
+        );
+    }
+    assert_eq!(
+        copy.get("expiring".to_owned()).await?,
+        Some("value".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should write a copy of a sled database the same way
+#[async_std::test]
+async fn sled_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path(), SyncPolicy::Never)?;
+    engine.set("a".to_owned(), "value-a".to_owned()).await?;
+    engine
+        .set_with_ttl(
+            "b".to_owned(),
+            "value-b".to_owned(),
+            Duration::from_secs(3600),
+        )
+        .await?;
+    engine
+        .set_with_ttl(
+            "c".to_owned(),
+            "value-c".to_owned(),
+            Duration::from_millis(1),
+        )
+        .await?;
+    task::sleep(Duration::from_millis(10)).await;
+
+    engine
+        .checkpoint(checkpoint_dir.path().to_path_buf())
+        .await?;
+    engine.set("a".to_owned(), "new".to_owned()).await?;
+    drop(engine);
+
+    let copy = SledKvsEngine::open(checkpoint_dir.path(), SyncPolicy::Never)?;
+    assert_eq!(copy.get("a".to_owned()).await?, Some("value-a".to_owned()));
+    assert_eq!(copy.get("b".to_owned()).await?, Some("value-b".to_owned()));
+    let scan = copy.scan_prefix(String::new(), ScanOrder::Forward).await?;
+    assert_eq!(collect_keys(scan).await?, vec!["a", "b"]);
+
+    Ok(())
+}
+
+// Should inspect and repair the files of a store that is not open
+#[async_std::test]
+async fn offline_admin() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    store.write_batch(batch).await?;
+    store
+        .set_with_ttl(
+            "expired".to_owned(),
+            "value".to_owned(),
+            Duration::from_millis(1),
+        )
+        .await?;
+    drop(store);
+    task::sleep(Duration::from_millis(10)).await;
+
+    let admin = KvsAdmin::new(temp_dir.path());
+    assert_eq!(admin.generations().await?, vec![1]);
+    let (entries, check) = admin.dump(1).await?;
+    assert!(check.is_intact());
+    assert_eq!(check.records, 3);
+    let operations: Vec<_> = entries
+        .iter()
+        .map(|entry| (entry.batched, entry.operation.clone()))
+        .collect();
+    assert!(matches!(
+        &operations[..],
+        [
+            (
+                false,
+                LogOperation::Set {
+                    expires_at: None,
+                    ..
+                }
+            ),
+            (true, LogOperation::Set { .. }),
+            (true, LogOperation::Remove { .. }),
+            (
+                false,
+                LogOperation::Set {
+                    expires_at: Some(_),
+                    ..
+                }
+            ),
+        ]
+    ));
+    assert_eq!(entries[1].offset + entries[1].length, entries[2].offset);
+
+    let usage = admin.usage().await?;
+    assert_eq!(usage.keys, 1);
+    assert_eq!(usage.generations[0].live_bytes, entries[1].length);
+    assert_eq!(
+        usage.generations[0].bytes,
+        fs::metadata(temp_dir.path().join("1.log"))?.len() as usize
+    );
+
+    // Damage the last record
+    let log_path = temp_dir.path().join("1.log");
+    let length = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(length - 1)?;
+    let checks = admin.verify().await?;
+    assert_eq!(checks.len(), 1);
+    assert!(!checks[0].is_intact());
+    assert_eq!(checks[0].records, 2);
+
+    let repaired = admin.repair(false).await?;
+    assert_eq!(
+        repaired,
+        vec![DamagedFile {
+            check: checks[0].clone(),
+            truncated: true,
+        }]
+    );
+    assert!(admin.verify().await?.iter().all(|check| check.is_intact()));
+    assert_eq!(
+        fs::metadata(&log_path)?.len() as usize,
+        checks[0].valid_length
+    );
+    assert!(matches!(admin.dump(2).await, Err(KvsError::StringError(_))));
+
+    admin.compact(KvStoreOptions::default()).await?;
+    assert!(!admin.generations().await?.contains(&1));
+    assert_eq!(admin.usage().await?.keys, 1);
+
+    // Compacting a directory that holds no store creates none
+    let missing_dir = temp_dir.path().join("missing");
+    assert!(matches!(
+        KvsAdmin::new(&missing_dir)
+            .compact(KvStoreOptions::default())
+            .await,
+        Err(KvsError::StoreNotFound(_))
+    ));
+    assert!(!missing_dir.exists());
+
+    Ok(())
+}
+
+// Should only cut a damaged sealed generation short when told to, as the
+// records past the damage are lost
+#[async_std::test]
+async fn repair_sealed_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .max_segment_size(1024)
+        .open()
+        .await?;
+    for key_id in 0..100 {
+        store
+            .set(format!("key{}", key_id), format!("value{}", key_id))
+            .await?;
+    }
+    drop(store);
+
+    let admin = KvsAdmin::new(temp_dir.path());
+    let generations = admin.generations().await?;
+    assert!(generations.len() > 2);
+    let newest = *generations.last().unwrap();
+
+    // Flip a byte in the middle of the first generation and tear the tail of
+    // the newest one
+    let sealed_path = temp_dir.path().join("1.log");
+    let mut sealed = fs::read(&sealed_path)?;
+    let sealed_length = sealed.len();
+    sealed[sealed_length / 2] ^= 0xff;
+    fs::write(&sealed_path, &sealed)?;
+    let newest_path = temp_dir.path().join(format!("{}.log", newest));
+    let newest_length = fs::metadata(&newest_path)?.len();
+    OpenOptions::new()
+        .append(true)
+        .open(&newest_path)?
+        .write_all(&[0xff; 10])?;
+
+    // The output of an interrupted compaction, which the manifest does not
+    // list, is no generation of the store
+    let orphan_path = temp_dir.path().join(format!("{}.log", newest + 1));
+    let mut orphan = b"KVS\0\x02\0\0\0".to_vec();
+    orphan.extend_from_slice(&[0xff; 10]);
+    fs::write(&orphan_path, &orphan)?;
+
+    let damaged = admin.repair(false).await?;
+    assert_eq!(damaged.len(), 2);
+    assert_eq!(damaged[0].check.path, sealed_path);
+    assert!(!damaged[0].truncated);
+    assert_eq!(damaged[1].check.path, newest_path);
+    assert!(damaged[1].truncated);
+    assert_eq!(fs::metadata(&sealed_path)?.len() as usize, sealed_length);
+    assert_eq!(fs::metadata(&newest_path)?.len(), newest_length);
+    assert_eq!(fs::read(&orphan_path)?, orphan);
+    let checks = admin.verify().await?;
+    assert!(!checks[0].is_intact());
+    assert!(checks[1..].iter().all(|check| check.is_intact()));
+
+    let damaged = admin.repair(true).await?;
+    assert_eq!(damaged.len(), 1);
+    assert!(damaged[0].truncated);
+    assert_eq!(
+        fs::metadata(&sealed_path)?.len() as usize,
+        damaged[0].check.valid_length
+    );
+    assert!(admin.verify().await?.iter().all(|check| check.is_intact()));
+    let store = KvStore::open(temp_dir.path()).await?;
+    assert_eq!(
+        store.get("key99".to_owned()).await?,
+        Some("value99".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should report on the keys, the generations and the compactions of a store
+#[async_std::test]
+async fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .max_segment_size(1024)
+        .compaction_threshold(usize::MAX)
+        .open()
+        .await?;
+    for round in 0..2 {
+        for key_id in 0..50 {
+            store
+                .set(format!("key{}", key_id), format!("value{}", round))
+                .await?;
+        }
+    }
+
+    let stats = match store.stats().await? {
+        EngineStats::Kvs(stats) => stats,
+        stats => panic!("unexpected stats {:?}", stats),
+    };
+    assert_eq!(stats.keys, 50);
+    assert!(stats.generations.len() > 1);
+    assert_eq!(
+        stats.generations.iter().map(|g| g.bytes).sum::<u64>(),
+        file_bytes(temp_dir.path(), "log")
+    );
+    assert!(stats.garbage_bytes > 0);
+    assert_eq!(
+        stats
+            .generations
+            .iter()
+            .map(|g| g.garbage_bytes)
+            .sum::<usize>(),
+        stats.garbage_bytes
+    );
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.last_compaction_at, None);
+
+    store.compact().await?;
+    let stats = match store.stats().await? {
+        EngineStats::Kvs(stats) => stats,
+        stats => panic!("unexpected stats {:?}", stats),
+    };
+    assert_eq!(stats.keys, 50);
+    assert_eq!(stats.garbage_bytes, 0);
+    assert_eq!(stats.compactions, 1);
+    assert!(stats.last_compaction_at.is_some());
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(sled_dir.path(), SyncPolicy::Never)?;
+    engine.set("key".to_owned(), "value".to_owned()).await?;
+    match engine.stats().await? {
+        EngineStats::Sled(stats) => assert_eq!(stats.keys, 1),
+        stats => panic!("unexpected stats {:?}", stats),
+    }
+
+    Ok(())
+}
+
+// Should store keys and values that are not valid UTF-8
+#[async_std::test]
+async fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).await?;
+    store.set_bytes(vec![0xff, 0x00], vec![0x80]).await?;
+    store.set_bytes(vec![0xff], vec![0xc3, 0x28]).await?;
+    store.set("text".to_owned(), "value".to_owned()).await?;
+
+    let check = |store: KvStore| async move {
+        assert_eq!(store.get_bytes(vec![0xff, 0x00]).await?, Some(vec![0x80]));
+        assert_eq!(
+            store.get_bytes(b"text".to_vec()).await?,
+            Some(b"value".to_vec())
+        );
+        assert_eq!(store.get_bytes(vec![0xff]).await?, Some(vec![0xc3, 0x28]));
+        assert_eq!(store.get("\u{ff}".to_owned()).await?, None);
+
+        let mut scan = store
+            .scan_prefix_bytes(vec![0xff], ScanOrder::Forward)
+            .await?;
+        let mut keys = Vec::new();
+        while let Some(pair) = scan.next().await {
+            keys.push(pair?.0);
+        }
+        assert_eq!(keys, vec![vec![0xff], vec![0xff, 0x00]]);
+        Result::Ok(())
+    };
+    check(store.clone()).await?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).await?;
+    check(store.clone()).await?;
+
+    store
+        .set_bytes(b"invalid".to_vec(), vec![0xc3, 0x28])
+        .await?;
+    match store.get("invalid".to_owned()).await {
+        Err(KvsError::Utf8(_)) => {}
+        _ => panic!("a value that is not UTF-8 should fail to decode as a string"),
+    }
+
+    Ok(())
+}
+
+// Should hand out values written to sled as arbitrary bytes
+#[async_std::test]
+async fn sled_binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path(), SyncPolicy::Never)?;
+    engine.set_bytes(vec![0xfe], vec![0xff, 0x00]).await?;
+
+    assert_eq!(engine.get_bytes(vec![0xfe]).await?, Some(vec![0xff, 0x00]));
+    engine
+        .compare_and_swap_bytes(vec![0xfe], Some(vec![0xff, 0x00]), None)
+        .await?;
+    assert_eq!(engine.get_bytes(vec![0xfe]).await?, None);
+
+    Ok(())
+}
+
+// Should compress large values as they are logged, and read logs written
+// with any compression setting
+#[async_std::test]
+async fn compress_values() -> Result<()> {
+    let log_bytes = |dir: &std::path::Path| -> u64 {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            .map(|path| fs::metadata(path).unwrap().len())
+            .sum()
+    };
+    let document = |key_id: usize| {
+        format!(
+            "{{\"id\": {}, \"tags\": [{}]}}",
+            key_id,
+            "\"tag\", ".repeat(200)
+        )
+    };
+
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(plain_dir.path()).await?;
+    for key_id in 0..20 {
+        store
+            .set(format!("key{}", key_id), document(key_id))
+            .await?;
+    }
+    drop(store);
+
+    for &compression in &[Compression::Lz4, Compression::Zstd] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder(temp_dir.path())
+            .compression(compression)
+            .open()
+            .await?;
+        for key_id in 0..20 {
+            store
+                .set(format!("key{}", key_id), document(key_id))
+                .await?;
+        }
+        store.set("small".to_owned(), "value".to_owned()).await?;
+        assert!(log_bytes(temp_dir.path()) * 4 < log_bytes(plain_dir.path()));
+
+        // Open from disk again without compression and check persistent data
+        drop(store);
+        let store = KvStore::open(temp_dir.path()).await?;
+        for key_id in 0..20 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some(document(key_id))
+            );
+        }
+        assert_eq!(
+            store.get("small".to_owned()).await?,
+            Some("value".to_owned())
+        );
+    }
+
+    // Compaction recompresses the values it copies
+    let store = KvStore::builder(plain_dir.path())
+        .compression(Compression::Lz4)
+        .open()
+        .await?;
+    let uncompressed_bytes = log_bytes(plain_dir.path());
+    store.compact().await?;
+    assert!(log_bytes(plain_dir.path()) * 4 < uncompressed_bytes);
+    for key_id in 0..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).await?,
+            Some(document(key_id))
+        );
+    }
+
+    Ok(())
+}
+
+fn file_bytes(dir: &std::path::Path, extension: &str) -> u64 {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(extension.as_ref()))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+// Should keep large values in blob files and read them back transparently
+#[async_std::test]
+async fn blob_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let large = |key_id: usize| format!("{}", key_id).repeat(10_000);
+    let store = KvStore::builder(temp_dir.path())
+        .blob_threshold(1024)
+        .open()
+        .await?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), large(key_id)).await?;
+    }
+    store
+        .set_with_ttl("expiring".to_owned(), large(0), Duration::from_secs(3600))
+        .await?;
+    store.set("small".to_owned(), "value".to_owned()).await?;
+    assert!(file_bytes(temp_dir.path(), "blob") > 10 * 10_000);
+    assert!(file_bytes(temp_dir.path(), "log") < 10_000);
+
+    // Compaction copies the pointers, not the values
+    let blob_bytes = file_bytes(temp_dir.path(), "blob");
+    store.compact().await?;
+    assert_eq!(file_bytes(temp_dir.path(), "blob"), blob_bytes);
+    assert!(file_bytes(temp_dir.path(), "log") < 10_000);
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).await?,
+            Some(large(key_id))
+        );
+    }
+
+    // Open from disk again without blob files and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).await?;
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).await?,
+            Some(large(key_id))
+        );
+    }
+    assert_eq!(store.get("expiring".to_owned()).await?, Some(large(0)));
+    assert_eq!(
+        store.get("small".to_owned()).await?,
+        Some("value".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should collect blob files mostly made of garbage, keeping their live values
+#[async_std::test]
+async fn compact_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let large = |key_id: usize| format!("{}", key_id).repeat(10_000);
+    let store = KvStore::builder(temp_dir.path())
+        .blob_threshold(1024)
+        .open()
+        .await?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), large(key_id)).await?;
+    }
+    for key_id in 0..4 {
+        store.remove(format!("key{}", key_id)).await?;
+    }
+
+    // Too little garbage so far, only the new active blob file is added
+    let blob_bytes = file_bytes(temp_dir.path(), "blob");
+    store.compact_blobs().await?;
+    assert_eq!(
+        file_bytes(temp_dir.path(), "blob"),
+        blob_bytes + FILE_HEADER_BYTES
+    );
+
+    let snapshot = store.snapshot().await?;
+    for key_id in 4..6 {
+        store.remove(format!("key{}", key_id)).await?;
+    }
+    store.compact_blobs().await?;
+    for key_id in 6..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).await?,
+            Some(large(key_id))
+        );
+    }
+    assert!(temp_dir.path().join("1.blob").exists());
+
+    // The snapshot still reads from the collected blob file until dropped
+    for key_id in 4..10 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id)).await?,
+            Some(large(key_id))
+        );
+    }
+    drop(snapshot);
+    let mut released = false;
+    for _ in 0..100 {
+        if !temp_dir.path().join("1.blob").exists() {
+            released = true;
+            break;
+        }
+        task::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(
+        released,
+        "blob file released by the snapshot was not deleted"
+    );
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).await?;
+    assert_eq!(store.get("key9".to_owned()).await?, Some(large(9)));
+    assert_eq!(store.get("key5".to_owned()).await?, None);
+
+    Ok(())
+}
+
+// Should collect the garbage of the blob files along with compacting offline
+#[async_std::test]
+async fn offline_compact_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let large = |key_id: usize, round: usize| format!("{}-{}", key_id, round).repeat(5_000);
+    let store = KvStore::builder(temp_dir.path())
+        .blob_threshold(1024)
+        .open()
+        .await?;
+    for round in 0..2 {
+        for key_id in 0..4 {
+            store
+                .set(format!("key{}", key_id), large(key_id, round))
+                .await?;
+        }
+    }
+    drop(store);
+
+    KvsAdmin::new(temp_dir.path())
+        .compact(KvStoreOptions::default())
+        .await?;
+    assert!(!temp_dir.path().join("1.blob").exists());
+    let store = KvStore::open(temp_dir.path()).await?;
+    for key_id in 0..4 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).await?,
+            Some(large(key_id, 1))
+        );
+    }
+
+    Ok(())
+}
+
+// Should collect a sealed blob file in the background once enough of its
+// values are overwritten, with either index mode
+#[async_std::test]
+async fn compact_blobs_automatically() -> Result<()> {
+    let large = |key_id: usize, round: usize| format!("{}-{}", key_id, round).repeat(5_000);
+    for &index_mode in &[IndexMode::InMemory, IndexMode::OnDisk] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let builder = || {
+            KvStore::builder(temp_dir.path())
+                .blob_threshold(1024)
+                .expiry_sweep_interval(
+                    Some(Duration::from_secs(1)).filter(|_| index_mode == IndexMode::InMemory),
+                )
+                .index_mode(index_mode)
+        };
+        let store = builder().open().await?;
+        for key_id in 0..10 {
+            store
+                .set(format!("key{}", key_id), large(key_id, 0))
+                .await?;
+        }
+
+        // Reopening seals the blob file, whose values are then found through
+        // the hint files or the sorted tables, and still are once compacted
+        drop(store);
+        let store = builder().open().await?;
+        store.compact().await?;
+        for key_id in 0..4 {
+            store
+                .set(format!("key{}", key_id), large(key_id, 1))
+                .await?;
+        }
+        task::sleep(Duration::from_millis(100)).await;
+        assert!(temp_dir.path().join("1.blob").exists());
+
+        store.set("key4".to_owned(), large(4, 1)).await?;
+        store.remove("key5".to_owned()).await?;
+        let mut collected = false;
+        for _ in 0..100 {
+            if !temp_dir.path().join("1.blob").exists() {
+                collected = true;
+                break;
+            }
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(
+            collected,
+            "blob file mostly made of garbage was not collected"
+        );
+
+        drop(store);
+        let store = builder().open().await?;
+        for key_id in 0..5 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some(large(key_id, 1))
+            );
+        }
+        assert_eq!(store.get("key5".to_owned()).await?, None);
+        for key_id in 6..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).await?,
+                Some(large(key_id, 0))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Should behave the same with the keys of sealed generations looked up on disk
+#[async_std::test]
+async fn on_disk_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |index_mode| {
+        KvStore::builder(temp_dir.path())
+            .max_segment_size(4096)
+            .index_mode(index_mode)
+            .expiry_sweep_interval(
+                Some(Duration::from_secs(1)).filter(|_| index_mode == IndexMode::InMemory),
+            )
+            .open()
+    };
+    let key = |key_id: usize| format!("key{:04}", key_id);
+
+    let mut store = open(IndexMode::OnDisk).await?;
+    for key_id in 0..1000 {
+        store.set(key(key_id), "stale".to_owned()).await?;
+    }
+    for key_id in 0..1000 {
+        store
+            .set(key(key_id), format!("value-{}", key(key_id)))
+            .await?;
+    }
+    for key_id in (0..1000).step_by(3) {
+        store.remove(key(key_id)).await?;
+    }
+    store.set("ttl".to_owned(), "value-ttl".to_owned()).await?;
+    store
+        .set_with_ttl(
+            "ttl".to_owned(),
+            "expiring".to_owned(),
+            Duration::from_millis(100),
+        )
+        .await?;
+    assert!(file_bytes(temp_dir.path(), "index") > 0);
+    task::sleep(Duration::from_millis(200)).await;
+
+    // A snapshot keeps reading the values as of when it was taken
+    let snapshot = store.snapshot().await?;
+    store.set(key(1), "new".to_owned()).await?;
+    store.compact().await?;
+    assert_eq!(
+        snapshot.get(key(1)).await?,
+        Some(format!("value-{}", key(1)))
+    );
+    drop(snapshot);
+    store.set(key(1), format!("value-{}", key(1))).await?;
+
+    let live: Vec<String> = (0..1000)
+        .filter(|key_id| key_id % 3 != 0)
+        .map(key)
+        .collect();
+    let reversed: Vec<String> = live.iter().rev().take(10).cloned().collect();
+    let in_range: Vec<String> = [502, 503, 505, 506, 508, 509, 511]
+        .iter()
+        .map(|&key_id| key(key_id))
+        .collect();
+
+    // Compacting and reopening, with the index on disk and then in memory,
+    // must neither lose keys nor bring removed or expired ones back
+    for reopen in &[None, Some(IndexMode::OnDisk), Some(IndexMode::InMemory)] {
+        if let Some(index_mode) = reopen {
+            store.compact().await?;
+            drop(store);
+            store = open(*index_mode).await?;
+        }
+
+        for key_id in 0..6 {
+            let expected = Some(format!("value-{}", key(key_id))).filter(|_| key_id % 3 != 0);
+            assert_eq!(store.get(key(key_id)).await?, expected);
+        }
+        assert_eq!(store.get("ttl".to_owned()).await?, None);
+        assert!(matches!(
+            store.remove(key(3)).await,
+            Err(KvsError::KeyNotFound)
+        ));
+
+        let scan = store
+            .scan_prefix("key".to_owned(), ScanOrder::Forward)
+            .await?;
+        assert_eq!(collect_keys(scan).await?, live);
+        let all = (Bound::Unbounded, Bound::Unbounded);
+        let scan = store.scan(all, Some(10), ScanOrder::Reverse).await?;
+        assert_eq!(collect_keys(scan).await?, reversed);
+        let range = (Bound::Excluded(key(500)), Bound::Included(key(511)));
+        let scan = store.scan(range, None, ScanOrder::Forward).await?;
+        assert_eq!(collect_keys(scan).await?, in_range);
+    }
+
+    Ok(())
+}
+
+// Should read the expiry of keys from the sorted tables, and refuse to sweep
+// an index on disk
+#[async_std::test]
+async fn on_disk_index_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = || {
+        KvStore::builder(temp_dir.path())
+            .max_segment_size(1024)
+            .index_mode(IndexMode::OnDisk)
+    };
+    assert!(matches!(
+        builder()
+            .expiry_sweep_interval(Some(Duration::from_secs(1)))
+            .open()
+            .await,
+        Err(KvsError::InvalidOptions(_))
+    ));
+
+    let open = || builder().expiry_sweep_interval(None).open();
+    let mut store = open().await?;
+    store
+        .set_with_ttl(
+            "short".to_owned(),
+            "value-short".to_owned(),
+            Duration::from_millis(100),
+        )
+        .await?;
+    store
+        .set_with_ttl(
+            "long".to_owned(),
+            "value-long".to_owned(),
+            Duration::from_secs(3600),
+        )
+        .await?;
+    // Seals the generation holding both into a sorted table
+    for key_id in 0..100 {
+        store
+            .set(format!("filler{}", key_id), "filler".to_owned())
+            .await?;
+    }
+    assert!(file_bytes(temp_dir.path(), "index") > 0);
+    task::sleep(Duration::from_millis(200)).await;
+
+    // With the store reopened, the expiries are read back from the tables
+    for reopen in &[false, true] {
+        if *reopen {
+            drop(store);
+            store = open().await?;
+        }
+        assert_eq!(store.get("short".to_owned()).await?, None);
+        assert!(matches!(
+            store.remove("short".to_owned()).await,
+            Err(KvsError::KeyNotFound)
+        ));
+        assert_eq!(
+            store.get("long".to_owned()).await?,
+            Some("value-long".to_owned())
+        );
+    }
+    store.remove("long".to_owned()).await?;
+    assert_eq!(store.get("long".to_owned()).await?, None);
+
+    Ok(())
+}
+
+// Should serve repeated reads from the value cache and drop replaced values
+#[async_std::test]
+async fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .value_cache_size(64)
+        .open()
+        .await?;
+    store.set("a".to_owned(), "value-a".to_owned()).await?;
+    store.set("b".to_owned(), "value-b".to_owned()).await?;
+
+    for _ in 0..3 {
+        assert_eq!(store.get("a".to_owned()).await?, Some("value-a".to_owned()));
+    }
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses), (2, 1));
+    assert_eq!((stats.entries, stats.bytes), (1, 8));
+
+    // Overwritten and removed values are dropped
+    store.get("b".to_owned()).await?;
+    assert_eq!(store.cache_stats().entries, 2);
+    store.set("a".to_owned(), "new".to_owned()).await?;
+    store.remove("b".to_owned()).await?;
+    assert_eq!(store.cache_stats().entries, 0);
+    assert_eq!(store.get("a".to_owned()).await?, Some("new".to_owned()));
+    assert_eq!(store.get("b".to_owned()).await?, None);
+
+    // Values past the capacity evict the least recently read ones
+    let large = "x".repeat(40);
+    store.set("c".to_owned(), large.clone()).await?;
+    store.get("a".to_owned()).await?;
+    store.get("c".to_owned()).await?;
+    store.get("a".to_owned()).await?;
+    store.set("d".to_owned(), large.clone()).await?;
+    store.get("d".to_owned()).await?;
+    let stats = store.cache_stats();
+    assert_eq!((stats.entries, stats.bytes), (2, 45));
+    store.get("a".to_owned()).await?;
+    assert_eq!(store.cache_stats().hits, stats.hits + 1);
+
+    // Compaction drops the values of the generations it replaces
+    store.compact().await?;
+    assert_eq!(store.cache_stats().entries, 0);
+    assert_eq!(store.get("c".to_owned()).await?, Some(large));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .value_cache_size(0)
+        .open()
+        .await?;
+    store.set("a".to_owned(), "value-a".to_owned()).await?;
+    store.get("a".to_owned()).await?;
+    assert_eq!(store.cache_stats(), CacheStats::default());
+
+    Ok(())
+}
+
+// Should merge only the generations with the most garbage, without bringing
+// back values removed or expired in them
+#[async_std::test]
+async fn incremental_compaction() -> Result<()> {
+    for &index_mode in &[IndexMode::InMemory, IndexMode::OnDisk] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            KvStore::builder(temp_dir.path())
+                .max_segment_size(1024)
+                .compaction_threshold(usize::MAX)
+                .compaction_mode(CompactionMode::Incremental {
+                    min_garbage_ratio: 0.5,
+                    max_generations: 16,
+                })
+                .expiry_sweep_interval(
+                    Some(Duration::from_millis(50)).filter(|_| index_mode == IndexMode::InMemory),
+                )
+                .index_mode(index_mode)
+                .open()
+        };
+        let key = |key_id: usize| format!("keep-{:02}", key_id);
+
+        // Generations 1 to 3 hold a key each, with some garbage but not much
+        let store = open().await?;
+        for key_id in 0..60 {
+            store
+                .set(key(key_id), format!("value-{}", key(key_id)))
+                .await?;
+        }
+        for key_id in 0..5 {
+            store.remove(key(key_id)).await?;
+        }
+        store
+            .set_with_ttl(key(5), "expiring".to_owned(), Duration::from_millis(50))
+            .await?;
+        for i in 0..100 {
+            store
+                .set("churn".to_owned(), format!("value-{}", i))
+                .await?;
+        }
+        task::sleep(Duration::from_millis(300)).await;
+
+        store.compact().await?;
+        for generation in 1..=3 {
+            assert!(temp_dir.path().join(format!("{}.log", generation)).exists());
+        }
+        assert!(!temp_dir.path().join("4.log").exists());
+
+        // Once more after reopening, so that the removals must have survived
+        drop(store);
+        for _ in 0..2 {
+            let store = open().await?;
+            for key_id in 0..6 {
+                assert_eq!(store.get(key(key_id)).await?, None);
+            }
+            for key_id in 6..60 {
+                assert_eq!(
+                    store.get(key(key_id)).await?,
+                    Some(format!("value-{}", key(key_id)))
+                );
+            }
+            assert_eq!(
+                store.get("churn".to_owned()).await?,
+                Some("value-99".to_owned())
+            );
+            store.compact().await?;
+        }
     }
 
     Ok(())